@@ -0,0 +1,204 @@
+//! `--audit-log`: a record of every device node `mknod()` request, separate from the log.
+//!
+//! Every `mknod` and `mknodat` request reaching a handler appends one JSON object per line with
+//! these fields:
+//!
+//! * `time`: seconds since the epoch, with millisecond precision
+//! * `init_pid`: the container's init pid as sent by the lxc monitor
+//! * `pid`: the calling process
+//! * `uid`: the caller's effective uid in the container's user namespace, or `host_uid` if it
+//!   cannot be mapped
+//! * `loginuid`: the caller's audit login uid in the container's user namespace, or
+//!   `host_loginuid` if it cannot be mapped, left out if it is not set
+//! * `syscall`: `mknod` or `mknodat`
+//! * `mode`: the requested mode in octal, including the file type bits
+//! * `device`: the device number as `major:minor`
+//! * `decision`: one of
+//!   - `denied`: refused by our policy, with the `reason` in an additional field
+//!   - `continue`: no privileges are needed, the process creates the node itself
+//!   - `created`: a helper created the node
+//!   - `failed`: the helper's `mknodat()` failed with `errno`, or handling the request failed
+//!     (without an `errno`)
+//!
+//! Records are only collected in memory by the request handlers, a separate thread appends them
+//! to the file every second, so a slow file system never holds up a request. Should the file
+//! system be too slow to keep up, records beyond `MAX_PENDING` bytes are dropped with a warning.
+//!
+//! On `SIGHUP` the file is reopened (see the `sighup` module), so it can be rotated by renaming
+//! it.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write as _};
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::log::push_json_string;
+use crate::lxcseccomp::ProxyMessageBuffer;
+use crate::process::device::Device;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum amount of unwritten records.
+const MAX_PENDING: usize = 1024 * 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+struct Pending {
+    data: Vec<u8>,
+    dropped: u64,
+}
+
+static PENDING: Mutex<Pending> = Mutex::new(Pending {
+    data: Vec::new(),
+    dropped: 0,
+});
+
+/// What happened to a request.
+pub enum Decision<'a> {
+    Denied(&'a dyn std::fmt::Display),
+    Continue,
+    Created,
+    Failed(Option<c_int>),
+}
+
+/// Open the audit log for appending and start the thread writing to it.
+pub fn open(path: &OsStr) -> io::Result<()> {
+    let file = open_file(path)?;
+    let path = path.to_owned();
+    std::thread::Builder::new()
+        .name("audit-log".to_string())
+        .spawn(move || writer(path, file))?;
+
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+fn open_file(path: &OsStr) -> io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+/// Make the writer reopen the file before its next write, see the `sighup` module.
+pub fn reopen() {
+    REOPEN.store(true, Ordering::Relaxed);
+}
+
+fn writer(path: OsString, mut file: File) {
+    loop {
+        std::thread::sleep(FLUSH_INTERVAL);
+
+        let (data, dropped) = {
+            let mut pending = PENDING.lock().unwrap();
+            (
+                std::mem::take(&mut pending.data),
+                std::mem::take(&mut pending.dropped),
+            )
+        };
+
+        if dropped != 0 {
+            warn!("audit log: dropped {dropped} records, the file system is too slow");
+        }
+
+        if !data.is_empty() {
+            if let Err(err) = file.write_all(&data) {
+                error!("failed to write audit log: {err}");
+            }
+        }
+
+        if REOPEN.swap(false, Ordering::Relaxed) {
+            match open_file(&path) {
+                Ok(new) => file = new,
+                Err(err) => error!("failed to reopen audit log, keeping the old file: {err}"),
+            }
+        }
+    }
+}
+
+/// Record a `mknod()` request, if `--audit-log` is enabled.
+pub fn mknod(
+    msg: &ProxyMessageBuffer,
+    syscall: &str,
+    mode: libc::mode_t,
+    dev: libc::dev_t,
+    decision: Decision<'_>,
+) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let pid_fd = msg.pid_fd();
+    let (major, minor) = Device::split_dev(dev);
+
+    let mut line = String::new();
+    let _ = write!(
+        line,
+        "{{\"time\":{}.{:03},\"init_pid\":{},\"pid\":{}",
+        time.as_secs(),
+        time.subsec_millis(),
+        msg.init_pid(),
+        pid_fd.get_pid(),
+    );
+
+    // like the log, this is informational, so don't fail the request if it is not available
+    let uid_map = pid_fd.get_uid_map().ok();
+    if let Ok(uids) = pid_fd.get_uids() {
+        match uid_map
+            .as_ref()
+            .and_then(|map| map.map_into(u64::from(uids.euid)))
+        {
+            Some(uid) => {
+                let _ = write!(line, ",\"uid\":{uid}");
+            }
+            None => {
+                let _ = write!(line, ",\"host_uid\":{}", uids.euid);
+            }
+        }
+    }
+    if let Ok(Some(loginuid)) = pid_fd.get_loginuid() {
+        match uid_map
+            .as_ref()
+            .and_then(|map| map.map_into(u64::from(loginuid)))
+        {
+            Some(uid) => {
+                let _ = write!(line, ",\"loginuid\":{uid}");
+            }
+            None => {
+                let _ = write!(line, ",\"host_loginuid\":{loginuid}");
+            }
+        }
+    }
+
+    line.push_str(",\"syscall\":");
+    push_json_string(&mut line, syscall);
+    let _ = write!(
+        line,
+        ",\"mode\":\"{mode:o}\",\"device\":\"{major}:{minor}\",\"decision\":"
+    );
+    match decision {
+        Decision::Denied(reason) => {
+            line.push_str("\"denied\",\"reason\":");
+            push_json_string(&mut line, &reason.to_string());
+        }
+        Decision::Continue => line.push_str("\"continue\""),
+        Decision::Created => line.push_str("\"created\""),
+        Decision::Failed(Some(errno)) => {
+            let _ = write!(line, "\"failed\",\"errno\":{errno}");
+        }
+        Decision::Failed(None) => line.push_str("\"failed\""),
+    }
+    line.push_str("}\n");
+
+    let mut pending = PENDING.lock().unwrap();
+    if pending.data.len() + line.len() > MAX_PENDING {
+        pending.dropped += 1;
+    } else {
+        pending.data.extend_from_slice(line.as_bytes());
+    }
+}
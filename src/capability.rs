@@ -1,5 +1,6 @@
 use std::io;
 use std::os::raw::{c_int, c_ulong};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 bitflags::bitflags! {
     pub struct SecureBits: c_ulong {
@@ -30,6 +31,116 @@ impl SecureBits {
     }
 }
 
+/// Capability numbers from `linux/capability.h` we need to check for.
+pub const CAP_CHOWN: u32 = 0;
+pub const CAP_DAC_OVERRIDE: u32 = 1;
+pub const CAP_SETGID: u32 = 6;
+pub const CAP_SETUID: u32 = 7;
+pub const CAP_SYS_CHROOT: u32 = 18;
+pub const CAP_SYS_PTRACE: u32 = 19;
+pub const CAP_SYS_ADMIN: u32 = 21;
+pub const CAP_MKNOD: u32 = 27;
+
+/// Capabilities the daemon itself needs in its effective set.
+///
+/// Helpers enter the target's namespaces (`CAP_SYS_ADMIN`) and root directory
+/// (`CAP_SYS_CHROOT`), switch to its credentials (`CAP_SETUID`, `CAP_SETGID`) and can only keep
+/// capabilities we hold ourselves, so eg. `mknod()` requires `CAP_MKNOD`. Reading the memory and
+/// /proc files of processes owned by other users requires `CAP_SYS_PTRACE` and
+/// `CAP_DAC_OVERRIDE`.
+pub const REQUIRED: &[u32] = &[
+    CAP_SYS_ADMIN,
+    CAP_SYS_CHROOT,
+    CAP_SYS_PTRACE,
+    CAP_SETUID,
+    CAP_SETGID,
+    CAP_DAC_OVERRIDE,
+    CAP_CHOWN,
+    CAP_MKNOD,
+];
+
+/// Names of the capabilities, indexed by their number.
+const NAMES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// Get the name of a capability, for log messages.
+pub fn name(cap: u32) -> &'static str {
+    NAMES
+        .get(cap as usize)
+        .copied()
+        .unwrap_or("unknown capability")
+}
+
+/// The names of all capabilities in `mask`, for log messages.
+pub fn names(mask: u64) -> String {
+    (0..64)
+        .filter(|cap| mask & (1 << cap) != 0)
+        .map(|cap| match NAMES.get(cap as usize) {
+            Some(name) => name.to_string(),
+            None => format!("capability {cap}"),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// All capabilities we know the names of.
+pub fn known() -> u64 {
+    (1 << NAMES.len()) - 1
+}
+
+static BOUNDING_SET: AtomicU64 = AtomicU64::new(!0);
+
+/// Remember our own bounding set, see [`Capabilities::clamp_to_bounding`].
+pub fn set_bounding_set(bounding: u64) {
+    BOUNDING_SET.store(bounding, Ordering::Relaxed);
+}
+
+/// Our own bounding set as passed to `set_bounding_set()`, all capabilities if it was not.
+pub fn bounding_set() -> u64 {
+    BOUNDING_SET.load(Ordering::Relaxed)
+}
+
 #[derive(Clone, Default)]
 pub struct Capabilities {
     pub inheritable: u64,
@@ -48,6 +159,25 @@ impl Capabilities {
     // We currently don't implement capget as it takes a pid which is racy on kernels without pidfd
     // support. Later on we might support a `capget(&PidFd)` method?
 
+    /// Check whether a capability is in the effective set.
+    pub fn has_effective(&self, cap: u32) -> bool {
+        cap < 64 && self.effective & (1 << cap) != 0
+    }
+
+    /// Remove the capabilities which are not in `bounding` from all sets, returns the removed
+    /// ones.
+    ///
+    /// Helpers cannot gain capabilities outside of the daemon's bounding set (and `capset()`
+    /// fails with `EPERM` if asked to), so when we run with a reduced one this lets them do
+    /// without instead of failing the whole request.
+    pub fn clamp_to_bounding(&mut self, bounding: u64) -> u64 {
+        let dropped = (self.inheritable | self.permitted | self.effective) & !bounding;
+        self.inheritable &= bounding;
+        self.permitted &= bounding;
+        self.effective &= bounding;
+        dropped
+    }
+
     /// Change our process capabilities. This does not include the bounding set.
     pub fn capset(&self) -> io::Result<()> {
         // kernel abi:
@@ -0,0 +1,96 @@
+//! Diagnostics for `--check`: verify the kernel provides what we need.
+
+use std::io;
+use std::os::raw::c_int;
+
+use crate::capability;
+use crate::process::{self, PidFd};
+use crate::seccomp::{self, SeccompNotifSizes};
+
+const SECCOMP_GET_ACTION_AVAIL: c_int = 2;
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+
+type Probe = fn() -> io::Result<()>;
+
+/// Run all probes and print a report to stdout. Returns `true` if everything is supported.
+///
+/// Every probe is independent of the others, so a partially supported kernel produces a list of
+/// all the missing parts.
+pub fn run() -> bool {
+    let probes: &[(&str, Probe)] = &[
+        ("seccomp user notifications", probe_user_notif),
+        ("seccomp notification sizes", probe_notif_sizes),
+        (
+            "SECCOMP_USER_NOTIF_FLAG_CONTINUE",
+            seccomp::check_flag_continue,
+        ),
+        ("process file descriptors via /proc", probe_pid_fd),
+        ("process_vm_readv()", probe_process_vm_readv),
+        ("required capabilities", probe_capabilities),
+    ];
+
+    let mut ok = true;
+    for (name, probe) in probes {
+        match probe() {
+            Ok(()) => println!("ok:     {name}"),
+            Err(err) => {
+                println!("FAILED: {name}: {err}");
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+fn probe_user_notif() -> io::Result<()> {
+    let action = SECCOMP_RET_USER_NOTIF;
+    c_try!(unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_GET_ACTION_AVAIL,
+            0,
+            &action as *const u32,
+        )
+    });
+    Ok(())
+}
+
+fn probe_notif_sizes() -> io::Result<()> {
+    let sizes = SeccompNotifSizes::get()?;
+    sizes
+        .check()
+        .map_err(|err| io_format_err!("{err} (kernel: {sizes})"))
+}
+
+fn probe_pid_fd() -> io::Result<()> {
+    PidFd::current()?.get_status().map(drop)
+}
+
+fn probe_process_vm_readv() -> io::Result<()> {
+    if process::process_vm_readv_usable() {
+        Ok(())
+    } else {
+        Err(io_format_err!(
+            "not usable, falling back to /proc/<pid>/mem (slower)"
+        ))
+    }
+}
+
+fn probe_capabilities() -> io::Result<()> {
+    let missing = missing_capabilities()?;
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(io_format_err!("missing {}", missing.join(", ")))
+    }
+}
+
+/// The names of the capabilities in `capability::REQUIRED` missing from our effective set.
+pub fn missing_capabilities() -> io::Result<Vec<&'static str>> {
+    let caps = PidFd::current()?.get_caps()?;
+    Ok(capability::REQUIRED
+        .iter()
+        .filter(|cap| !caps.has_effective(**cap))
+        .map(|cap| capability::name(*cap))
+        .collect())
+}
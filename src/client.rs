@@ -1,19 +1,117 @@
-use std::sync::Arc;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use anyhow::Error;
 use nix::errno::Errno;
+use nix::sys::socket::UnixCredentials;
 
 use crate::io::seq_packet::SeqPacketSocket;
 use crate::lxcseccomp::ProxyMessageBuffer;
-use crate::syscall::{self, Syscall, SyscallStatus};
+use crate::metrics::{self, METRICS};
+use crate::syscall::{self, CallerArch, Syscall, SyscallStatus};
+
+/// The cookie (`lxc.seccomp.notify.cookie`) messages must carry, if any.
+static EXPECTED_COOKIE: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// Only handle messages carrying this cookie, others are answered with `EPERM`.
+///
+/// This allows using the cookie as a shared secret between lxc and us.
+pub fn set_expected_cookie(cookie: Vec<u8>) {
+    *EXPECTED_COOKIE.lock().unwrap() = Some(cookie);
+}
+
+fn cookie_valid(cookie: &[u8]) -> bool {
+    match &*EXPECTED_COOKIE.lock().unwrap() {
+        Some(expected) => crate::tools::constant_time_eq(cookie, expected),
+        None => true,
+    }
+}
+
+static VERIFY_MONITOR: AtomicBool = AtomicBool::new(false);
+
+/// Reject messages whose `monitor_pid` is not the pid of the connection's peer.
+///
+/// Normally the lxc monitor sending a message also is the process it names as `monitor_pid`, the
+/// only legitimate exception being another proxy forwarding messages to us. So unless such a
+/// chain of proxies is in use, this detects a process forwarding messages it should not (eg.
+/// one with access to the socket replaying another container's messages). This is off by
+/// default, since it would break such chains.
+pub fn enable_verify_monitor() {
+    VERIFY_MONITOR.store(true, Ordering::Relaxed);
+}
 
 pub struct Client {
+    id: u64,
     socket: SeqPacketSocket,
+    peer: UnixCredentials,
+    /// The container init pid of the last message received, 0 if there was none yet.
+    init_pid: AtomicI32,
+}
+
+/// Identifies a client in log messages.
+struct ClientName<'a>(&'a Client);
+
+impl fmt::Display for ClientName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {}", self.0.id)?;
+        match self.0.init_pid.load(Ordering::Relaxed) {
+            0 => Ok(()),
+            pid => write!(f, " (container init pid {pid})"),
+        }
+    }
+}
+
+/// Describes the process a message came from in log messages, including its audit login uid
+/// (in terms of the container's user namespace) if it has one.
+struct Caller<'a>(&'a ProxyMessageBuffer);
+
+impl fmt::Display for Caller<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pid_fd = self.0.pid_fd();
+        write!(f, "pid {}", pid_fd.get_pid())?;
+
+        // This is only informational, so don't fail the log message if the data is not available.
+        if let Ok(Some(loginuid)) = pid_fd.get_loginuid() {
+            match pid_fd
+                .get_uid_map()
+                .ok()
+                .and_then(|map| map.map_into(u64::from(loginuid)))
+            {
+                Some(uid) => write!(f, ", loginuid {uid}")?,
+                None => write!(f, ", host loginuid {loginuid}")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The name of the syscall a message is about, for log messages.
+fn syscall_name(msg: &ProxyMessageBuffer) -> &'static str {
+    let data = &msg.request().data;
+    CallerArch::from_notification(data.arch, data.nr)
+        .and_then(|arch| syscall::translate_syscall(arch, data.nr))
+        .map_or("unknown", Syscall::name)
 }
 
 impl Client {
-    pub fn new(socket: SeqPacketSocket) -> Arc<Self> {
-        Arc::new(Self { socket })
+    /// Create a client for an accepted connection.
+    ///
+    /// The `id` is only used to tell clients apart in the log, `peer` are the socket's peer
+    /// credentials.
+    pub fn new(id: u64, socket: SeqPacketSocket, peer: UnixCredentials) -> Arc<Self> {
+        Arc::new(Self {
+            id,
+            socket,
+            peer,
+            init_pid: AtomicI32::new(0),
+        })
+    }
+
+    fn name(&self) -> ClientName<'_> {
+        ClientName(self)
     }
 
     /// Wrap futures returning a `Result` so if they fail we `shutdown()` the socket to drop the
@@ -22,75 +120,253 @@ impl Client {
     where
         F: std::future::Future<Output = Result<(), Error>>,
     {
-        if let Err(err) = fut.await {
-            eprintln!("client error, dropping connection: {err}");
-            if let Err(err) = self.socket.shutdown(nix::sys::socket::Shutdown::Both) {
-                eprintln!("    (error shutting down client socket: {err})");
+        match fut.await {
+            Ok(()) => info!(client_id = self.id; "{} disconnected", self.name()),
+            Err(err) => {
+                info!(client_id = self.id; "{} disconnected (error: {err})", self.name());
+                if let Err(err) = self.socket.shutdown(nix::sys::socket::Shutdown::Both) {
+                    error!(client_id = self.id; "    (error shutting down client socket: {err})");
+                }
             }
         }
     }
 
     pub async fn main(self: Arc<Self>) {
-        Arc::clone(&self).wrap_error(self.main_do()).await
+        info!(
+            client_id = self.id, pid = self.peer.pid();
+            "client {} connected (pid {}, uid {})",
+            self.id,
+            self.peer.pid(),
+            self.peer.uid()
+        );
+
+        metrics::inc(&METRICS.clients_total);
+        metrics::inc(&METRICS.clients_active);
+        Arc::clone(&self).wrap_error(self.main_do()).await;
+        metrics::dec(&METRICS.clients_active);
     }
 
+    /// Messages are handled one at a time: we only `recv` the next one after responding to the
+    /// previous one. Until we do, the kernel keeps further notifications queued (and the
+    /// processes blocked), so a flooding container cannot make us buffer anything.
+    ///
+    /// Should handling ever become concurrent, the number of messages in flight per client needs
+    /// to be bounded here to keep that property.
     async fn main_do(self: Arc<Self>) -> Result<(), Error> {
         let mut msg = ProxyMessageBuffer::new(64);
         loop {
             if !msg.recv(&self.socket).await? {
                 break Ok(());
             }
+            self.init_pid.store(msg.init_pid(), Ordering::Relaxed);
 
             self.handle_syscall(&mut msg).await?;
         }
     }
 
     async fn handle_syscall(&self, msg: &mut ProxyMessageBuffer) -> Result<(), Error> {
-        let result = match Self::handle_syscall_do(msg).await {
+        let start = Instant::now();
+        metrics::inc(&METRICS.syscalls_total);
+        let result = match self.handle_syscall_do(msg).await {
             Ok(r) => r,
             Err(err) => {
                 // handle the various kinds of errors we may get:
                 if let Some(errno) = err.downcast_ref::<nix::Error>() {
                     SyscallStatus::Err(*errno as _)
-                } else if let Some(ioerr) = err.downcast_ref::<std::io::Error>() {
-                    if let Some(errno) = ioerr.raw_os_error() {
-                        SyscallStatus::Err(errno)
-                    } else {
-                        return Err(err);
-                    }
+                } else if let Some(errno) = err
+                    .downcast_ref::<std::io::Error>()
+                    .and_then(|ioerr| ioerr.raw_os_error())
+                {
+                    SyscallStatus::Err(errno)
                 } else {
-                    return Err(err);
+                    // Only this one request failed, the connection itself is fine, so don't drop
+                    // the client (and with it all of the container's future syscalls).
+                    error!(
+                        client_id = self.id,
+                        init_pid = msg.init_pid(),
+                        pid = msg.pid_fd().get_pid();
+                        "{}: failed to handle syscall for {}: {err:?}",
+                        self.name(),
+                        Caller(msg),
+                    );
+                    SyscallStatus::Err(libc::EPERM)
                 }
             }
         };
 
-        let resp = msg.response_mut();
-        match result {
-            SyscallStatus::Ok(val) => {
-                resp.val = val;
-                resp.error = 0;
-            }
-            SyscallStatus::Err(err) => {
-                resp.val = -1;
-                resp.error = -err;
-            }
+        debug!(
+            client_id = self.id,
+            init_pid = msg.init_pid(),
+            pid = msg.pid_fd().get_pid(),
+            syscall = syscall_name(msg),
+            result = match result {
+                SyscallStatus::Ok(val) => val,
+                SyscallStatus::Err(err) => -i64::from(err),
+                SyscallStatus::Continue => 0,
+            };
+            "{}: {} for {} returned {}",
+            self.name(),
+            syscall_name(msg),
+            Caller(msg),
+            match result {
+                SyscallStatus::Ok(val) => val.to_string(),
+                SyscallStatus::Err(err) => format!("error {}", Errno::from_i32(err)),
+                SyscallStatus::Continue => "continue".to_string(),
+            },
+        );
+
+        if let SyscallStatus::Err(_) = result {
+            metrics::inc(&METRICS.syscalls_failed);
+            // the failure may be caused by a changed environment, so don't reuse it
+            msg.invalidate_env_cache();
         }
+        msg.set_result(&result);
 
-        msg.respond(&self.socket).await.map_err(Error::from)
+        msg.respond(&self.socket).await?;
+        METRICS.syscall_duration.record(start.elapsed());
+        Ok(())
     }
 
-    async fn handle_syscall_do(msg: &ProxyMessageBuffer) -> Result<SyscallStatus, Error> {
-        let (arch, sysnr) = (msg.request().data.arch, msg.request().data.nr);
+    async fn handle_syscall_do(&self, msg: &ProxyMessageBuffer) -> Result<SyscallStatus, Error> {
+        if !cookie_valid(msg.cookie()) {
+            warn!(
+                client_id = self.id,
+                init_pid = msg.init_pid(),
+                pid = msg.pid_fd().get_pid();
+                "{}: wrong cookie from monitor pid {}, rejecting syscall for {}",
+                self.name(),
+                msg.monitor_pid(),
+                Caller(msg),
+            );
+            return Ok(Errno::EPERM.into());
+        }
+
+        if VERIFY_MONITOR.load(Ordering::Relaxed) && msg.monitor_pid() != self.peer.pid() {
+            warn!(
+                client_id = self.id,
+                init_pid = msg.init_pid(),
+                pid = msg.pid_fd().get_pid();
+                "{}: message names monitor pid {} but was sent by pid {}, rejecting syscall for {}",
+                self.name(),
+                msg.monitor_pid(),
+                self.peer.pid(),
+                Caller(msg),
+            );
+            return Ok(Errno::EPERM.into());
+        }
+
+        let (audit_arch, sysnr) = (msg.request().data.arch, msg.request().data.nr);
+
+        // Handlers must use this rather than looking at the notification's `arch` themselves.
+        let arch = match CallerArch::from_notification(audit_arch, sysnr) {
+            Some(arch) => arch,
+            None => {
+                metrics::inc(&METRICS.syscalls_unsupported);
+                return Ok(Errno::ENOSYS.into());
+            }
+        };
 
         let syscall_nr = match syscall::translate_syscall(arch, sysnr) {
             Some(nr) => nr,
-            None => return Ok(Errno::ENOSYS.into()),
+            None => {
+                metrics::inc(&METRICS.syscalls_unsupported);
+                return Ok(Errno::ENOSYS.into());
+            }
         };
 
-        match syscall_nr {
-            Syscall::Mknod => crate::sys_mknod::mknod(msg).await,
-            Syscall::MknodAt => crate::sys_mknod::mknodat(msg).await,
-            Syscall::Quotactl => crate::sys_quotactl::quotactl(msg).await,
+        if syscall_nr.is_disabled() {
+            metrics::inc(&METRICS.syscalls_unsupported);
+            return Ok(Errno::ENOSYS.into());
         }
+
+        handle_request(msg, arch, syscall_nr).await
+    }
+}
+
+/// Run the handler for a request which passed the client's checks.
+///
+/// Handlers must do their cheap validation (flags, device numbers, sub commands...) first, then
+/// read strings and structs from the process' memory, and only then build the `UserCaps` (which
+/// parses several files in /proc) and fork, so requests which are denied anyway don't pay for the
+/// expensive parts.
+///
+/// Requests denied by our own policy fail with `EPERM` (or `EINVAL` for arguments the kernel would
+/// reject as well), while errors of the actual syscall in the helper are passed on unchanged via
+/// `sc_libc_try!()`, so eg. an `EACCES` or `EROFS` still tells the container that the file system
+/// refused.
+pub async fn handle_request(
+    msg: &ProxyMessageBuffer,
+    arch: CallerArch,
+    syscall: Syscall,
+) -> Result<SyscallStatus, Error> {
+    match syscall {
+        Syscall::Mknod => crate::sys_mknod::mknod(msg).await,
+        Syscall::MknodAt => crate::sys_mknod::mknodat(msg).await,
+        Syscall::Quotactl => crate::sys_quotactl::quotactl(msg, arch).await,
+        Syscall::RenameAt2 => crate::sys_rename::renameat2(msg).await,
+        Syscall::FchownAt => crate::sys_chown::fchownat(msg).await,
+        Syscall::Umount2 => crate::sys_mount::umount2(msg).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::{FromRawFd, OwnedFd};
+
+    use nix::sys::socket;
+
+    use super::*;
+    use crate::process::PidFd;
+    use crate::seccomp::SeccompNotif;
+
+    /// A request of the current process for `nr` with a memory file as fake memory.
+    fn message(nr: libc::c_long, args: [u64; 6]) -> ProxyMessageBuffer {
+        let mut request: SeccompNotif = unsafe { std::mem::zeroed() };
+        request.id = 1;
+        request.pid = std::process::id();
+        request.data.arch = crate::sandbox::AUDIT_ARCH_X86_64;
+        request.data.nr = nr as libc::c_int;
+        request.data.args = args;
+        let fd = unsafe { libc::memfd_create(b"mem\0".as_ptr() as _, libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let mem = unsafe { std::fs::File::from_raw_fd(fd) };
+        ProxyMessageBuffer::from_parts(request, mem, PidFd::current().unwrap())
+    }
+
+    #[test]
+    fn disabled_syscall() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (ours, theirs) = socket::socketpair(
+                socket::AddressFamily::Unix,
+                socket::SockType::SeqPacket,
+                None,
+                socket::SockFlag::SOCK_CLOEXEC,
+            )
+            .unwrap();
+            let _theirs = unsafe { OwnedFd::from_raw_fd(theirs) };
+            let socket =
+                SeqPacketSocket::from_connected(unsafe { OwnedFd::from_raw_fd(ours) }).unwrap();
+            let peer = socket.peer_credentials().unwrap();
+            let client = Client::new(0, socket, peer);
+
+            // no other test uses quotactl, so disabling it for good is fine
+            Syscall::Quotactl.disable();
+            let quotactl = message(libc::SYS_quotactl, [0; 6]);
+            let result = client.handle_syscall_do(&quotactl).await.unwrap();
+            assert!(matches!(result, SyscallStatus::Err(libc::ENOSYS)));
+
+            // still handled, block devices are denied by the handler
+            let dev = libc::makedev(8, 0);
+            let mknod = message(
+                libc::SYS_mknod,
+                [0, u64::from(libc::S_IFBLK | 0o600), dev, 0, 0, 0],
+            );
+            let result = client.handle_syscall_do(&mknod).await.unwrap();
+            assert!(matches!(result, SyscallStatus::Err(libc::EPERM)));
+        });
     }
 }
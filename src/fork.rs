@@ -5,26 +5,47 @@
 //! data we continue to work with.
 
 use std::convert::TryInto;
+use std::fmt;
 use std::io;
 use std::os::raw::c_int;
 use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::panic::UnwindSafe;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::Instant;
 
+use lazy_static::lazy_static;
 use tokio::io::AsyncReadExt;
 
 use crate::io::pipe::{self, Pipe};
+use crate::metrics::{self, METRICS};
 use crate::syscall::SyscallStatus;
 
 pub async fn forking_syscall<F>(func: F) -> io::Result<SyscallStatus>
 where
     F: FnOnce() -> io::Result<SyscallStatus> + UnwindSafe,
 {
+    let start = Instant::now();
     let mut fork = Fork::new(func)?;
     let result = fork.get_result().await?;
     fork.wait()?;
+    METRICS.helper_duration.record(start.elapsed());
     Ok(result)
 }
 
+/// Helpers still running after this many seconds are killed, 0 if they may run forever.
+static TIMEOUT_SECS: AtomicU32 = AtomicU32::new(0);
+
+/// Kill helpers which have not finished after `secs` seconds (0 to disable), the syscall then
+/// fails with `ETIMEDOUT`.
+///
+/// The helpers arm an `alarm()` themselves right after the fork, so this needs no timer in the
+/// daemon and also covers helpers stuck before reaching the syscall, eg. while entering the
+/// process' cgroup.
+pub fn set_timeout(secs: u32) {
+    TIMEOUT_SECS.store(secs, Ordering::Relaxed);
+}
+
 pub struct Fork {
     pid: Option<libc::pid_t>,
     // FIXME: abuse! tokio-fs is not updated to futures@0.3 yet, but a TcpStream does the same
@@ -32,19 +53,145 @@ pub struct Fork {
     out: Pipe<pipe::Read>,
 }
 
+lazy_static! {
+    /// Helpers to reap in the background, see `Drop for Fork`.
+    ///
+    /// A single thread waits for them one after the other. One stuck in an uninterruptible sleep
+    /// (even `SIGKILL` only takes effect once it wakes up) delays reaping the ones after it, which
+    /// only leaves them as zombies for that long.
+    static ref REAPER: Option<Mutex<mpsc::Sender<libc::pid_t>>> = {
+        let (send, recv) = mpsc::channel::<libc::pid_t>();
+        let thread = std::thread::Builder::new()
+            .name("helper-reaper".to_string())
+            .spawn(move || {
+                for pid in recv {
+                    let _ = wait_pid(pid);
+                }
+            });
+        match thread {
+            Ok(_) => Some(Mutex::new(send)),
+            Err(err) => {
+                error!("failed to start helper reaper thread: {err}");
+                None
+            }
+        }
+    };
+}
+
 impl Drop for Fork {
+    /// We get here without having waited for the helper if reading its result failed or the
+    /// request was abandoned (eg. because the client disconnected). The helper may be stuck (eg.
+    /// on an unresponsive file system), and since this runs on a runtime thread we must not block,
+    /// so unless it already exited, the helper is killed and left to the reaper thread.
     fn drop(&mut self) {
-        if self.pid.is_some() {
-            let _ = self.wait();
+        let pid = match self.pid.take() {
+            Some(pid) => pid,
+            None => return,
+        };
+
+        match try_reap(pid) {
+            Ok(false) => (),
+            Ok(true) | Err(_) => return,
+        }
+
+        debug!("killing abandoned helper process {pid}");
+        // We did not reap it yet, so the pid cannot have been reused.
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+        }
+        match &*REAPER {
+            Some(reaper) => {
+                let _ = reaper.lock().unwrap().send(pid);
+            }
+            // Should never happen, but a blocked runtime thread is still better than leaking
+            // zombies.
+            None => {
+                let _ = wait_pid(pid);
+            }
         }
     }
 }
 
+/// Reap the process `pid` if it has exited, returns whether it did.
+fn try_reap(pid: libc::pid_t) -> io::Result<bool> {
+    let mut status: c_int = -1;
+    loop {
+        match c_result!(unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) }) {
+            Ok(0) => return Ok(false),
+            Ok(_) => return Ok(true),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(other) => return Err(other),
+        }
+    }
+}
+
+/// Wait for the process `pid` to exit and return its wait status.
+fn wait_pid(pid: libc::pid_t) -> io::Result<c_int> {
+    let mut status: c_int = -1;
+    loop {
+        match c_result!(unsafe { libc::waitpid(pid, &mut status, 0) }) {
+            Ok(other) if other == pid => return Ok(status),
+            Ok(_other) => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(other) => return Err(other),
+        }
+    }
+}
+
+/// The size of the error description a helper can send along with a failure.
+const MESSAGE_SIZE: usize = 256;
+
 #[repr(C, packed)]
 struct Data {
     val: i64,
+    /// The positive errno of `SyscallStatus::Err`, 0 for `SyscallStatus::Ok`, and -1 along with
+    /// a `failure`.
     error: i32,
+    /// The positive errno of an error of the helper itself, see `helper_errno()`.
     failure: i32,
+    message_len: u32,
+    message: [u8; MESSAGE_SIZE],
+}
+
+/// A helper's error along with a description of what failed, see `error_context()`.
+#[derive(Debug)]
+struct HelperError {
+    errno: c_int,
+    message: String,
+}
+
+impl fmt::Display for HelperError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for HelperError {}
+
+/// Describe what failed in a helper process.
+///
+/// Only the errno of a helper's error is passed on to the caller, so this is the only way to get
+/// a message like `failed to set fsuid to 1000: Operation not permitted` into the log: the parent
+/// logs the description and then fails with the original errno as usual.
+pub fn error_context(err: io::Error, context: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        err.kind(),
+        HelperError {
+            errno: helper_errno(&err),
+            message: format!("{context}: {err}"),
+        },
+    )
+}
+
+/// The errno to return for a helper's error, `EFAULT` if it is not an OS error.
+fn helper_errno(err: &io::Error) -> c_int {
+    err.raw_os_error()
+        .or_else(|| {
+            err.get_ref()
+                .and_then(|inner| inner.downcast_ref::<HelperError>())
+                .map(|inner| inner.errno)
+        })
+        .unwrap_or(libc::EFAULT)
 }
 
 impl Fork {
@@ -56,28 +203,50 @@ impl Fork {
 
         let pid = c_try!(unsafe { libc::fork() });
         if pid == 0 {
+            arm_timeout();
             drop(pipe_r);
             let pipe_w = pipe_w.into_fd();
             let _ = std::panic::catch_unwind(move || {
                 crate::tools::set_fd_nonblocking(&pipe_w, false).unwrap();
                 let mut pipe_w = unsafe { std::fs::File::from_raw_fd(pipe_w.into_raw_fd()) };
-                let out = match func() {
-                    Ok(SyscallStatus::Ok(val)) => Data {
-                        val,
-                        error: 0,
-                        failure: 0,
-                    },
-                    Ok(SyscallStatus::Err(error)) => Data {
-                        val: -1,
-                        error: error as _,
-                        failure: 0,
-                    },
-                    Err(err) => Data {
-                        val: -1,
-                        error: -1,
-                        failure: err.raw_os_error().unwrap_or(libc::EFAULT),
-                    },
-                };
+                let mut out: Data = unsafe { std::mem::zeroed() };
+                match func() {
+                    Ok(SyscallStatus::Ok(val)) => out.val = val,
+                    Ok(SyscallStatus::Err(error)) if error > 0 => {
+                        out.val = -1;
+                        out.error = error as _;
+                    }
+                    Ok(SyscallStatus::Err(error)) => {
+                        // 0 would turn into a successful syscall returning -1
+                        let message = format!("helper returned an invalid errno ({error})");
+                        out.val = -1;
+                        out.error = -1;
+                        out.failure = libc::EPERM;
+                        out.message[..message.len()].copy_from_slice(message.as_bytes());
+                        out.message_len = message.len() as u32;
+                    }
+                    Ok(SyscallStatus::Continue) => {
+                        // the syscall would run *after* the helper, in a different context
+                        let message = b"helpers cannot let the syscall continue";
+                        out.val = -1;
+                        out.error = -1;
+                        out.failure = libc::EINVAL;
+                        out.message[..message.len()].copy_from_slice(message);
+                        out.message_len = message.len() as u32;
+                    }
+                    Err(err) => {
+                        out.val = -1;
+                        out.error = -1;
+                        out.failure = helper_errno(&err);
+                        // a plain errno carries no information beyond `failure`
+                        if err.raw_os_error().is_none() {
+                            let message = err.to_string();
+                            let len = message.len().min(MESSAGE_SIZE);
+                            out.message[..len].copy_from_slice(&message.as_bytes()[..len]);
+                            out.message_len = len as u32;
+                        }
+                    }
+                }
 
                 let slice = unsafe {
                     std::slice::from_raw_parts(
@@ -115,23 +284,17 @@ impl Fork {
     }
 
     pub fn wait(&mut self) -> io::Result<()> {
-        let my_pid = self.pid.take().unwrap();
-        let mut status: c_int = -1;
-
-        loop {
-            match c_result!(unsafe { libc::waitpid(my_pid, &mut status, 0) }) {
-                Ok(pid) if pid == my_pid => break,
-                Ok(_other) => continue,
-                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
-                Err(other) => return Err(other),
-            }
-        }
-
-        if status != 0 {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "error in child process",
-            ))
+        let pid = self.pid.take().unwrap();
+        let status = wait_pid(pid)?;
+        if libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGALRM {
+            metrics::inc(&METRICS.helper_timeouts);
+            warn!(
+                "helper process {pid} did not finish within {} seconds, killed it",
+                TIMEOUT_SECS.load(Ordering::Relaxed)
+            );
+            Err(io::Error::from_raw_os_error(libc::ETIMEDOUT))
+        } else if status != 0 {
+            Err(status_error(status))
         } else {
             Ok(())
         }
@@ -146,7 +309,19 @@ impl Fork {
                 std::mem::size_of::<Data>(),
             )
         };
-        self.out.read_exact(dataslice).await?;
+        match self.out.read_exact(dataslice).await {
+            Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                // The helper exited (or was killed) before sending a result, this is an internal
+                // error and must not be confused with the syscall failing. Its exit status tells
+                // us what happened.
+                return Err(match self.wait() {
+                    Ok(()) => io_format_err!("helper process exited without sending a result"),
+                    Err(err) => err,
+                });
+            }
+            Err(err) => return Err(err),
+        }
         //self.read_exact(unsafe {
         //    std::slice::from_raw_parts_mut(
         //        &mut data as *mut Data as *mut u8,
@@ -155,6 +330,16 @@ impl Fork {
         //})
         //.await?;
         if data.failure != 0 {
+            let len = (data.message_len as usize).min(MESSAGE_SIZE);
+            if len != 0 {
+                warn!(
+                    "helper process failed: {}",
+                    String::from_utf8_lossy(&data.message[..len])
+                );
+            }
+            // The helper exits right after sending its result, so reap it here rather than
+            // leaving it to `Drop`. Its exit status tells us nothing beyond the failure.
+            let _ = self.wait();
             Err(io::Error::from_raw_os_error(data.failure))
         } else if data.error == 0 {
             Ok(SyscallStatus::Ok(data.val))
@@ -163,3 +348,105 @@ impl Fork {
         }
     }
 }
+
+/// In a helper, make `SIGALRM` kill us after the configured timeout.
+fn arm_timeout() {
+    let secs = TIMEOUT_SECS.load(Ordering::Relaxed);
+    if secs == 0 {
+        return;
+    }
+    // the forking thread's signal mask and dispositions are inherited
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGALRM);
+        libc::sigprocmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+        libc::signal(libc::SIGALRM, libc::SIG_DFL);
+        libc::alarm(secs);
+    }
+}
+
+/// Describe a non-zero wait status of a helper process.
+///
+/// Note that a panic in the helper causes an exit status of 255.
+fn status_error(status: c_int) -> io::Error {
+    if libc::WIFSIGNALED(status) {
+        io_format_err!("helper process killed by signal {}", libc::WTERMSIG(status))
+    } else if libc::WIFEXITED(status) {
+        io_format_err!(
+            "helper process exited with status {}",
+            libc::WEXITSTATUS(status)
+        )
+    } else {
+        io_format_err!("helper process failed (wait status {:#x})", status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn drop_reaps_stuck_helper() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let pid = runtime.block_on(async {
+            let fork = Fork::new(|| loop {
+                unsafe {
+                    libc::pause();
+                }
+            })
+            .unwrap();
+            fork.pid.unwrap()
+        });
+
+        // killed and handed to the reaper thread, so it disappears entirely, zombie included
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while unsafe { libc::kill(pid, 0) } == 0 {
+            assert!(Instant::now() < deadline, "helper {pid} was not reaped");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::ESRCH));
+    }
+
+    #[test]
+    fn helper_errno() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let result = forking_syscall(|| Ok(SyscallStatus::Err(libc::EPERM))).await;
+            assert!(matches!(result, Ok(SyscallStatus::Err(libc::EPERM))));
+
+            // would be a successful -1 otherwise
+            let result = forking_syscall(|| Ok(SyscallStatus::Err(0))).await;
+            assert_eq!(result.err().unwrap().raw_os_error(), Some(libc::EPERM));
+        });
+    }
+
+    #[test]
+    fn timeout_kills_helper() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // This is global, but helpers of other tests finish well within a second.
+        set_timeout(1);
+        let start = Instant::now();
+        let result = runtime.block_on(forking_syscall(|| loop {
+            unsafe {
+                libc::pause();
+            }
+        }));
+        set_timeout(0);
+
+        assert_eq!(result.err().unwrap().raw_os_error(), Some(libc::ETIMEDOUT));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
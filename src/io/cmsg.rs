@@ -47,6 +47,11 @@ impl<'a> Iterator for RawCmsgIterator<'a> {
         let hdr: cmsghdr = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const cmsghdr) };
         let data_off = mem::size_of::<cmsghdr>();
         let data_end = hdr.cmsg_len;
+        // a truncated or malformed header, don't look any further
+        if data_end < data_off || data_end > buf.len() {
+            self.buf = &[];
+            return None;
+        }
         let next_hdr = align(hdr.cmsg_len);
         let data = &buf[data_off..data_end];
         let item = ControlMessageRef {
@@ -68,3 +73,32 @@ impl<'a> Iterator for RawCmsgIterator<'a> {
 pub fn iter(buf: &[u8]) -> RawCmsgIterator {
     RawCmsgIterator { buf }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; space(8)];
+        let mut hdr: libc::cmsghdr = unsafe { mem::zeroed() };
+        hdr.cmsg_len = len;
+        hdr.cmsg_level = libc::SOL_SOCKET;
+        hdr.cmsg_type = libc::SCM_RIGHTS;
+        unsafe { std::ptr::write_unaligned(buf.as_mut_ptr() as *mut libc::cmsghdr, hdr) };
+        buf
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let hdr_len = mem::size_of::<libc::cmsghdr>();
+
+        let buf = header(hdr_len + 8);
+        let items: Vec<_> = iter(&buf).map(|cmsg| cmsg.data.len()).collect();
+        assert_eq!(items, [8]);
+
+        // shorter than the header itself, or extending past the buffer
+        for len in [0, hdr_len - 1, space(8) + 1] {
+            assert!(iter(&header(len)).next().is_none(), "cmsg_len {len}");
+        }
+    }
+}
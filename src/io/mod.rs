@@ -8,6 +8,10 @@ pub mod pipe;
 pub mod rw_traits;
 pub mod seq_packet;
 
+/// Retry `call` until it does not fail with `EWOULDBLOCK` or `EINTR` anymore.
+///
+/// Tokio registers file descriptors with `EPOLLRDHUP` in addition to `EPOLLIN`, so a peer
+/// shutting down its side of a connection makes us readable, and the read returns 0 right away.
 pub async fn wrap_read<R, F>(async_fd: &AsyncFd<OwnedFd>, mut call: F) -> io::Result<R>
 where
     F: FnMut(RawFd) -> io::Result<R>,
@@ -21,11 +25,14 @@ where
                 guard.clear_ready();
                 continue;
             }
+            // a signal must not end the connection, and the fd may well still be ready
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
             other => return other,
         }
     }
 }
 
+/// The counterpart of [`wrap_read`] for writing.
 pub async fn wrap_write<R, F>(async_fd: &AsyncFd<OwnedFd>, mut call: F) -> io::Result<R>
 where
     F: FnMut(RawFd) -> io::Result<R>,
@@ -39,7 +46,63 @@ where
                 guard.clear_ready();
                 continue;
             }
+            // a signal must not end the connection, and the fd may well still be ready
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
             other => return other,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::FromRawFd;
+
+    use super::*;
+
+    /// Returns `EINTR` for the first two calls, then the number of calls.
+    fn interrupted_twice(calls: &mut usize) -> io::Result<usize> {
+        *calls += 1;
+        if *calls <= 2 {
+            Err(io::Error::from_raw_os_error(libc::EINTR))
+        } else {
+            Ok(*calls)
+        }
+    }
+
+    #[test]
+    fn retry_interrupted() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut fds = [0 as RawFd; 2];
+        let rc = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(rc, 0);
+        let (ours, theirs) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        // make ours readable
+        assert_eq!(
+            unsafe { libc::write(theirs.as_raw_fd(), b"x".as_ptr() as _, 1) },
+            1
+        );
+
+        runtime.block_on(async {
+            let ours = AsyncFd::new(ours).unwrap();
+
+            let mut calls = 0;
+            let result = wrap_read(&ours, |_| interrupted_twice(&mut calls)).await;
+            assert_eq!(result.unwrap(), 3);
+
+            let mut calls = 0;
+            let result = wrap_write(&ours, |_| interrupted_twice(&mut calls)).await;
+            assert_eq!(result.unwrap(), 3);
+        });
+    }
+}
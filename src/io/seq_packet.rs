@@ -1,4 +1,5 @@
 use std::io::{self, IoSlice, IoSliceMut};
+use std::os::raw::c_int;
 use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::ptr;
 
@@ -30,9 +31,19 @@ impl AsRawFd for SeqPacketListener {
 }
 
 impl SeqPacketListener {
-    pub fn bind(address: &dyn SockaddrLike) -> Result<Self, Error> {
-        let fd = seq_packet_socket(SockFlag::empty())?;
+    /// Bind to `address` and listen.
+    ///
+    /// `prepare` is called in between, eg. to set up the permissions of the socket file before
+    /// anyone can connect.
+    pub fn bind<F>(address: &dyn SockaddrLike, prepare: F) -> Result<Self, Error>
+    where
+        F: FnOnce() -> Result<(), Error>,
+    {
+        // Must be non-blocking, `accept()` may run into a stale readiness state, which must not
+        // block a runtime thread.
+        let fd = seq_packet_socket(SockFlag::SOCK_NONBLOCK)?;
         socket::bind(fd.as_raw_fd(), address)?;
+        prepare()?;
         socket::listen(fd.as_raw_fd(), 16)?;
 
         let fd = AsyncFd::new(fd)?;
@@ -76,6 +87,34 @@ impl SeqPacketSocket {
         })
     }
 
+    /// Use an inherited socket, which must be a connected unix seqpacket socket.
+    ///
+    /// It is made non-blocking (like the sockets we accept) and close-on-exec.
+    pub fn from_connected(fd: OwnedFd) -> io::Result<Self> {
+        let mut domain: c_int = 0;
+        let mut len = std::mem::size_of::<c_int>() as libc::socklen_t;
+        c_try!(unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_DOMAIN,
+                &mut domain as *mut c_int as *mut libc::c_void,
+                &mut len,
+            )
+        });
+        if domain != libc::AF_UNIX
+            || socket::getsockopt(fd.as_raw_fd(), socket::sockopt::SockType)?
+                != SockType::SeqPacket
+        {
+            io_bail!("not a unix seqpacket socket");
+        }
+        socket::getpeername::<socket::UnixAddr>(fd.as_raw_fd())?;
+
+        c_try!(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) });
+        crate::tools::set_fd_nonblocking(&fd, true)?;
+        Self::new(fd)
+    }
+
     async fn sendmsg(&self, msg: &AssertSendSync<libc::msghdr>) -> io::Result<usize> {
         let rc = super::wrap_write(&self.fd, |fd| {
             c_result!(unsafe { libc::sendmsg(fd, &msg.0 as *const libc::msghdr, 0) })
@@ -98,21 +137,30 @@ impl SeqPacketSocket {
         self.sendmsg(&msg).await
     }
 
-    async fn recvmsg(&self, msg: &mut AssertSendSync<libc::msghdr>) -> io::Result<usize> {
+    async fn recvmsg(
+        &self,
+        msg: &mut AssertSendSync<libc::msghdr>,
+        flags: c_int,
+    ) -> io::Result<usize> {
         let rc = super::wrap_read(&self.fd, move |fd| {
-            c_result!(unsafe { libc::recvmsg(fd, &mut msg.0 as *mut libc::msghdr, 0) })
+            c_result!(unsafe { libc::recvmsg(fd, &mut msg.0 as *mut libc::msghdr, flags) })
         })
         .await?;
         Ok(rc as usize)
     }
 
+    /// Receive a message.
+    ///
+    /// Returns the size of the whole datagram, the size of the received control data and the
+    /// message flags. If the datagram was larger than `iov`, its size is still returned and the
+    /// `MSG_TRUNC` flag is set.
     // clippy is wrong about this one
     #[allow(clippy::needless_lifetimes)]
     pub async fn recvmsg_vectored(
         &self,
         iov: &mut [IoSliceMut<'_>],
         cmsg_buf: &mut [u8],
-    ) -> io::Result<(usize, usize)> {
+    ) -> io::Result<(usize, usize, c_int)> {
         let mut msg = AssertSendSync(libc::msghdr {
             msg_name: ptr::null_mut(),
             msg_namelen: 0,
@@ -123,8 +171,14 @@ impl SeqPacketSocket {
             msg_flags: libc::MSG_CMSG_CLOEXEC,
         });
 
-        let data_size = self.recvmsg(&mut msg).await?;
-        Ok((data_size, msg.0.msg_controllen))
+        let data_size = self.recvmsg(&mut msg, libc::MSG_TRUNC).await?;
+        Ok((data_size, msg.0.msg_controllen, msg.0.msg_flags))
+    }
+
+    /// Get the credentials of the peer process as of the time it connected.
+    #[inline]
+    pub fn peer_credentials(&self) -> nix::Result<socket::UnixCredentials> {
+        socket::getsockopt(self.as_raw_fd(), socket::sockopt::PeerCredentials)
     }
 
     #[inline]
@@ -0,0 +1,150 @@
+//! Minimal logging to stderr.
+//!
+//! Messages are written either as plain text lines or, with `--log-format json`, as one JSON
+//! object per line. The JSON objects always contain `level` (`error`, `warn`, `info`, `debug` or
+//! `trace`) and `msg`, and depending on the event any of these fields:
+//!
+//! * `client_id`: the id of the client connection (see `client N connected` messages)
+//! * `init_pid`: the container's init pid as sent by the lxc monitor
+//! * `pid`: the process the message is about
+//! * `syscall`: the name of the syscall
+//! * `result`: the syscall's return value, or the negative errno if it failed (0 if the syscall
+//!   was passed on to the kernel)
+//!
+//! These field names are considered stable. In plain text mode only the message is printed, with
+//! a `warning: ` prefix for warnings.
+//!
+//! Per-syscall messages are logged at the `debug` level, which is disabled by default, see
+//! `--log-level`. Every file opened and read in a process' `/proc` directory is logged at the
+//! `trace` level, to find out which one failed.
+
+use std::fmt::{self, Write as _};
+use std::io::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ]
+        .into_iter()
+        .find(|level| level.as_str() == name)
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Only log messages up to this level.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Switch between plain text and JSON output.
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// A value of an additional field in JSON output.
+pub enum Value<'a> {
+    Int(i64),
+    Str(&'a str),
+}
+
+macro_rules! value_from_int {
+    ($($ty:ty)*) => {$(
+        impl From<$ty> for Value<'_> {
+            fn from(value: $ty) -> Self {
+                Value::Int(value as i64)
+            }
+        }
+    )*};
+}
+
+value_from_int!(i32 u32 i64 u64 usize);
+
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(value: &'a str) -> Self {
+        Value::Str(value)
+    }
+}
+
+/// Write a message, use the `error!()`, `warn!()`, ... macros instead of calling this directly.
+pub fn log(level: Level, fields: &[(&str, Value<'_>)], args: fmt::Arguments<'_>) {
+    let mut line = String::new();
+    if JSON.load(Ordering::Relaxed) {
+        line.push_str("{\"level\":\"");
+        line.push_str(level.as_str());
+        line.push_str("\",\"msg\":");
+        push_json_string(&mut line, &args.to_string());
+        for (name, value) in fields {
+            line.push(',');
+            push_json_string(&mut line, name);
+            line.push(':');
+            match value {
+                Value::Int(value) => {
+                    let _ = write!(line, "{value}");
+                }
+                Value::Str(value) => push_json_string(&mut line, value),
+            }
+        }
+        line.push('}');
+    } else {
+        if level == Level::Warn {
+            line.push_str("warning: ");
+        }
+        let _ = write!(line, "{args}");
+    }
+    line.push('\n');
+
+    // a single write, so lines of concurrent tasks and forked helpers don't get mixed up
+    let _ = std::io::stderr().write_all(line.as_bytes());
+}
+
+/// Append `s` to `out` as a quoted JSON string.
+pub fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -6,7 +6,9 @@ use std::io::{self, IoSlice, IoSliceMut};
 use std::mem;
 use std::os::raw::{c_int, c_uint};
 use std::os::unix::fs::FileExt;
-use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::panic::RefUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use anyhow::{bail, format_err, Error};
 use lazy_static::lazy_static;
@@ -15,8 +17,10 @@ use nix::errno::Errno;
 
 use crate::io::cmsg;
 use crate::io::seq_packet::SeqPacketSocket;
-use crate::process::PidFd;
-use crate::seccomp::{SeccompNotif, SeccompNotifResp, SeccompNotifSizes};
+use crate::metrics::{self, METRICS};
+use crate::process::{EnvCache, PidFd, UserCaps};
+use crate::seccomp::{SeccompNotif, SeccompNotifResp, SeccompNotifRespFlags, SeccompNotifSizes};
+use crate::syscall::SyscallStatus;
 use crate::tools::FromFd;
 
 /// Seccomp notification proxy message sent by the lxc monitor.
@@ -64,31 +68,210 @@ pub struct ProxyMessageBuffer {
     sizes: SeccompNotifSizes,
     seccomp_packet_size: usize,
 
+    max_string: usize,
+    max_mem_read: usize,
+
     pid_fd: Option<PidFd>,
-    mem_fd: Option<std::fs::File>,
+    mem_fd: Option<Box<dyn FileExt + Send + Sync + RefUnwindSafe>>,
+    /// The seccomp notify fd, if the lxc monitor passed it along.
+    notify_fd: Option<OwnedFd>,
+    /// Whether the process' memory may be read directly via `process_vm_readv()`.
+    direct_mem_read: bool,
+
+    /// Since a buffer is reused for all messages of a client connection, this caches process
+    /// data across a burst of requests from the same container.
+    env_cache: EnvCache,
 }
 
-unsafe fn io_vec_mut<T>(value: &mut T) -> IoSliceMut {
+// The layout of `struct seccomp_notify_proxy_msg` in lxc: the sizes are 3 `__u16` followed by
+// 2 bytes of padding before the `__u64` cookie length. The seccomp structures are checked in the
+// `seccomp` module, and their sizes against the kernel's at startup.
+const _: () = {
+    assert!(mem::size_of::<SeccompNotifSizes>() == 6);
+    assert!(mem::size_of::<SeccompNotifyProxyMsg>() == 32);
+    assert!(mem::align_of::<SeccompNotifyProxyMsg>() == 8);
+};
+
+/// Structures sent and received as raw bytes.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` and valid for any bit pattern. Padding bytes are sent as
+/// well, so values must start out zeroed.
+unsafe trait WireData: Sized {}
+
+unsafe impl WireData for SeccompNotifyProxyMsg {}
+unsafe impl WireData for SeccompNotif {}
+unsafe impl WireData for SeccompNotifResp {}
+
+fn io_vec_mut<T: WireData>(value: &mut T) -> IoSliceMut<'_> {
     IoSliceMut::new(unsafe {
         std::slice::from_raw_parts_mut(value as *mut T as *mut u8, mem::size_of::<T>())
     })
 }
 
-unsafe fn io_vec<T>(value: &T) -> IoSlice {
+fn io_vec<T: WireData>(value: &T) -> IoSlice<'_> {
     IoSlice::new(unsafe {
         std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
     })
 }
 
+/// Take ownership of the file descriptors passed in the control messages `cmsgs`.
+///
+/// Other control messages (eg. credentials) may come first, so look at all of them. Only a
+/// single `SCM_RIGHTS` message is expected, but take ownership of the descriptors of any
+/// additional one as well, so they are closed.
+///
+/// # Safety
+///
+/// `cmsgs` must have been received from the kernel, with the descriptors installed in our fd
+/// table and not owned by anything else yet.
+unsafe fn received_fds(cmsgs: &[u8]) -> Result<Vec<OwnedFd>, Error> {
+    let mut fds: Option<Vec<OwnedFd>> = None;
+    let mut rights_messages = 0;
+    for cmsg in cmsg::iter(cmsgs) {
+        if cmsg.cmsg_level != libc::SOL_SOCKET || cmsg.cmsg_type != libc::SCM_RIGHTS {
+            continue;
+        }
+        rights_messages += 1;
+
+        let received: Vec<OwnedFd> = cmsg
+            .data
+            .chunks_exact(mem::size_of::<RawFd>())
+            .map(|chunk| unsafe {
+                // clippy bug
+                #[allow(clippy::cast_ptr_alignment)]
+                OwnedFd::from_raw_fd(std::ptr::read_unaligned(chunk.as_ptr() as _))
+            })
+            .collect();
+        if fds.is_none() {
+            fds = Some(received);
+        }
+    }
+
+    let fds = fds.ok_or_else(|| format_err!("missing file descriptors in message"))?;
+    if rights_messages != 1 {
+        bail!("expected a single SCM_RIGHTS control message, got {rights_messages}");
+    }
+    Ok(fds)
+}
+
 lazy_static! {
     static ref SECCOMP_SIZES: SeccompNotifSizes = SeccompNotifSizes::get_checked()
         .map_err(|e| panic!("{e}\nrefusing to run"))
         .unwrap();
 }
 
+static DIRECT_MEM_READ: AtomicBool = AtomicBool::new(false);
+
+/// Read the memory of processes via `process_vm_readv()` rather than the mem fd, if usable.
+///
+/// This is off by default: for a 4 KiB read it takes about 1.9µs compared to 1.2µs for a
+/// `pread()` on the mem fd, mostly due to verifying the process is still alive afterwards (see
+/// `PidFd::read_mem()`). Measure with the `bench_read_mem` test of the `pid_fd` module.
+pub fn enable_direct_mem_read() {
+    DIRECT_MEM_READ.store(true, Ordering::Relaxed);
+}
+
+pub fn direct_mem_read_enabled() -> bool {
+    DIRECT_MEM_READ.load(Ordering::Relaxed)
+}
+
+/// The default maximum length of strings read from a process' memory, including the terminating
+/// zero byte, see [`ProxyMessageBufferBuilder::max_string`]. This is `PATH_MAX`.
+pub const MAX_STRING: usize = 4096;
+
+/// The default maximum number of bytes read from a process' memory at once, see
+/// [`ProxyMessageBufferBuilder::max_mem_read`].
+pub const MAX_MEM_READ: usize = 64 * 1024;
+
+/// Builder for a [`ProxyMessageBuffer`] with custom limits.
+pub struct ProxyMessageBufferBuilder {
+    max_cookie: usize,
+    max_string: usize,
+    max_mem_read: usize,
+}
+
+impl Default for ProxyMessageBufferBuilder {
+    fn default() -> Self {
+        Self {
+            max_cookie: 64,
+            max_string: MAX_STRING,
+            max_mem_read: MAX_MEM_READ,
+        }
+    }
+}
+
+impl ProxyMessageBufferBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The maximum size of the cookie sent along with a message.
+    pub fn max_cookie(mut self, max_cookie: usize) -> Self {
+        self.max_cookie = max_cookie;
+        self
+    }
+
+    /// The maximum length of strings read from the process' memory, including the terminating
+    /// zero byte.
+    pub fn max_string(mut self, max_string: usize) -> Self {
+        self.max_string = max_string;
+        self
+    }
+
+    /// The maximum number of bytes read from the process' memory at once.
+    ///
+    /// Strings are read at once, so this is raised to `max_string` if smaller.
+    pub fn max_mem_read(mut self, max_mem_read: usize) -> Self {
+        self.max_mem_read = max_mem_read;
+        self
+    }
+
+    pub fn build(self) -> ProxyMessageBuffer {
+        ProxyMessageBuffer::with_limits(self)
+    }
+
+    /// Build a message from an already received seccomp notification, see
+    /// [`ProxyMessageBuffer::from_parts`].
+    pub fn build_from_parts<M>(
+        self,
+        request: SeccompNotif,
+        mem: M,
+        pid_fd: PidFd,
+    ) -> ProxyMessageBuffer
+    where
+        M: FileExt + Send + Sync + RefUnwindSafe + 'static,
+    {
+        let mut this = self.build();
+        this.proxy_msg.monitor_pid = pid_fd.get_pid();
+        this.proxy_msg.init_pid = pid_fd.get_pid();
+        this.proxy_msg.sizes = this.sizes.clone();
+        this.seccomp_notif = request;
+        this.pid_fd = Some(pid_fd);
+        this.mem_fd = Some(Box::new(mem));
+        unsafe {
+            this.cookie_buf.set_len(0);
+        }
+        this.prepare_response();
+        this
+    }
+}
+
 impl ProxyMessageBuffer {
     /// Allocate a new proxy message buffer with a specific maximum cookie size.
+    ///
+    /// Other limits use their defaults, see [`ProxyMessageBuffer::builder`].
     pub fn new(max_cookie: usize) -> Self {
+        Self::builder().max_cookie(max_cookie).build()
+    }
+
+    /// Create a builder to configure the buffer's limits.
+    pub fn builder() -> ProxyMessageBufferBuilder {
+        ProxyMessageBufferBuilder::new()
+    }
+
+    fn with_limits(limits: ProxyMessageBufferBuilder) -> Self {
         let sizes = SECCOMP_SIZES.clone();
 
         let seccomp_packet_size = mem::size_of::<SeccompNotifyProxyMsg>()
@@ -99,17 +282,42 @@ impl ProxyMessageBuffer {
             proxy_msg: unsafe { mem::zeroed() },
             seccomp_notif: unsafe { mem::zeroed() },
             seccomp_resp: unsafe { mem::zeroed() },
-            cookie_buf: unsafe { super::tools::vec::uninitialized(max_cookie) },
+            cookie_buf: unsafe { super::tools::vec::uninitialized(limits.max_cookie) },
             sizes,
             seccomp_packet_size,
+            // we need room for at least the terminating zero
+            max_string: limits.max_string.max(1),
+            // strings are read at once
+            max_mem_read: limits.max_mem_read.max(limits.max_string),
             pid_fd: None,
             mem_fd: None,
+            notify_fd: None,
+            direct_mem_read: false,
+            env_cache: EnvCache::new(),
         }
     }
 
+    /// Create a message from an already received seccomp notification instead of an lxc proxy
+    /// message.
+    ///
+    /// The process' memory is accessed exclusively via `mem`, which can be the process'
+    /// `/proc/<pid>/mem` file, or for testing any other `FileExt` implementation such as a
+    /// temporary file. The cookie is empty and the monitor and init pids are set to the pidfd's
+    /// pid.
+    pub fn from_parts<M>(request: SeccompNotif, mem: M, pid_fd: PidFd) -> Self
+    where
+        M: FileExt + Send + Sync + RefUnwindSafe + 'static,
+    {
+        Self::builder()
+            .max_cookie(1)
+            .build_from_parts(request, mem, pid_fd)
+    }
+
     fn reset(&mut self) {
         self.proxy_msg.cookie_len = 0;
         self.mem_fd = None;
+        self.notify_fd = None;
+        self.direct_mem_read = false;
         self.pid_fd = None;
     }
 
@@ -123,48 +331,43 @@ impl ProxyMessageBuffer {
         }
 
         let mut iovec = [
-            unsafe { io_vec_mut(&mut self.proxy_msg) },
-            unsafe { io_vec_mut(&mut self.seccomp_notif) },
-            unsafe { io_vec_mut(&mut self.seccomp_resp) },
+            io_vec_mut(&mut self.proxy_msg),
+            io_vec_mut(&mut self.seccomp_notif),
+            io_vec_mut(&mut self.seccomp_resp),
             IoSliceMut::new(self.cookie_buf.as_mut_slice()),
         ];
 
         // receive:
-        let mut fd_cmsg_buf = cmsg::buffer::<[RawFd; 2]>();
+        let mut fd_cmsg_buf = cmsg::buffer::<[RawFd; 3]>();
         let result = socket.recvmsg_vectored(&mut iovec, &mut fd_cmsg_buf).await;
         unsafe {
             self.cookie_buf.set_len(0);
         }
-        let (datalen, cmsglen) = result?;
+        let (datalen, cmsglen, msg_flags) = result?;
 
         if datalen == 0 {
             return Ok(false);
         }
 
-        self.set_len(datalen)?;
-
         // iterate through control messages:
-
-        let cmsg = cmsg::iter(&fd_cmsg_buf[..cmsglen])
-            .next()
-            .ok_or_else(|| format_err!("missing file descriptors in message"))?;
-
-        if cmsg.cmsg_level != libc::SOL_SOCKET && cmsg.cmsg_type != libc::SCM_RIGHTS {
-            bail!("expected SCM_RIGHTS control message");
+        //
+        // The kernel already installed any passed file descriptors in our fd table, so we need to
+        // take ownership of them before validating anything else, otherwise we'd leak them when
+        // bailing out.
+        let fds = unsafe { received_fds(&fd_cmsg_buf[..cmsglen]) }?;
+
+        if msg_flags & libc::MSG_TRUNC != 0 {
+            bail!("oversized seccomp message ({} bytes)", datalen);
         }
 
-        let fds: Vec<OwnedFd> = cmsg
-            .data
-            .chunks_exact(mem::size_of::<RawFd>())
-            .map(|chunk| unsafe {
-                // clippy bug
-                #[allow(clippy::cast_ptr_alignment)]
-                OwnedFd::from_raw_fd(std::ptr::read_unaligned(chunk.as_ptr() as _))
-            })
-            .collect();
+        self.set_len(datalen)?;
 
-        if fds.len() != 2 {
-            bail!("expected exactly 2 file descriptors in control message");
+        // Newer lxc versions also pass the seccomp notify fd after the pidfd and memfd.
+        if fds.len() != 2 && fds.len() != 3 {
+            bail!(
+                "expected 2 or 3 file descriptors in control message, got {}",
+                fds.len()
+            );
         }
 
         let mut fds = fds.into_iter();
@@ -179,7 +382,10 @@ impl ProxyMessageBuffer {
             .ok_or_else(|| format_err!("lxc seccomp message without memfd"))?;
 
         self.pid_fd = Some(pid_fd);
-        self.mem_fd = Some(std::fs::File::from_fd(mem_fd));
+        self.mem_fd = Some(Box::new(std::fs::File::from_fd(mem_fd)));
+        self.notify_fd = fds.next();
+        self.direct_mem_read =
+            direct_mem_read_enabled() && crate::process::process_vm_readv_usable();
 
         Ok(true)
     }
@@ -191,6 +397,47 @@ impl ProxyMessageBuffer {
         self.pid_fd.as_ref().unwrap()
     }
 
+    /// Get the seccomp notify fd, if the lxc monitor passed it along with the message.
+    ///
+    /// It stays open until the next message is received into the buffer. Messages created via
+    /// [`from_parts`](Self::from_parts) never have one.
+    #[allow(dead_code)]
+    pub fn notify_fd(&self) -> Option<BorrowedFd<'_>> {
+        self.notify_fd.as_ref().map(|fd| fd.as_fd())
+    }
+
+    /// Get the permission-check environment of the process.
+    ///
+    /// The cgroup and AppArmor data may be served from the client's [`EnvCache`]. Helpers applying
+    /// it verify the process still has the start time it had when this was called. Only requests
+    /// forking a helper need this, so the start time is not read for every message.
+    ///
+    /// Our `/proc/<pid>` descriptor already stops working once the process exits, rather than
+    /// referring to a new process with the same pid, so this is a second line of defense.
+    ///
+    /// Note that the message must be valid, otherwise this panics!
+    pub fn user_caps(&self) -> Result<UserCaps<'_>, Error> {
+        let env = self.env_cache.get(self.init_pid(), self.pid_fd())?;
+        let mut caps = UserCaps::with_env(self.pid_fd(), &env)?;
+        // if it cannot be read, the process is most likely gone already
+        if let Ok(start_time) = self.pid_fd().get_starttime() {
+            caps.verify_start_time(start_time);
+        }
+        Ok(caps)
+    }
+
+    /// Drop cached process data, to be called when handling a request failed.
+    pub fn invalidate_env_cache(&self) {
+        self.env_cache.invalidate();
+    }
+
+    /// The maximum length of strings read from the process' memory, including the terminating
+    /// zero byte.
+    #[inline]
+    pub fn max_string(&self) -> usize {
+        self.max_string
+    }
+
     /// Get the process' mem fd.
     ///
     /// Note that this returns a non-mut trait object. This is because positional I/O does not need
@@ -198,15 +445,50 @@ impl ProxyMessageBuffer {
     ///
     /// Note that the message must be valid, otherwise this panics!
     pub fn mem_fd(&self) -> &dyn FileExt {
-        self.mem_fd.as_ref().unwrap()
+        &**self.mem_fd.as_ref().unwrap()
+    }
+
+    /// Read from the process' memory.
+    ///
+    /// For received messages this uses `process_vm_readv()` if enabled and usable, and falls back
+    /// to the mem fd otherwise. Messages created via [`from_parts`](Self::from_parts) always use
+    /// the provided memory.
+    /// Both paths behave the same: short reads return the number of bytes read, unmapped memory
+    /// causes an `EIO` error.
+    ///
+    /// At most `max_mem_read` bytes are read, larger buffers result in a short read.
+    ///
+    /// Note that the message must be valid, otherwise this panics!
+    pub fn read_mem(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = buf.len().min(self.max_mem_read);
+        let buf = &mut buf[..len];
+
+        if self.direct_mem_read {
+            match self.pid_fd().read_mem(buf, offset) {
+                // not permitted for this process, try the mem fd instead
+                Err(ref err) if matches!(err.raw_os_error(), Some(libc::EPERM | libc::ENOSYS)) => {}
+                other => return other,
+            }
+        }
+
+        self.mem_fd().read_at(buf, offset)
     }
 
     /// Send the current data as response.
     pub async fn respond(&mut self, socket: &SeqPacketSocket) -> io::Result<()> {
+        debug_assert!(
+            !self
+                .seccomp_resp
+                .flags
+                .contains(SeccompNotifRespFlags::CONTINUE)
+                || (self.seccomp_resp.val == 0 && self.seccomp_resp.error == 0),
+            "SECCOMP_USER_NOTIF_FLAG_CONTINUE with a return value",
+        );
+
         let iov = [
-            unsafe { io_vec(&self.proxy_msg) },
-            unsafe { io_vec(&self.seccomp_notif) },
-            unsafe { io_vec(&self.seccomp_resp) },
+            io_vec(&self.proxy_msg),
+            io_vec(&self.seccomp_notif),
+            io_vec(&self.seccomp_resp),
         ];
         let len: usize = iov.iter().map(|e| e.len()).sum();
         if socket.sendmsg_vectored(&iov).await? != len {
@@ -215,6 +497,33 @@ impl ProxyMessageBuffer {
         Ok(())
     }
 
+    /// Fill in the response according to a syscall's result.
+    pub fn set_result(&mut self, result: &SyscallStatus) {
+        let resp = self.response_mut();
+        match *result {
+            SyscallStatus::Ok(val) => {
+                resp.val = val;
+                resp.error = 0;
+            }
+            SyscallStatus::Err(err) => {
+                resp.val = -1;
+                // see `SyscallStatus::Err`: 0 would be a success, and a positive value would be
+                // the return value
+                resp.error = if err > 0 {
+                    -err
+                } else {
+                    error!("invalid errno {err} for syscall response, using EPERM");
+                    -libc::EPERM
+                };
+            }
+            SyscallStatus::Continue => {
+                resp.val = 0;
+                resp.error = 0;
+                resp.flags = SeccompNotifRespFlags::CONTINUE;
+            }
+        }
+    }
+
     #[inline]
     fn prepare_response(&mut self) {
         let id = self.request().id;
@@ -222,22 +531,34 @@ impl ProxyMessageBuffer {
         resp.id = id;
         resp.val = -1;
         resp.error = -libc::ENOSYS;
-        resp.flags = 0;
+        resp.flags = SeccompNotifRespFlags::empty();
     }
 
     /// Called by recv() after the callback returned the new size. This verifies that there's
     /// enough data available.
     fn set_len(&mut self, len: usize) -> Result<(), Error> {
         if len < self.seccomp_packet_size {
-            bail!("seccomp proxy message too short");
+            bail!(
+                "seccomp proxy message too short ({len} bytes, expected at least {}), \
+                 liblxc seccomp notify protocol mismatch",
+                self.seccomp_packet_size,
+            );
         }
 
         if self.proxy_msg.reserved0 != 0 {
             bail!("reserved data wasn't 0, liblxc secocmp notify protocol mismatch");
         }
 
+        // This fails the connection, so a misconfigured monitor causes one log message per
+        // connection attempt, not per message.
         if !self.check_sizes() {
-            bail!("seccomp proxy message content size validation failed");
+            metrics::inc(&METRICS.size_mismatches);
+            self.log_size_mismatch();
+            bail!(
+                "seccomp proxy message content size validation failed (got {}, expected {})",
+                self.proxy_msg.sizes,
+                self.sizes,
+            );
         }
 
         if len - self.seccomp_packet_size > self.cookie_buf.capacity() {
@@ -271,10 +592,27 @@ impl ProxyMessageBuffer {
     }
 
     fn check_sizes(&self) -> bool {
-        let got = self.proxy_msg.sizes.clone();
-        got.notif == self.sizes.notif
-            && got.notif_resp == self.sizes.notif_resp
-            && got.data == self.sizes.data
+        self.proxy_msg.sizes == self.sizes
+    }
+
+    /// Log the details of a size mismatch.
+    ///
+    /// We also query the kernel again, as the most likely causes for this are either an lxc
+    /// monitor built against different kernel headers, or the kernel having changed underneath us
+    /// (the sizes are only queried once at startup).
+    fn log_size_mismatch(&self) {
+        let current = match SeccompNotifSizes::get() {
+            Ok(sizes) => sizes.to_string(),
+            Err(err) => format!("error: {err}"),
+        };
+        error!(
+            "seccomp notification size mismatch from monitor pid {}: \
+             received {}, expected {}, kernel currently reports {}",
+            self.monitor_pid(),
+            self.proxy_msg.sizes,
+            self.sizes,
+            current,
+        );
     }
 
     /// Get the monitor pid from the current message.
@@ -330,7 +668,8 @@ impl ProxyMessageBuffer {
 
     /// Get a parameter as C String where the pointer may be `NULL`.
     ///
-    /// Strings are limited to 4k bytes currently.
+    /// Strings are limited to [`MAX_STRING`] bytes by default, see
+    /// [`ProxyMessageBufferBuilder::max_string`].
     #[inline]
     pub fn arg_opt_c_string(&self, arg: u32) -> Result<Option<CString>, Error> {
         let offset = self.arg(arg)?;
@@ -343,7 +682,8 @@ impl ProxyMessageBuffer {
 
     /// Get a parameter as C String.
     ///
-    /// Strings are limited to 4k bytes currently.
+    /// Strings are limited to [`MAX_STRING`] bytes by default, see
+    /// [`ProxyMessageBufferBuilder::max_string`].
     #[inline]
     pub fn arg_c_string(&self, arg: u32) -> Result<CString, Error> {
         self.arg_opt_c_string(arg)?
@@ -358,7 +698,7 @@ impl ProxyMessageBuffer {
         let slice = unsafe {
             std::slice::from_raw_parts_mut(&mut data as *mut _ as *mut u8, mem::size_of::<T>())
         };
-        let got = self.mem_fd().read_at(slice, offset)?;
+        let got = self.read_mem(slice, offset)?;
         if got != mem::size_of::<T>() {
             Err(Errno::EINVAL.into())
         } else {
@@ -387,27 +727,67 @@ impl ProxyMessageBuffer {
     }
 
     /// Checked way to get a `dev_t` argument.
+    ///
+    /// The kernel takes device numbers as `unsigned int` for every ABI, so like it we only use
+    /// the lower 32 bits, regardless of the [`CallerArch`](crate::syscall::CallerArch).
     #[inline]
     pub fn arg_dev_t(&self, arg: u32) -> Result<nix::sys::stat::dev_t, Error> {
-        self.arg(arg)
+        Ok(nix::sys::stat::dev_t::from(self.arg(arg)? as u32))
     }
 
     /// Checked way to get a file descriptor argument.
+    ///
+    /// `AT_FDCWD` yields the process' working directory, other negative values fail with `EBADF`.
     #[inline]
     pub fn arg_fd(&self, arg: u32, flags: c_int) -> Result<OwnedFd, Error> {
-        let fd = self.arg(arg)? as RawFd;
-        // we pass negative ones 'as-is', others get opened via the pidfd
+        // The kernel takes descriptors as `int` for every ABI, so like it we only use the lower
+        // 32 bits, regardless of the `CallerArch`. This matters for i386 processes, whose
+        // `AT_FDCWD` arrives as 0xffff_ff9c rather than sign extended to 64 bits.
+        let fd = self.arg(arg)? as u32 as RawFd;
+        // other negative ones are invalid, others get opened via the pidfd
         Ok(if fd == libc::AT_FDCWD {
             // NOTE: we could pass this one through, but let's be explicit here, in the future we
             // might want to reuse this one?
             self.pid_fd().fd_cwd()?
         } else if fd < 0 {
-            unsafe { OwnedFd::from_raw_fd(fd) }
+            // like the kernel, and `OwnedFd` must not hold -1 (it panics)
+            return Err(Errno::EBADF.into());
         } else {
-            self.pid_fd().fd_num(fd, flags)?
+            self.get_fd(fd, flags)?
         })
     }
 
+    /// Get one of the process' file descriptors, preferably the actual one via `pidfd_getfd()`,
+    /// otherwise as an `O_PATH` descriptor via `/proc/<pid>/fd` (see `PidFd::fd_num()`).
+    ///
+    /// Of the `flags` only `O_DIRECTORY` is checked for duplicated descriptors, the others only
+    /// apply when opening.
+    fn get_fd(&self, fd: RawFd, flags: c_int) -> io::Result<OwnedFd> {
+        let fd = match self.pid_fd().get_fd(fd) {
+            Ok(fd) => fd,
+            // EINVAL: the process is not the thread group leader, `pidfd_open()` only takes those
+            Err(err)
+                if matches!(
+                    err.raw_os_error(),
+                    Some(libc::ENOSYS) | Some(libc::EPERM) | Some(libc::EINVAL)
+                ) =>
+            {
+                return self.pid_fd().fd_num(fd, flags);
+            }
+            Err(err) => return Err(err),
+        };
+
+        if flags & libc::O_DIRECTORY != 0 {
+            let mut stat: libc::stat = unsafe { mem::zeroed() };
+            c_try!(unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) });
+            if stat.st_mode & libc::S_IFMT != libc::S_IFDIR {
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+            }
+        }
+
+        Ok(fd)
+    }
+
     /// Checked way to get a c_uint argument.
     #[inline]
     pub fn arg_uint(&self, arg: u32) -> Result<c_uint, Error> {
@@ -438,3 +818,475 @@ impl ProxyMessageBuffer {
         Ok(self.arg(arg)? as usize as *const libc::c_char)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    use nix::sys::socket::{self, ControlMessage, MsgFlags};
+
+    use super::*;
+    use crate::syscall::{CallerArch, Syscall};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// A connected pair of seqpacket sockets, the first one for the proxy, the other one for the
+    /// monitor side of the test.
+    fn socketpair() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0 as RawFd; 2];
+        let rc = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(rc, 0);
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    /// A `mknod()` request of the current process.
+    fn mknod_request(mode: libc::mode_t, dev: libc::dev_t) -> SeccompNotif {
+        let mut request: SeccompNotif = unsafe { mem::zeroed() };
+        request.id = 42;
+        request.pid = unsafe { libc::getpid() } as u32;
+        request.data.arch = crate::sandbox::AUDIT_ARCH_X86_64;
+        request.data.nr = libc::SYS_mknod as c_int;
+        request.data.args[1] = u64::from(mode);
+        request.data.args[2] = dev;
+        request
+    }
+
+    /// Send a request like the lxc monitor does, with the requesting process' pidfd and mem fd.
+    fn send_request(socket: &OwnedFd, request: &SeccompNotif) {
+        send_request_with_fds(socket, request, &[]);
+    }
+
+    /// Like [`send_request`], with `extra_fds` after the pidfd and mem fd.
+    fn send_request_with_fds(socket: &OwnedFd, request: &SeccompNotif, extra_fds: &[RawFd]) {
+        let mut proxy_msg: SeccompNotifyProxyMsg = unsafe { mem::zeroed() };
+        proxy_msg.monitor_pid = unsafe { libc::getpid() };
+        proxy_msg.init_pid = proxy_msg.monitor_pid;
+        proxy_msg.sizes = SECCOMP_SIZES.clone();
+        let resp: SeccompNotifResp = unsafe { mem::zeroed() };
+
+        let pid_fd = std::fs::File::open(format!("/proc/{}", request.pid)).unwrap();
+        let mem_fd = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/{}/mem", request.pid))
+            .unwrap();
+        let mut fds = vec![pid_fd.as_raw_fd(), mem_fd.as_raw_fd()];
+        fds.extend_from_slice(extra_fds);
+        socket::sendmsg::<()>(
+            socket.as_raw_fd(),
+            &[io_vec(&proxy_msg), io_vec(request), io_vec(&resp)],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+    }
+
+    /// Receive the proxy's response on the monitor side.
+    fn recv_response(socket: &OwnedFd) -> SeccompNotifResp {
+        let mut proxy_msg: SeccompNotifyProxyMsg = unsafe { mem::zeroed() };
+        let mut request: SeccompNotif = unsafe { mem::zeroed() };
+        let mut resp: SeccompNotifResp = unsafe { mem::zeroed() };
+        let mut iov = [
+            io_vec_mut(&mut proxy_msg),
+            io_vec_mut(&mut request),
+            io_vec_mut(&mut resp),
+        ];
+        socket::recvmsg::<()>(socket.as_raw_fd(), &mut iov, None, MsgFlags::empty()).unwrap();
+        resp
+    }
+
+    /// A memory file holding `data` at `offset`, standing in for the process' memory.
+    fn fake_mem(offset: u64, data: &[u8]) -> std::fs::File {
+        let fd = unsafe { libc::memfd_create(b"mem\0".as_ptr() as _, libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let mem = unsafe { std::fs::File::from_raw_fd(fd) };
+        mem.write_all_at(data, offset).unwrap();
+        mem
+    }
+
+    #[test]
+    fn from_parts_reads_fake_memory() {
+        const PATH: u64 = 0x1000;
+        const DATA: u64 = 0x2000;
+
+        let mut request = mknod_request(libc::S_IFBLK | 0o600, libc::makedev(8, 0));
+        request.data.args[0] = PATH;
+        request.data.args[3] = DATA;
+        let mem = fake_mem(PATH, b"/dev/sda\0");
+        mem.write_all_at(&0x1234_5678u32.to_ne_bytes(), DATA)
+            .unwrap();
+
+        let msg = ProxyMessageBuffer::from_parts(request, mem, PidFd::current().unwrap());
+        assert_eq!(msg.arg_c_string(0).unwrap().as_bytes(), b"/dev/sda");
+        assert_eq!(msg.arg_struct_by_ptr::<u32>(3).unwrap(), 0x1234_5678);
+
+        // the device check denies block devices before the path is even used
+        let result = runtime().block_on(crate::sys_mknod::mknod(&msg)).unwrap();
+        assert!(matches!(result, SyscallStatus::Err(libc::EPERM)));
+    }
+
+    #[test]
+    fn arg_fd_truncates_at_fdcwd() {
+        fn stat(fd: RawFd) -> (libc::dev_t, libc::ino_t) {
+            let mut stat: libc::stat = unsafe { mem::zeroed() };
+            assert_eq!(unsafe { libc::fstat(fd, &mut stat) }, 0);
+            (stat.st_dev, stat.st_ino)
+        }
+        let cwd = std::fs::File::open(".").unwrap();
+
+        let mut request = mknod_request(libc::S_IFCHR | 0o666, libc::makedev(1, 3));
+        // i386 passes AT_FDCWD in a 32 bit register, x86_64 sign extends it
+        request.data.args[0] = 0xffff_ff9c;
+        request.data.args[1] = 0xffff_ffff_ffff_ff9c;
+        let msg =
+            ProxyMessageBuffer::from_parts(request, fake_mem(0, b""), PidFd::current().unwrap());
+        for arg in 0..2 {
+            let fd = msg.arg_fd(arg, libc::O_DIRECTORY).unwrap();
+            assert_eq!(stat(fd.as_raw_fd()), stat(cwd.as_raw_fd()), "arg {arg}");
+        }
+
+        // the upper half is ignored for descriptors, like the kernel does
+        let mut request = mknod_request(libc::S_IFCHR | 0o666, libc::makedev(1, 3));
+        request.data.args[0] = 0x1_0000_0000 | cwd.as_raw_fd() as u64;
+        let msg =
+            ProxyMessageBuffer::from_parts(request, fake_mem(0, b""), PidFd::current().unwrap());
+        let fd = msg.arg_fd(0, libc::O_DIRECTORY).unwrap();
+        assert_eq!(stat(fd.as_raw_fd()), stat(cwd.as_raw_fd()));
+
+        // -1 and other negative descriptors
+        for value in [u64::MAX, 0xffff_fffe] {
+            let mut request = mknod_request(libc::S_IFCHR | 0o666, libc::makedev(1, 3));
+            request.data.args[0] = value;
+            let msg = ProxyMessageBuffer::from_parts(
+                request,
+                fake_mem(0, b""),
+                PidFd::current().unwrap(),
+            );
+            let err = msg.arg_fd(0, libc::O_DIRECTORY).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<Errno>(),
+                Some(&Errno::EBADF),
+                "{value:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn arg_fd_duplicates_descriptor() {
+        let file = fake_mem(0, b"0123456789");
+        assert_eq!(
+            unsafe { libc::lseek(file.as_raw_fd(), 3, libc::SEEK_SET) },
+            3
+        );
+
+        let mut request = mknod_request(libc::S_IFCHR | 0o666, libc::makedev(1, 3));
+        request.data.args[0] = file.as_raw_fd() as u64;
+        let msg =
+            ProxyMessageBuffer::from_parts(request, fake_mem(0, b""), PidFd::current().unwrap());
+
+        // pidfd_getfd() gives us the same open file, sharing its offset, rather than a new one
+        let fd = msg.arg_fd(0, 0).unwrap();
+        assert_ne!(fd.as_raw_fd(), file.as_raw_fd());
+        assert_eq!(unsafe { libc::lseek(fd.as_raw_fd(), 0, libc::SEEK_CUR) }, 3);
+        assert_eq!(unsafe { libc::lseek(fd.as_raw_fd(), 5, libc::SEEK_SET) }, 5);
+        assert_eq!(
+            unsafe { libc::lseek(file.as_raw_fd(), 0, libc::SEEK_CUR) },
+            5
+        );
+
+        // a duplicated descriptor is not opened again, so O_DIRECTORY is checked separately
+        let err = msg.arg_fd(0, libc::O_DIRECTORY).unwrap_err();
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+    }
+
+    /// A control message with `fds` as data, as `recvmsg()` would store it.
+    fn control_message(level: c_int, kind: c_int, fds: &[RawFd]) -> Vec<u8> {
+        let len = mem::size_of_val(fds);
+        let mut buf = vec![0u8; cmsg::space(len)];
+        let mut hdr: libc::cmsghdr = unsafe { mem::zeroed() };
+        hdr.cmsg_len = mem::size_of::<libc::cmsghdr>() + len;
+        hdr.cmsg_level = level;
+        hdr.cmsg_type = kind;
+        unsafe { std::ptr::write_unaligned(buf.as_mut_ptr() as *mut libc::cmsghdr, hdr) };
+        for (i, fd) in fds.iter().enumerate() {
+            let offset = mem::size_of::<libc::cmsghdr>() + i * mem::size_of::<RawFd>();
+            buf[offset..(offset + mem::size_of::<RawFd>())].copy_from_slice(&fd.to_ne_bytes());
+        }
+        buf
+    }
+
+    fn is_open(fd: RawFd) -> bool {
+        unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
+    }
+
+    #[test]
+    fn received_fds_checks_level_and_type() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let fd = file.as_raw_fd();
+
+        // only SOL_SOCKET/SCM_RIGHTS carries descriptors, nothing else may be taken as one
+        for (level, kind) in [
+            (libc::SOL_IP, libc::SCM_RIGHTS),
+            (libc::SOL_SOCKET, libc::SCM_CREDENTIALS),
+        ] {
+            let buf = control_message(level, kind, &[fd]);
+            let err = unsafe { received_fds(&buf) }.unwrap_err();
+            assert_eq!(err.to_string(), "missing file descriptors in message");
+            assert!(is_open(fd), "descriptor of ({level}, {kind}) was taken");
+        }
+    }
+
+    #[test]
+    fn received_fds_messages() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let fd = file.as_raw_fd();
+
+        // other control messages are skipped
+        let received = unsafe { libc::dup(fd) };
+        let mut buf = control_message(libc::SOL_IP, libc::SCM_RIGHTS, &[fd]);
+        buf.extend(control_message(
+            libc::SOL_SOCKET,
+            libc::SCM_RIGHTS,
+            &[received],
+        ));
+        let fds = unsafe { received_fds(&buf) }.unwrap();
+        assert_eq!(fds.len(), 1);
+        assert_eq!(fds[0].as_raw_fd(), received);
+        assert!(is_open(fd));
+
+        // more than one SCM_RIGHTS message
+        let received = [unsafe { libc::dup(fd) }, unsafe { libc::dup(fd) }];
+        let mut buf = control_message(libc::SOL_SOCKET, libc::SCM_RIGHTS, &received[..1]);
+        buf.extend(control_message(
+            libc::SOL_SOCKET,
+            libc::SCM_RIGHTS,
+            &received[1..],
+        ));
+        let err = unsafe { received_fds(&buf) }.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected a single SCM_RIGHTS control message, got 2"
+        );
+    }
+
+    #[test]
+    fn set_result_negates_errno() {
+        let request = mknod_request(libc::S_IFCHR | 0o666, libc::makedev(1, 3));
+        let mut msg =
+            ProxyMessageBuffer::from_parts(request, fake_mem(0, b""), PidFd::current().unwrap());
+
+        msg.set_result(&SyscallStatus::Err(libc::EPERM));
+        assert_eq!(
+            (msg.seccomp_resp.val, msg.seccomp_resp.error),
+            (-1, -libc::EPERM)
+        );
+        msg.set_result(&SyscallStatus::Ok(0));
+        assert_eq!((msg.seccomp_resp.val, msg.seccomp_resp.error), (0, 0));
+
+        // neither a success nor a return value
+        for invalid in [0, -libc::ENOENT] {
+            msg.set_result(&SyscallStatus::Err(invalid));
+            assert_eq!(
+                (msg.seccomp_resp.val, msg.seccomp_resp.error),
+                (-1, -libc::EPERM)
+            );
+        }
+    }
+
+    #[test]
+    fn recv_handle_respond_cycle() {
+        runtime().block_on(async {
+            let (ours, theirs) = socketpair();
+            let socket = SeqPacketSocket::from_connected(ours).unwrap();
+
+            // block devices are always denied
+            send_request(
+                &theirs,
+                &mknod_request(libc::S_IFBLK | 0o600, libc::makedev(8, 0)),
+            );
+
+            let mut msg = ProxyMessageBuffer::new(64);
+            assert!(msg.recv(&socket).await.unwrap());
+            assert_eq!(msg.pid_fd().get_pid(), unsafe { libc::getpid() });
+            let result = crate::client::handle_request(&msg, CallerArch::X86_64, Syscall::Mknod)
+                .await
+                .unwrap();
+            msg.set_result(&result);
+            msg.respond(&socket).await.unwrap();
+
+            let resp = recv_response(&theirs);
+            assert_eq!(resp.id, 42);
+            assert_eq!(resp.val, -1);
+            assert_eq!(resp.error, -libc::EPERM);
+
+            // the monitor hanging up is an EOF
+            drop(theirs);
+            assert!(!msg.recv(&socket).await.unwrap());
+        });
+
+        crate::tools::assert_no_leaked_fds();
+    }
+
+    #[test]
+    fn recv_keeps_notify_fd() {
+        runtime().block_on(async {
+            let (ours, theirs) = socketpair();
+            let socket = SeqPacketSocket::from_connected(ours).unwrap();
+
+            // newer lxc versions also pass the seccomp notify fd, we use a pipe's write end to
+            // see when it is closed
+            let mut fds = [0 as RawFd; 2];
+            let rc = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) };
+            assert_eq!(rc, 0);
+            let (read, write) =
+                unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+            let request = mknod_request(libc::S_IFBLK | 0o600, libc::makedev(8, 0));
+            send_request_with_fds(&theirs, &request, &[write.as_raw_fd()]);
+            drop(write);
+
+            let pipe_closed = || {
+                let mut buf = [0u8; 1];
+                let got = unsafe { libc::read(read.as_raw_fd(), buf.as_mut_ptr() as _, 1) };
+                match got {
+                    0 => true,
+                    _ => {
+                        assert_eq!(io::Error::last_os_error().kind(), io::ErrorKind::WouldBlock);
+                        false
+                    }
+                }
+            };
+
+            let mut msg = ProxyMessageBuffer::new(64);
+            assert!(msg.recv(&socket).await.unwrap());
+            assert_eq!(msg.pid_fd().get_pid(), unsafe { libc::getpid() });
+            assert!(msg.notify_fd().is_some());
+            assert!(!pipe_closed());
+
+            // replaced by the next message, which comes without one
+            send_request(&theirs, &request);
+            assert!(msg.recv(&socket).await.unwrap());
+            assert!(msg.notify_fd().is_none());
+            assert!(pipe_closed());
+        });
+
+        crate::tools::assert_no_leaked_fds();
+    }
+
+    /// Like `--selftest`, but pass the notification on to the proxy like lxc does, so the whole
+    /// path from receiving the proxy message to creating the node runs against the kernel.
+    #[test]
+    #[ignore] // needs root and seccomp user notifications
+    fn seccomp_notification_via_proxy() {
+        // as done on startup, helpers cannot get capabilities beyond it
+        let bounding = PidFd::current().unwrap().get_bounding_set().unwrap();
+        crate::capability::set_bounding_set(bounding);
+
+        runtime()
+            .block_on(crate::selftest::run_child(|listener| async move {
+                let request = crate::selftest::recv_notification(&listener)?;
+
+                let (ours, theirs) = socketpair();
+                let socket = SeqPacketSocket::from_connected(ours)?;
+                send_request(&theirs, &request);
+
+                let mut msg = ProxyMessageBuffer::new(64);
+                assert!(msg.recv(&socket).await?);
+                let data = &msg.request().data;
+                let arch = CallerArch::from_notification(data.arch, data.nr).unwrap();
+                let syscall = crate::syscall::translate_syscall(arch, data.nr).unwrap();
+                assert_eq!(syscall, Syscall::MknodAt);
+                let result = crate::client::handle_request(&msg, arch, syscall).await?;
+                msg.set_result(&result);
+                msg.respond(&socket).await?;
+
+                let mut resp = recv_response(&theirs);
+                assert_eq!(resp.id, request.id);
+                crate::selftest::send_response(&listener, &mut resp)?;
+                Ok(result)
+            }))
+            .unwrap();
+    }
+
+    #[test]
+    fn recv_oversized_message() {
+        runtime().block_on(async {
+            let (ours, theirs) = socketpair();
+            let socket = SeqPacketSocket::from_connected(ours).unwrap();
+
+            // a cookie beyond the buffer's 64 bytes
+            let mut proxy_msg: SeccompNotifyProxyMsg = unsafe { mem::zeroed() };
+            proxy_msg.monitor_pid = unsafe { libc::getpid() };
+            proxy_msg.init_pid = proxy_msg.monitor_pid;
+            proxy_msg.sizes = SECCOMP_SIZES.clone();
+            proxy_msg.cookie_len = 128;
+            let request = mknod_request(libc::S_IFCHR | 0o666, libc::makedev(1, 3));
+            let resp: SeccompNotifResp = unsafe { mem::zeroed() };
+            let cookie = [b'x'; 128];
+
+            let pid_fd = std::fs::File::open("/proc/self").unwrap();
+            let mem_fd = std::fs::File::open("/proc/self/mem").unwrap();
+            let fds = [pid_fd.as_raw_fd(), mem_fd.as_raw_fd()];
+            socket::sendmsg::<()>(
+                theirs.as_raw_fd(),
+                &[
+                    io_vec(&proxy_msg),
+                    io_vec(&request),
+                    io_vec(&resp),
+                    IoSlice::new(&cookie),
+                ],
+                &[ControlMessage::ScmRights(&fds)],
+                MsgFlags::empty(),
+                None,
+            )
+            .unwrap();
+
+            let mut msg = ProxyMessageBuffer::new(64);
+            let err = msg.recv(&socket).await.unwrap_err();
+            assert!(err.to_string().contains("oversized"), "{err}");
+        });
+
+        crate::tools::assert_no_leaked_fds();
+    }
+
+    #[test]
+    fn recv_error_closes_fds() {
+        runtime().block_on(async {
+            let (ours, theirs) = socketpair();
+            let socket = SeqPacketSocket::from_connected(ours).unwrap();
+
+            // only the proxy message header, but with the descriptors
+            let proxy_msg: SeccompNotifyProxyMsg = unsafe { mem::zeroed() };
+            let pid_fd = std::fs::File::open("/proc/self").unwrap();
+            let mem_fd = std::fs::File::open("/proc/self/mem").unwrap();
+            let fds = [pid_fd.as_raw_fd(), mem_fd.as_raw_fd()];
+            socket::sendmsg::<()>(
+                theirs.as_raw_fd(),
+                &[io_vec(&proxy_msg)],
+                &[ControlMessage::ScmRights(&fds)],
+                MsgFlags::empty(),
+                None,
+            )
+            .unwrap();
+
+            let mut msg = ProxyMessageBuffer::new(64);
+            let err = msg.recv(&socket).await.unwrap_err();
+            assert!(err.to_string().contains("too short"), "{err}");
+        });
+
+        crate::tools::assert_no_leaked_fds();
+    }
+}
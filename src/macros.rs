@@ -72,3 +72,43 @@ macro_rules! io_bail {
         return Err(::std::io::Error::new(::std::io::ErrorKind::Other, format!($($msg)*)));
     };
 }
+
+/// `log!(Level::Info, key = value, ...; "format", args...)`, see the `log` module.
+macro_rules! log {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($msg:tt)+) => {{
+        let level = $level;
+        if $crate::log::enabled(level) {
+            $crate::log::log(
+                level,
+                &[$((stringify!($key), $crate::log::Value::from($value))),+],
+                format_args!($($msg)+),
+            );
+        }
+    }};
+    ($level:expr, $($msg:tt)+) => {{
+        let level = $level;
+        if $crate::log::enabled(level) {
+            $crate::log::log(level, &[], format_args!($($msg)+));
+        }
+    }};
+}
+
+macro_rules! error {
+    ($($args:tt)+) => { log!($crate::log::Level::Error, $($args)+) };
+}
+
+macro_rules! warn {
+    ($($args:tt)+) => { log!($crate::log::Level::Warn, $($args)+) };
+}
+
+macro_rules! info {
+    ($($args:tt)+) => { log!($crate::log::Level::Info, $($args)+) };
+}
+
+macro_rules! debug {
+    ($($args:tt)+) => { log!($crate::log::Level::Debug, $($args)+) };
+}
+
+macro_rules! trace {
+    ($($args:tt)+) => { log!($crate::log::Level::Trace, $($args)+) };
+}
@@ -1,33 +1,48 @@
 #![deny(unsafe_op_in_unsafe_fn)]
+// Lints of newer toolchains than the minimum supported one, which the older code predates.
+#![allow(unknown_lints, mismatched_lifetime_syntaxes, clippy::incompatible_msrv)]
 
 use std::ffi::{OsStr, OsString};
 use std::future::Future;
 use std::io as StdIo;
 use std::io::{stderr, stdout, Write};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::Poll;
 
 use anyhow::{bail, format_err, Error};
-use nix::sys::socket::UnixAddr;
+use nix::sys::socket::{UnixAddr, UnixCredentials};
 
 #[macro_use]
 mod macros;
 
 pub mod apparmor;
+pub mod audit;
 pub mod capability;
+pub mod check;
 pub mod client;
 pub mod fork;
 pub mod io;
+pub mod log;
 pub mod lxcseccomp;
+pub mod metrics;
 pub mod nsfd;
 pub mod poll_fn;
 pub mod process;
+pub mod sandbox;
 pub mod seccomp;
+pub mod selftest;
+pub mod sighup;
+pub mod sys_chown;
 pub mod sys_mknod;
+pub mod sys_mount;
 pub mod sys_quotactl;
+pub mod sys_rename;
 pub mod syscall;
 pub mod tools;
 
-use crate::io::seq_packet::SeqPacketListener;
+use crate::io::seq_packet::{SeqPacketListener, SeqPacketSocket};
 
 #[track_caller]
 pub fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
@@ -39,11 +54,66 @@ fn usage(status: i32, program: &OsStr, out: &mut dyn Write) -> ! {
     let _ = out.write_all(program.as_bytes());
     let _ = out.write_all(
         concat!(
-            "[options] SOCKET_PATH\n",
+            "[options] SOCKET_PATH...\n",
+            "clients may connect to any of the sockets, \
+             paths starting with '@' refer to abstract unix sockets\n",
+            "(any process in our network namespace can bind such a name before we do and \
+             impersonate us, so only use them in a network namespace of our own)\n",
             "options:\n",
             "    -h, --help      show this help message\n",
+            "    --check         check whether the kernel supports everything we need and exit\n",
+            "    --selftest      handle a syscall of a child process as a test and exit\n",
             "    --system        \
                      run as systemd daemon (use sd_notify() when ready to accept connections)\n",
+            "    --syscall-timeout SECS\n",
+            "                    \
+                     kill helpers not done after SECS seconds (default: 0, never)\n",
+            "    --sandbox-helpers\n",
+            "                    \
+                     only allow helpers the syscalls they need (via a seccomp filter)\n",
+            "    --connection-fd FD\n",
+            "                    serve only the connection passed as FD, exit once it is closed\n",
+            "    --status-socket PATH\n",
+            "                    serve runtime counters on an additional socket\n",
+            "    --audit-log FILE\n",
+            "                    append a record of every mknod() request to FILE\n",
+            "    --device-allow-list FILE\n",
+            "                    read the character devices mknod() may create from FILE\n",
+            "    --timing-histograms\n",
+            "                    \
+                     include histograms of syscall and helper durations in the status\n",
+            "    --allow-uid UID accept connections from this uid in addition to root\n",
+            "    --socket-mode OCTAL\n",
+            "                    permissions of the socket files (default: 600)\n",
+            "    --socket-owner UID[:GID]\n",
+            "                    owner of the socket files\n",
+            "    --no-unlink     \
+                     fail if a socket file exists instead of replacing it\n",
+            "    --min-umask OCTAL\n",
+            "                    \
+                     umask bits to always apply to newly created files and device nodes\n",
+            "    --direct-mem-read\n",
+            "                    \
+                     read process memory via process_vm_readv() instead of the mem fd\n",
+            "    --verify-monitor\n",
+            "                    \
+                     reject messages not sent by the monitor process they name\n",
+            "    --expect-cookie-file FILE\n",
+            "                    only handle messages whose lxc.seccomp.notify.cookie is in FILE\n",
+            "    --helper-cgroup-subtree NAME\n",
+            "                    \
+                     run helpers in a child cgroup NAME of the container's cgroup\n",
+            "    --enable-syscall NAME\n",
+            "                    \
+                     handle this syscall even if off by default (umount2), may be repeated\n",
+            "    --disable-syscall NAME\n",
+            "                    \
+                     do not handle this syscall (fail with ENOSYS), may be repeated\n",
+            "    --log-format plain|json\n",
+            "                    write log messages as plain text (default) or JSON lines\n",
+            "    --log-level LEVEL\n",
+            "                    \
+                     one of error, warn, info (default), debug, trace\n",
         )
         .as_bytes(),
     );
@@ -55,20 +125,25 @@ fn main() {
     let program = args.next().unwrap(); // program name always exists
 
     let mut use_sd_notify = false;
-    let mut path = None;
-
-    let mut nonopt_arg = |arg: OsString| {
-        if path.is_some() {
-            let _ = stderr().write_all(b"unexpected extra parameter: ");
-            let _ = stderr().write_all(arg.as_bytes());
-            let _ = stderr().write_all(b"\n");
-            usage(1, &program, &mut stderr());
-        }
-
-        path = Some(arg);
+    let mut check = false;
+    let mut selftest = false;
+    let mut status_path = None;
+    let mut connection_fd = None;
+    let mut audit_path = None;
+    let mut device_list_path = None;
+    let mut cookie_path = None;
+    let mut allow_uid = None;
+    let mut permissions = SocketPermissions {
+        mode: 0o600,
+        uid: None,
+        gid: None,
+        replace: true,
     };
+    let mut paths = Vec::new();
 
-    for arg in &mut args {
+    let mut nonopt_arg = |arg: OsString| paths.push(arg);
+
+    while let Some(arg) = args.next() {
         if arg == "-h" || arg == "--help" {
             usage(0, &program, &mut stdout());
         }
@@ -77,6 +152,150 @@ fn main() {
             break;
         } else if arg == "--system" {
             use_sd_notify = true;
+        } else if arg == "--timing-histograms" {
+            metrics::enable_histograms();
+        } else if arg == "--direct-mem-read" {
+            lxcseccomp::enable_direct_mem_read();
+        } else if arg == "--verify-monitor" {
+            client::enable_verify_monitor();
+        } else if arg == "--no-unlink" {
+            permissions.replace = false;
+        } else if arg == "--sandbox-helpers" {
+            sandbox::enable();
+        } else if arg == "--check" {
+            check = true;
+        } else if arg == "--selftest" {
+            selftest = true;
+        } else if arg == "--status-socket" {
+            status_path = Some(option_value(&mut args, &arg, &program));
+        } else if arg == "--audit-log" {
+            audit_path = Some(option_value(&mut args, &arg, &program));
+        } else if arg == "--device-allow-list" {
+            device_list_path = Some(option_value(&mut args, &arg, &program));
+        } else if arg == "--min-umask" {
+            let value = option_value(&mut args, &arg, &program);
+            match value
+                .to_str()
+                .and_then(|v| libc::mode_t::from_str_radix(v, 8).ok())
+                .filter(|mask| *mask <= 0o777)
+            {
+                Some(mask) => process::set_min_umask(mask),
+                None => {
+                    let _ = stderr().write_all(b"invalid umask: ");
+                    let _ = stderr().write_all(value.as_bytes());
+                    let _ = stderr().write_all(b"\n");
+                    usage(1, &program, &mut stderr());
+                }
+            }
+        } else if arg == "--connection-fd" {
+            let value = option_value(&mut args, &arg, &program);
+            match value.to_str().and_then(|v| v.parse::<RawFd>().ok()) {
+                Some(fd) if fd >= 0 => connection_fd = Some(fd),
+                _ => {
+                    let _ = stderr().write_all(b"invalid file descriptor: ");
+                    let _ = stderr().write_all(value.as_bytes());
+                    let _ = stderr().write_all(b"\n");
+                    usage(1, &program, &mut stderr());
+                }
+            }
+        } else if arg == "--syscall-timeout" {
+            let value = option_value(&mut args, &arg, &program);
+            match value.to_str().and_then(|v| v.parse::<u32>().ok()) {
+                Some(secs) => fork::set_timeout(secs),
+                None => {
+                    let _ = stderr().write_all(b"invalid timeout: ");
+                    let _ = stderr().write_all(value.as_bytes());
+                    let _ = stderr().write_all(b"\n");
+                    usage(1, &program, &mut stderr());
+                }
+            }
+        } else if arg == "--expect-cookie-file" {
+            cookie_path = Some(option_value(&mut args, &arg, &program));
+        } else if arg == "--helper-cgroup-subtree" {
+            let value = option_value(&mut args, &arg, &program);
+            let bytes = value.as_bytes();
+            if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') {
+                let _ = stderr().write_all(b"invalid cgroup name: ");
+                let _ = stderr().write_all(bytes);
+                let _ = stderr().write_all(b"\n");
+                usage(1, &program, &mut stderr());
+            }
+            process::set_helper_cgroup_subtree(value);
+        } else if arg == "--enable-syscall" || arg == "--disable-syscall" {
+            let value = option_value(&mut args, &arg, &program);
+            match value.to_str().and_then(syscall::Syscall::from_name) {
+                Some(syscall) if arg == "--enable-syscall" => syscall.enable(),
+                Some(syscall) => syscall.disable(),
+                None => {
+                    let _ = stderr().write_all(b"unknown syscall: ");
+                    let _ = stderr().write_all(value.as_bytes());
+                    let _ = stderr().write_all(b"\n");
+                    usage(1, &program, &mut stderr());
+                }
+            }
+        } else if arg == "--log-format" {
+            let value = option_value(&mut args, &arg, &program);
+            if value == "json" {
+                log::set_json(true);
+            } else if value == "plain" {
+                log::set_json(false);
+            } else {
+                let _ = stderr().write_all(b"invalid log format: ");
+                let _ = stderr().write_all(value.as_bytes());
+                let _ = stderr().write_all(b"\n");
+                usage(1, &program, &mut stderr());
+            }
+        } else if arg == "--log-level" {
+            let value = option_value(&mut args, &arg, &program);
+            match value.to_str().and_then(log::Level::from_name) {
+                Some(level) => log::set_max_level(level),
+                None => {
+                    let _ = stderr().write_all(b"invalid log level: ");
+                    let _ = stderr().write_all(value.as_bytes());
+                    let _ = stderr().write_all(b"\n");
+                    usage(1, &program, &mut stderr());
+                }
+            }
+        } else if arg == "--socket-mode" {
+            let value = option_value(&mut args, &arg, &program);
+            match value
+                .to_str()
+                .and_then(|v| libc::mode_t::from_str_radix(v, 8).ok())
+                .filter(|mode| *mode <= 0o777)
+            {
+                Some(mode) => permissions.mode = mode,
+                None => {
+                    let _ = stderr().write_all(b"invalid socket mode: ");
+                    let _ = stderr().write_all(value.as_bytes());
+                    let _ = stderr().write_all(b"\n");
+                    usage(1, &program, &mut stderr());
+                }
+            }
+        } else if arg == "--socket-owner" {
+            let value = option_value(&mut args, &arg, &program);
+            match value.to_str().and_then(parse_owner) {
+                Some((uid, gid)) => {
+                    permissions.uid = Some(uid);
+                    permissions.gid = gid;
+                }
+                None => {
+                    let _ = stderr().write_all(b"invalid socket owner: ");
+                    let _ = stderr().write_all(value.as_bytes());
+                    let _ = stderr().write_all(b"\n");
+                    usage(1, &program, &mut stderr());
+                }
+            }
+        } else if arg == "--allow-uid" {
+            let value = option_value(&mut args, &arg, &program);
+            match value.to_str().and_then(|v| v.parse::<libc::uid_t>().ok()) {
+                Some(uid) => allow_uid = Some(uid),
+                None => {
+                    let _ = stderr().write_all(b"invalid uid: ");
+                    let _ = stderr().write_all(value.as_bytes());
+                    let _ = stderr().write_all(b"\n");
+                    usage(1, &program, &mut stderr());
+                }
+            }
         } else {
             if arg.as_bytes().starts_with(b"-") {
                 let _ = stderr().write_all(b"unexpected option: ");
@@ -93,52 +312,365 @@ fn main() {
         nonopt_arg(arg);
     }
 
-    let path = match path {
-        Some(path) => path,
-        None => {
-            eprintln!("missing path");
-            usage(1, &program, &mut stderr());
+    if check {
+        std::process::exit(if check::run() { 0 } else { 1 });
+    }
+
+    if connection_fd.is_some() && (!paths.is_empty() || status_path.is_some()) {
+        eprintln!("--connection-fd cannot be combined with socket paths or --status-socket");
+        usage(1, &program, &mut stderr());
+    }
+
+    if paths.is_empty() && connection_fd.is_none() && !selftest {
+        eprintln!("missing path");
+        usage(1, &program, &mut stderr());
+    }
+
+    // Without these, every request would fail in the helpers, so rather refuse to start.
+    match check::missing_capabilities() {
+        Ok(missing) if missing.is_empty() => (),
+        Ok(missing) => {
+            error!("missing required capabilities: {}", missing.join(", "));
+            std::process::exit(1);
         }
-    };
+        Err(err) => {
+            error!("failed to read our own capabilities: {err}");
+            std::process::exit(1);
+        }
+    }
+
+    match process::PidFd::current().and_then(|pidfd| pidfd.get_bounding_set()) {
+        Ok(bounding) => {
+            if bounding & capability::known() != capability::known() {
+                info!(
+                    "our bounding set lacks {}, helpers will run without them",
+                    capability::names(capability::known() & !bounding)
+                );
+            }
+            capability::set_bounding_set(bounding);
+        }
+        Err(err) => {
+            error!("failed to read our capability bounding set: {err}");
+            std::process::exit(1);
+        }
+    }
+
+    let reloadable = audit_path.is_some() || device_list_path.is_some();
+
+    if let Some(path) = audit_path {
+        if let Err(err) = audit::open(&path) {
+            error!("failed to open audit log {}: {err}", path.to_string_lossy());
+            std::process::exit(1);
+        }
+    }
+
+    // Not taken from the command line, where any local user could read it.
+    if let Some(path) = cookie_path {
+        match std::fs::read(&path) {
+            Ok(mut cookie) => {
+                if cookie.last() == Some(&b'\n') {
+                    cookie.pop();
+                }
+                if cookie.is_empty() {
+                    error!("cookie file {} is empty", path.to_string_lossy());
+                    std::process::exit(1);
+                }
+                client::set_expected_cookie(cookie);
+            }
+            Err(err) => {
+                error!(
+                    "failed to read cookie file {}: {err}",
+                    path.to_string_lossy()
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = device_list_path {
+        match sys_mknod::load_device_list(&path) {
+            Ok(count) => info!(
+                "loaded device allow list {}: {count} rules",
+                path.to_string_lossy()
+            ),
+            Err(err) => {
+                error!(
+                    "failed to load device allow list {}: {err}",
+                    path.to_string_lossy()
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if reloadable {
+        if let Err(err) = sighup::install() {
+            error!("failed to set up SIGHUP handling: {err}");
+            std::process::exit(1);
+        }
+    }
 
     let cpus = num_cpus::get();
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .worker_threads(cpus.clamp(2, 4))
+        // at most 15 characters, the kernel truncates longer thread names
+        .thread_name("lxc-syscalld-rt")
         .build()
         .expect("failed to spawn tokio runtime");
 
-    if let Err(err) = rt.block_on(do_main(use_sd_notify, path)) {
-        eprintln!("error: {err}");
+    if selftest {
+        std::process::exit(if rt.block_on(selftest::run()) { 0 } else { 1 });
+    }
+
+    if let Some(fd) = connection_fd {
+        if let Err(err) = rt.block_on(serve_connection(fd, allow_uid)) {
+            error!("error: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(err) = rt.block_on(do_main(
+        use_sd_notify,
+        paths,
+        status_path,
+        allow_uid,
+        permissions,
+    )) {
+        error!("error: {err}");
         std::process::exit(1);
     }
 }
 
-async fn do_main(use_sd_notify: bool, socket_path: OsString) -> Result<(), Error> {
-    match std::fs::remove_file(&socket_path) {
+/// Parse a numeric `UID[:GID]`.
+fn parse_owner(value: &str) -> Option<(libc::uid_t, Option<libc::gid_t>)> {
+    match value.split_once(':') {
+        Some((uid, gid)) => Some((uid.parse().ok()?, Some(gid.parse().ok()?))),
+        None => Some((value.parse().ok()?, None)),
+    }
+}
+
+fn option_value(args: &mut std::env::ArgsOs, option: &OsStr, program: &OsStr) -> OsString {
+    match args.next() {
+        Some(value) => value,
+        None => {
+            let _ = stderr().write_all(b"missing value for option: ");
+            let _ = stderr().write_all(option.as_bytes());
+            let _ = stderr().write_all(b"\n");
+            usage(1, program, &mut stderr());
+        }
+    }
+}
+
+/// Permissions and ownership of the socket files.
+struct SocketPermissions {
+    mode: libc::mode_t,
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+    /// Replace existing files (eg. left over from a previous instance), see `bind_socket()`.
+    replace: bool,
+}
+
+/// Create a listening socket at `socket_path`.
+///
+/// The socket is created under a temporary name first, and renamed into place once its
+/// permissions are set up. This way there is no window in which the socket is accessible with the
+/// permissions resulting from the umask, and clients never find the path missing when we restart.
+///
+/// With `--no-unlink` we bind to `socket_path` directly instead and never remove or replace any
+/// files, so binding fails if the path exists. The permissions are still set before we start
+/// listening, so nobody can connect before.
+///
+/// A path starting with `@` denotes a socket in the abstract namespace instead. These have no
+/// file, and with it no permissions, so anyone in our network namespace can connect and we rely
+/// on checking the peer credentials alone. Worse, anyone there can also bind the name first and
+/// have clients connect to them instead of us, which is why the usage text warns about it.
+fn bind_socket(
+    socket_path: &OsStr,
+    permissions: &SocketPermissions,
+) -> Result<SeqPacketListener, Error> {
+    if let Some(name) = socket_path.as_bytes().strip_prefix(b"@") {
+        let address = UnixAddr::new_abstract(name)
+            .map_err(|e| format_err!("invalid abstract socket name: {}", e))?;
+        return SeqPacketListener::bind(&address, || Ok(()))
+            .map_err(|e| format_err!("failed to create listening socket: {}", e));
+    }
+
+    if !permissions.replace {
+        let address = UnixAddr::new(socket_path).expect("cannot create struct sockaddr_un?");
+        return SeqPacketListener::bind(&address, || {
+            set_socket_permissions(socket_path, permissions)
+        })
+        .map_err(|e| format_err!("failed to create listening socket: {}", e));
+    }
+
+    let mut tmp_path = socket_path.to_owned();
+    tmp_path.push(format!(".tmp.{}", std::process::id()));
+
+    match std::fs::remove_file(&tmp_path) {
         Ok(_) => (),
         Err(ref e) if e.kind() == StdIo::ErrorKind::NotFound => (), // Ok
-        Err(e) => bail!("failed to remove previous socket: {}", e),
+        Err(e) => bail!("failed to remove previous temporary socket: {}", e),
+    }
+
+    let address = UnixAddr::new(tmp_path.as_os_str()).expect("cannot create struct sockaddr_un?");
+
+    let listener =
+        SeqPacketListener::bind(&address, || set_socket_permissions(&tmp_path, permissions))
+            .map_err(|e| {
+                let _ = std::fs::remove_file(&tmp_path);
+                format_err!("failed to create listening socket: {}", e)
+            })?;
+
+    if let Err(err) = std::fs::rename(&tmp_path, socket_path) {
+        let _ = std::fs::remove_file(&tmp_path);
+        bail!("failed to move socket into place: {}", err);
+    }
+
+    Ok(listener)
+}
+
+fn set_socket_permissions(path: &OsStr, permissions: &SocketPermissions) -> Result<(), Error> {
+    let path = std::ffi::CString::new(path.as_bytes())?;
+    c_try!(unsafe { libc::chmod(path.as_ptr(), permissions.mode) });
+    if permissions.uid.is_some() || permissions.gid.is_some() {
+        // -1 leaves the id unchanged
+        c_try!(unsafe {
+            libc::chown(
+                path.as_ptr(),
+                permissions.uid.unwrap_or(u32::MAX),
+                permissions.gid.unwrap_or(u32::MAX),
+            )
+        });
     }
+    Ok(())
+}
 
-    let address =
-        UnixAddr::new(socket_path.as_os_str()).expect("cannot create struct sockaddr_un?");
+async fn do_main(
+    use_sd_notify: bool,
+    socket_paths: Vec<OsString>,
+    status_path: Option<OsString>,
+    allow_uid: Option<libc::uid_t>,
+    permissions: SocketPermissions,
+) -> Result<(), Error> {
+    // probe this once up front rather than on the first request
+    if lxcseccomp::direct_mem_read_enabled() && !process::process_vm_readv_usable() {
+        info!("process_vm_readv() not usable, reading process memory via /proc/<pid>/mem");
+    }
 
-    let mut listener = SeqPacketListener::bind(&address)
-        .map_err(|e| format_err!("failed to create listening socket: {}", e))?;
+    let listeners = socket_paths
+        .iter()
+        .map(|path| bind_socket(path, &permissions))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    if let Some(status_path) = status_path {
+        spawn(metrics::serve_status(bind_socket(
+            &status_path,
+            &permissions,
+        )?));
+    }
 
     if use_sd_notify {
         notify_systemd()?;
     }
 
+    accept_clients(listeners, allow_uid).await
+}
+
+/// Accept and serve clients on all `listeners`, until accepting fails on one of them.
+async fn accept_clients(
+    listeners: Vec<SeqPacketListener>,
+    allow_uid: Option<libc::uid_t>,
+) -> Result<(), Error> {
+    // Client ids are unique across all listeners. The accept loops all run in this task, so when
+    // one of them fails, returning drops (and closes) all of the listening sockets.
+    let next_client_id = AtomicU64::new(0);
+    let mut accept_loops: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| Box::pin(accept_loop(listener, allow_uid, &next_client_id)))
+        .collect();
+
+    poll_fn::poll_fn(|cx| {
+        for accept_loop in accept_loops.iter_mut() {
+            if let Poll::Ready(result) = accept_loop.as_mut().poll(cx) {
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+async fn accept_loop(
+    mut listener: SeqPacketListener,
+    allow_uid: Option<libc::uid_t>,
+    next_client_id: &AtomicU64,
+) -> Result<(), Error> {
     loop {
         let client = listener.accept().await?;
-        let client = client::Client::new(client);
+        let peer = match allowed_peer(&client, allow_uid) {
+            Some(peer) => peer,
+            None => continue,
+        };
+
+        let id = next_client_id.fetch_add(1, Ordering::Relaxed);
+        let client = client::Client::new(id, client, peer);
         spawn(client.main());
     }
 }
 
+/// Get the credentials of a connection's peer, `None` (after logging why) if it must be rejected.
+///
+/// Clients can make us perform privileged syscalls on behalf of any process they have a pidfd
+/// for, so only the lxc monitor (running as root) may connect.
+fn allowed_peer(
+    client: &SeqPacketSocket,
+    allow_uid: Option<libc::uid_t>,
+) -> Option<UnixCredentials> {
+    let peer = match client.peer_credentials() {
+        Ok(peer) => peer,
+        Err(err) => {
+            error!("failed to query peer credentials, rejecting connection: {err}");
+            return None;
+        }
+    };
+    if !uid_allowed(peer.uid(), allow_uid) {
+        warn!(
+            pid = peer.pid();
+            "rejecting connection from pid {} (uid {})",
+            peer.pid(),
+            peer.uid()
+        );
+        return None;
+    }
+    Some(peer)
+}
+
+/// Root may always connect, `allow_uid` in addition to it.
+fn uid_allowed(uid: libc::uid_t, allow_uid: Option<libc::uid_t>) -> bool {
+    uid == 0 || Some(uid) == allow_uid
+}
+
+/// Serve a single, already accepted connection passed as file descriptor `fd` (inetd style), until
+/// it is closed.
+async fn serve_connection(fd: RawFd, allow_uid: Option<libc::uid_t>) -> Result<(), Error> {
+    // make sure the descriptor is open before taking ownership of it
+    c_result!(unsafe { libc::fcntl(fd, libc::F_GETFD) })
+        .map_err(|err| format_err!("invalid connection fd {fd}: {err}"))?;
+    let socket = SeqPacketSocket::from_connected(unsafe { OwnedFd::from_raw_fd(fd) })
+        .map_err(|err| format_err!("invalid connection fd {fd}: {err}"))?;
+
+    let peer = match allowed_peer(&socket, allow_uid) {
+        Some(peer) => peer,
+        None => bail!("connection rejected"),
+    };
+
+    client::Client::new(0, socket, peer).main().await;
+    Ok(())
+}
+
 #[link(name = "systemd")]
 extern "C" {
     fn sd_notify(unset_environment: libc::c_int, state: *const libc::c_char) -> libc::c_int;
@@ -152,3 +684,182 @@ fn notify_systemd() -> StdIo::Result<()> {
         Err(StdIo::Error::from_raw_os_error(-err))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+
+    use nix::sys::socket;
+
+    use super::*;
+
+    #[test]
+    fn uids_allowed() {
+        assert!(uid_allowed(0, None));
+        assert!(uid_allowed(0, Some(1000)));
+        assert!(uid_allowed(1000, Some(1000)));
+        assert!(!uid_allowed(1000, None));
+        assert!(!uid_allowed(1000, Some(1001)));
+    }
+
+    /// A temporary directory for socket files, removed again on drop.
+    struct SocketDir(std::path::PathBuf);
+
+    impl SocketDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "pve-lxc-syscalld-test-{name}-{}",
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for SocketDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn permissions(replace: bool) -> SocketPermissions {
+        SocketPermissions {
+            mode: 0o640,
+            uid: None,
+            gid: None,
+            replace,
+        }
+    }
+
+    fn assert_socket(path: &std::path::Path, mode: u32) {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let metadata = std::fs::symlink_metadata(path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, mode);
+    }
+
+    #[test]
+    fn bind_socket_replaces_file() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let dir = SocketDir::new("bind-replace");
+        let path = dir.0.join("socket");
+
+        // left over from a previous instance
+        std::fs::write(&path, b"").unwrap();
+        runtime.block_on(async {
+            let _listener = bind_socket(path.as_os_str(), &permissions(true)).unwrap();
+            assert_socket(&path, 0o640);
+        });
+
+        // and nothing else, in particular no temporary socket
+        let entries: Vec<_> = std::fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["socket"]);
+    }
+
+    #[test]
+    fn bind_socket_no_unlink() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let dir = SocketDir::new("bind-no-unlink");
+        let path = dir.0.join("socket");
+
+        std::fs::write(&path, b"data").unwrap();
+        runtime.block_on(async {
+            assert!(bind_socket(path.as_os_str(), &permissions(false)).is_err());
+        });
+        // untouched
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+
+        std::fs::remove_file(&path).unwrap();
+        runtime.block_on(async {
+            let _listener = bind_socket(path.as_os_str(), &permissions(false)).unwrap();
+            assert_socket(&path, 0o640);
+        });
+    }
+
+    #[test]
+    fn accept_on_all_sockets() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let dir = SocketDir::new("accept-all");
+        let paths = [dir.0.join("first"), dir.0.join("second")];
+
+        runtime.block_on(async {
+            let listeners = paths
+                .iter()
+                .map(|path| bind_socket(path.as_os_str(), &permissions(false)).unwrap())
+                .collect();
+            tokio::spawn(async move {
+                let _ = accept_clients(listeners, Some(unsafe { libc::getuid() })).await;
+            });
+
+            // Each connection must be accepted and served by a client, which closes it on our
+            // invalid message. A connection nobody accepts would just sit in the backlog.
+            let paths = paths.clone();
+            tokio::task::spawn_blocking(move || {
+                for path in paths.iter().rev() {
+                    let fd = socket::socket(
+                        socket::AddressFamily::Unix,
+                        socket::SockType::SeqPacket,
+                        socket::SockFlag::SOCK_CLOEXEC,
+                        None,
+                    )
+                    .unwrap();
+                    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                    let timeout = nix::sys::time::TimeVal::new(5, 0);
+                    socket::setsockopt(fd.as_raw_fd(), socket::sockopt::ReceiveTimeout, &timeout)
+                        .unwrap();
+                    socket::connect(fd.as_raw_fd(), &UnixAddr::new(path).unwrap()).unwrap();
+
+                    socket::send(fd.as_raw_fd(), b"x", socket::MsgFlags::empty()).unwrap();
+                    let mut buf = [0u8; 16];
+                    let got = socket::recv(fd.as_raw_fd(), &mut buf, socket::MsgFlags::empty());
+                    assert_eq!(got, Ok(0), "{path:?} not served");
+                }
+            })
+            .await
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn peer_credentials() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (ours, theirs) = socket::socketpair(
+            socket::AddressFamily::Unix,
+            socket::SockType::SeqPacket,
+            None,
+            socket::SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap();
+        let _theirs = unsafe { OwnedFd::from_raw_fd(theirs) };
+
+        runtime.block_on(async {
+            let socket =
+                SeqPacketSocket::from_connected(unsafe { OwnedFd::from_raw_fd(ours) }).unwrap();
+            let uid = unsafe { libc::getuid() };
+
+            let peer = allowed_peer(&socket, Some(uid)).expect("own uid rejected");
+            assert_eq!(peer.uid(), uid);
+            assert_eq!(peer.pid(), std::process::id() as libc::pid_t);
+
+            // only root gets in without being allowed explicitly
+            let other = if uid == 0 { None } else { Some(uid + 1) };
+            assert_eq!(allowed_peer(&socket, other).is_some(), uid == 0);
+        });
+    }
+}
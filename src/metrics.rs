@@ -0,0 +1,230 @@
+//! Runtime counters exposed via the status socket.
+
+use std::fmt::Write as _;
+use std::io::IoSlice;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::io::seq_packet::{SeqPacketListener, SeqPacketSocket};
+
+pub struct Metrics {
+    pub clients_active: AtomicU64,
+    pub clients_total: AtomicU64,
+    pub syscalls_total: AtomicU64,
+    pub syscalls_failed: AtomicU64,
+    pub syscalls_unsupported: AtomicU64,
+    /// Messages with seccomp structure sizes differing from ours, see `ProxyMessageBuffer`.
+    pub size_mismatches: AtomicU64,
+    /// Helpers killed because they exceeded `--syscall-timeout`.
+    pub helper_timeouts: AtomicU64,
+    /// From receiving a notification to having sent the response.
+    pub syscall_duration: Timing,
+    /// Running forked helpers, from the `fork()` until they were reaped.
+    pub helper_duration: Timing,
+}
+
+pub static METRICS: Metrics = Metrics {
+    clients_active: AtomicU64::new(0),
+    clients_total: AtomicU64::new(0),
+    syscalls_total: AtomicU64::new(0),
+    syscalls_failed: AtomicU64::new(0),
+    syscalls_unsupported: AtomicU64::new(0),
+    size_mismatches: AtomicU64::new(0),
+    helper_timeouts: AtomicU64::new(0),
+    syscall_duration: Timing::new(),
+    helper_duration: Timing::new(),
+};
+
+static HISTOGRAMS: AtomicBool = AtomicBool::new(false);
+
+/// Also record histograms of the durations, not just their count, sum, minimum and maximum.
+pub fn enable_histograms() {
+    HISTOGRAMS.store(true, Ordering::Relaxed);
+}
+
+/// Histogram buckets are powers of two in microseconds, the last one catches everything from
+/// about a second on.
+const BUCKETS: usize = 21;
+
+/// Durations in microseconds.
+pub struct Timing {
+    count: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Timing {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            count: ZERO,
+            sum: ZERO,
+            min: AtomicU64::new(u64::MAX),
+            max: ZERO,
+            buckets: [ZERO; BUCKETS],
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let us = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(us, Ordering::Relaxed);
+        self.min.fetch_min(us, Ordering::Relaxed);
+        self.max.fetch_max(us, Ordering::Relaxed);
+
+        if HISTOGRAMS.load(Ordering::Relaxed) {
+            // the number of bits needed for `us`, so bucket `i` holds values below `2^i`
+            let bucket = (64 - us.leading_zeros() as usize).min(BUCKETS - 1);
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn format(&self, out: &mut String, name: &str) {
+        let count = self.count.load(Ordering::Relaxed);
+        let min = match count {
+            0 => 0,
+            _ => self.min.load(Ordering::Relaxed),
+        };
+        let _ = writeln!(out, "{name}_count {count}");
+        let _ = writeln!(out, "{name}_sum_us {}", self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{name}_min_us {min}");
+        let _ = writeln!(out, "{name}_max_us {}", self.max.load(Ordering::Relaxed));
+
+        if HISTOGRAMS.load(Ordering::Relaxed) {
+            for (i, bucket) in self.buckets.iter().enumerate() {
+                let value = bucket.load(Ordering::Relaxed);
+                if i + 1 == BUCKETS {
+                    let _ = writeln!(out, "{name}_us_lt_inf {value}");
+                } else {
+                    let _ = writeln!(out, "{name}_us_lt_{} {value}", 1u64 << i);
+                }
+            }
+        }
+    }
+}
+
+/// Increment a counter.
+#[inline]
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Decrement a counter.
+#[inline]
+pub fn dec(counter: &AtomicU64) {
+    counter.fetch_sub(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    /// Format the current values as `name value` lines.
+    ///
+    /// The counters are read individually, so the snapshot is not atomic as a whole.
+    pub fn format(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in [
+            ("clients_active", &self.clients_active),
+            ("clients_total", &self.clients_total),
+            ("syscalls_total", &self.syscalls_total),
+            ("syscalls_failed", &self.syscalls_failed),
+            ("syscalls_unsupported", &self.syscalls_unsupported),
+            ("size_mismatches", &self.size_mismatches),
+            ("helper_timeouts", &self.helper_timeouts),
+        ] {
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        self.syscall_duration.format(&mut out, "syscall_duration");
+        self.helper_duration.format(&mut out, "helper_duration");
+        out
+    }
+}
+
+/// Answer connections on the status socket.
+///
+/// Every connection receives a single message containing the current counters and is then
+/// closed. Each connection is served in its own task so a slow reader cannot stall anything else.
+pub async fn serve_status(mut listener: SeqPacketListener) {
+    loop {
+        match listener.accept().await {
+            Ok(client) => crate::spawn(send_status(client)),
+            Err(err) => error!("error accepting status connection: {err}"),
+        }
+    }
+}
+
+async fn send_status(client: SeqPacketSocket) {
+    let status = METRICS.format();
+    if let Err(err) = client
+        .sendmsg_vectored(&[IoSlice::new(status.as_bytes())])
+        .await
+    {
+        error!("error sending status: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::IoSliceMut;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+    use nix::sys::socket::{self, UnixAddr};
+
+    use super::*;
+
+    #[test]
+    fn status_socket() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let name = format!("pve-lxc-syscalld-test-status-{}", std::process::id());
+        let address = UnixAddr::new_abstract(name.as_bytes()).unwrap();
+
+        let status = runtime.block_on(async {
+            let listener = SeqPacketListener::bind(&address, || Ok(())).unwrap();
+            crate::spawn(serve_status(listener));
+
+            for duration in [Duration::from_micros(100), Duration::from_micros(300)] {
+                inc(&METRICS.syscalls_total);
+                METRICS.syscall_duration.record(duration);
+            }
+
+            let fd = socket::socket(
+                socket::AddressFamily::Unix,
+                socket::SockType::SeqPacket,
+                socket::SockFlag::SOCK_CLOEXEC,
+                None,
+            )
+            .unwrap();
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            socket::connect(fd.as_raw_fd(), &address).unwrap();
+            let client = SeqPacketSocket::from_connected(fd).unwrap();
+
+            let mut buf = vec![0u8; 4096];
+            let (len, _, _) = client
+                .recvmsg_vectored(&mut [IoSliceMut::new(&mut buf)], &mut [])
+                .await
+                .unwrap();
+            buf.truncate(len);
+            String::from_utf8(buf).unwrap()
+        });
+
+        let values: HashMap<&str, u64> = status
+            .lines()
+            .map(|line| {
+                let (name, value) = line.split_once(' ').unwrap();
+                (name, value.parse().unwrap())
+            })
+            .collect();
+        // other tests may run syscalls concurrently
+        assert!(values["syscalls_total"] >= 2, "{status}");
+        assert!(values["syscall_duration_count"] >= 2, "{status}");
+        assert!(values["syscall_duration_sum_us"] >= 400, "{status}");
+        assert!(values["syscall_duration_max_us"] >= 300, "{status}");
+        assert!(values.contains_key("helper_timeouts"), "{status}");
+    }
+}
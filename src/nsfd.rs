@@ -3,8 +3,9 @@
 use std::ffi::CStr;
 use std::io;
 use std::marker::PhantomData;
+use std::mem;
 use std::os::raw::c_int;
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, RawFd};
 
 pub mod ns_type {
     pub trait NsType {
@@ -23,28 +24,51 @@ pub mod ns_type {
     define_ns_type!(Mount, libc::CLONE_NEWNS);
     define_ns_type!(User, libc::CLONE_NEWUSER);
     define_ns_type!(Cgroup, libc::CLONE_NEWCGROUP);
+    define_ns_type!(Time, libc::CLONE_NEWTIME);
+    define_ns_type!(Ipc, libc::CLONE_NEWIPC);
+    define_ns_type!(Net, libc::CLONE_NEWNET);
 }
 
 pub use ns_type::NsType;
 
+/// `_IO(0xb7, 0x1)` from `linux/nsfs.h`.
+const NS_GET_USERNS: libc::c_ulong = 0xb701;
+
 file_descriptor_type!(RawNsFd);
 
 impl RawNsFd {
     pub fn open(path: &CStr) -> io::Result<Self> {
-        Self::openat(libc::AT_FDCWD, path)
+        Self::openat_raw(libc::AT_FDCWD, path)
+    }
+
+    pub fn openat(dir: BorrowedFd<'_>, path: &CStr) -> io::Result<Self> {
+        Self::openat_raw(dir.as_raw_fd(), path)
     }
 
-    pub fn openat(fd: RawFd, path: &CStr) -> io::Result<Self> {
+    fn openat_raw(fd: RawFd, path: &CStr) -> io::Result<Self> {
         let fd =
             c_try!(unsafe { libc::openat(fd, path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) });
 
         Ok(unsafe { Self::from_raw_fd(fd) })
     }
 
+    /// Enter the namespace.
+    ///
+    /// None of `setns()`'s errors are transient (there is no `EAGAIN` or `EBUSY`), so there is no
+    /// point in retrying a failure.
     pub fn setns(&self, ns_type: c_int) -> io::Result<()> {
         c_try!(unsafe { libc::setns(self.as_raw_fd(), ns_type) });
         Ok(())
     }
+
+    /// Check whether two namespace descriptors refer to the same namespace.
+    pub fn is_same(&self, other: &RawNsFd) -> io::Result<bool> {
+        let mut a: libc::stat = unsafe { mem::zeroed() };
+        let mut b: libc::stat = unsafe { mem::zeroed() };
+        c_try!(unsafe { libc::fstat(self.as_raw_fd(), &mut a) });
+        c_try!(unsafe { libc::fstat(other.as_raw_fd(), &mut b) });
+        Ok(a.st_dev == b.st_dev && a.st_ino == b.st_ino)
+    }
 }
 
 #[repr(transparent)]
@@ -63,11 +87,17 @@ impl<T: NsType> NsFd<T> {
         Ok(Self(RawNsFd::open(path)?, PhantomData))
     }
 
-    pub fn openat(fd: RawFd, path: &CStr) -> io::Result<Self> {
-        Ok(Self(RawNsFd::openat(fd, path)?, PhantomData))
+    pub fn openat(dir: BorrowedFd<'_>, path: &CStr) -> io::Result<Self> {
+        Ok(Self(RawNsFd::openat(dir, path)?, PhantomData))
     }
 
     pub fn setns(&self) -> io::Result<()> {
         self.0.setns(T::TYPE)
     }
+
+    /// Get the user namespace owning this namespace.
+    pub fn owner(&self) -> io::Result<NsFd<ns_type::User>> {
+        let fd = c_try!(unsafe { libc::ioctl(self.as_raw_fd(), NS_GET_USERNS) });
+        Ok(NsFd(unsafe { RawNsFd::from_raw_fd(fd) }, PhantomData))
+    }
 }
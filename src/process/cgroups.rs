@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct CGroups {
     pub v1: Option<HashMap<String, OsString>>,
     pub v2: Option<OsString>,
@@ -25,4 +25,12 @@ impl CGroups {
     pub fn has_v1(&self) -> bool {
         self.v1.is_some()
     }
+
+    /// Whether there is a cgroup controlling device access: a v1 `devices` cgroup or a v2 cgroup
+    /// (where device access is controlled by BPF programs).
+    ///
+    /// On hybrid hosts a process may be listed only in named v1 hierarchies like `name=systemd`.
+    pub fn has_devices(&self) -> bool {
+        self.get("devices").is_some() || self.v2.is_some()
+    }
 }
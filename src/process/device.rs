@@ -0,0 +1,153 @@
+//! Device node helpers.
+
+use nix::sys::stat;
+
+/// The kind of file a `mknod()` call creates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DevKind {
+    Regular,
+    Char,
+    Block,
+    Fifo,
+    Socket,
+}
+
+impl DevKind {
+    pub fn from_mode(mode: stat::mode_t) -> Option<Self> {
+        Some(match mode & libc::S_IFMT {
+            libc::S_IFREG => DevKind::Regular,
+            libc::S_IFCHR => DevKind::Char,
+            libc::S_IFBLK => DevKind::Block,
+            libc::S_IFIFO => DevKind::Fifo,
+            libc::S_IFSOCK => DevKind::Socket,
+            _ => return None,
+        })
+    }
+}
+
+/// A device as passed to `mknod()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Device {
+    pub kind: DevKind,
+    pub major: u64,
+    pub minor: u64,
+}
+
+/// An entry in a device allow list. A `minor` of `None` matches any minor number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceRule {
+    pub kind: DevKind,
+    pub major: u64,
+    pub minor: Option<u64>,
+}
+
+impl Device {
+    /// Get the device from a `mknod()` syscall's `mode` and `dev` arguments.
+    ///
+    /// The kernel's `mknod()` takes the device as an `unsigned int` in its own encoding (12 bit
+    /// major, 20 bit minor), whereas glibc's `dev_t` is 64 bits wide with the major's and minor's
+    /// upper bits in the upper half. We look at the syscall argument, so the upper half must be
+    /// ignored just like the kernel does, instead of feeding it to glibc's `major()`/`minor()`.
+    ///
+    /// This is the same for 32 bit callers: compat `mknod()` decodes its argument the same way, so
+    /// we must not use the legacy 16 bit layout (8 bit major, 8 bit minor) for them. It is a subset
+    /// of this encoding anyway, eg. `/dev/null` is `0x103` in both.
+    pub fn from_mode_dev(mode: stat::mode_t, dev: u64) -> Option<Self> {
+        let (major, minor) = Self::split_dev(dev);
+        Some(Self {
+            kind: DevKind::from_mode(mode)?,
+            major,
+            minor,
+        })
+    }
+
+    /// Get the major and minor number from a `mknod()` syscall's `dev` argument, see
+    /// [`from_mode_dev`](Self::from_mode_dev).
+    pub fn split_dev(dev: u64) -> (u64, u64) {
+        let dev = dev as u32;
+        (
+            u64::from((dev & 0xfff00) >> 8),
+            u64::from((dev & 0xff) | ((dev >> 12) & 0xfff00)),
+        )
+    }
+
+    /// The device as a glibc `dev_t` as expected by `libc::mknodat()`.
+    pub fn dev_t(&self) -> stat::dev_t {
+        stat::makedev(self.major, self.minor)
+    }
+
+    /// Check whether any of the rules in an allow list covers this device.
+    pub fn matches(&self, allowlist: &[DeviceRule]) -> bool {
+        allowlist.iter().any(|rule| {
+            rule.kind == self.kind
+                && rule.major == self.major
+                && rule.minor.map(|minor| minor == self.minor).unwrap_or(true)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_kernel_encoding() {
+        // /dev/null is the same in the legacy 16 bit, the kernel's and glibc's encoding
+        assert_eq!(Device::split_dev(0x103), (1, 3));
+        // minor bits beyond the lower 8 go above the major's 12 bits
+        assert_eq!(Device::split_dev(0x1231_0345), (259, 0x12345));
+        // glibc agrees as long as major and minor fit into the kernel's 12 and 20 bits
+        assert_eq!(stat::makedev(259, 0x12345), 0x1231_0345);
+    }
+
+    #[test]
+    fn split_ignores_upper_half() {
+        // the kernel only looks at the lower 32 bits of the syscall argument
+        assert_eq!(Device::split_dev(0xffff_ffff_0000_0103), (1, 3));
+        // so glibc's encoding of a major beyond 12 bits does not reach the kernel
+        assert_eq!(Device::split_dev(stat::makedev(0x1000, 0)), (0, 0));
+        assert_eq!(Device::split_dev(stat::makedev(0x1001, 1)), (1, 1));
+    }
+
+    #[test]
+    fn from_mode_dev() {
+        let device = Device::from_mode_dev(libc::S_IFCHR | 0o666, 0x103).unwrap();
+        assert_eq!(
+            device,
+            Device {
+                kind: DevKind::Char,
+                major: 1,
+                minor: 3
+            }
+        );
+        assert_eq!(device.dev_t(), stat::makedev(1, 3));
+
+        // a mode without (or with an invalid) file type
+        assert_eq!(Device::from_mode_dev(0o666, 0), None);
+        assert_eq!(Device::from_mode_dev(libc::S_IFDIR | 0o755, 0), None);
+    }
+
+    #[test]
+    fn match_rules() {
+        let rules = [
+            DeviceRule {
+                kind: DevKind::Char,
+                major: 1,
+                minor: Some(3),
+            },
+            DeviceRule {
+                kind: DevKind::Char,
+                major: 136,
+                minor: None,
+            },
+        ];
+        let device = |kind, major, minor| Device { kind, major, minor };
+
+        assert!(device(DevKind::Char, 1, 3).matches(&rules));
+        assert!(!device(DevKind::Char, 1, 5).matches(&rules));
+        assert!(!device(DevKind::Block, 1, 3).matches(&rules));
+        assert!(device(DevKind::Char, 136, 0).matches(&rules));
+        assert!(device(DevKind::Char, 136, 0xfffff).matches(&rules));
+        assert!(!device(DevKind::Block, 136, 0).matches(&rules));
+    }
+}
@@ -0,0 +1,82 @@
+//! Short-lived cache for process environment data.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Error;
+use libc::pid_t;
+
+use super::{PidFd, ProcEnv};
+
+/// Maximum age of a cache entry.
+///
+/// This is meant to cover bursts of syscalls (eg. a container populating `/dev`), not to keep
+/// data around for the lifetime of a process.
+const CACHE_TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct CacheKey {
+    init_pid: pid_t,
+    pid: pid_t,
+    inode: u64,
+}
+
+struct CacheEntry {
+    key: CacheKey,
+    created: Instant,
+    env: Arc<ProcEnv>,
+}
+
+/// Cache for the [`ProcEnv`] part of a process' [`UserCaps`](super::UserCaps).
+///
+/// Every handled syscall needs to build a `UserCaps` for the calling process, which means parsing
+/// several files in `/proc/<pid>`. Not all of that data is equally safe to cache:
+///
+/// * The uids, gids, capabilities and umask from `/proc/<pid>/status` can be changed by the
+///   process itself at any time with a single syscall, so they are *never* cached.
+/// * The cgroup membership and the AppArmor label only change when the process is explicitly
+///   migrated or changes its profile, so these are kept for at most `CACHE_TIMEOUT`.
+///
+/// Entries are keyed by the container's init pid, the process' pid and the inode of its proc
+/// directory, so a reused pid never hits a stale entry. The cache should be invalidated whenever
+/// a request fails, so a changed environment is picked up right away.
+#[derive(Default)]
+pub struct EnvCache {
+    entry: Mutex<Option<CacheEntry>>,
+}
+
+impl EnvCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the cached environment for a process or read it if necessary.
+    pub fn get(&self, init_pid: pid_t, pidfd: &PidFd) -> Result<Arc<ProcEnv>, Error> {
+        let key = CacheKey {
+            init_pid,
+            pid: pidfd.get_pid(),
+            inode: pidfd.inode()?,
+        };
+
+        let mut entry = self.entry.lock().unwrap();
+        if let Some(entry) = &*entry {
+            if entry.key == key && entry.created.elapsed() < CACHE_TIMEOUT {
+                return Ok(Arc::clone(&entry.env));
+            }
+        }
+        *entry = None;
+
+        let env = Arc::new(ProcEnv::read(pidfd)?);
+        *entry = Some(CacheEntry {
+            key,
+            created: Instant::now(),
+            env: Arc::clone(&env),
+        });
+        Ok(env)
+    }
+
+    /// Drop any cached data.
+    pub fn invalidate(&self) {
+        *self.entry.lock().unwrap() = None;
+    }
+}
@@ -1,5 +1,7 @@
 //! uid/gid mapping helpers
 
+use anyhow::{bail, Error};
+
 pub struct IdMap(Vec<IdMapEntry>);
 
 pub struct IdMapEntry {
@@ -9,8 +11,29 @@ pub struct IdMapEntry {
 }
 
 impl IdMap {
-    pub fn new(entries: Vec<IdMapEntry>) -> Self {
-        Self(entries)
+    /// Create an id map from a list of entries.
+    ///
+    /// The kernel refuses to write overlapping ranges into `uid_map`/`gid_map`, but since an
+    /// overlap would make lookups depend on the order of the entries, we check this anyway
+    /// rather than silently translating ids wrongly.
+    pub fn new(entries: Vec<IdMapEntry>) -> Result<Self, Error> {
+        for entry in entries.iter() {
+            if entry.ns.checked_add(entry.range).is_none()
+                || entry.host.checked_add(entry.range).is_none()
+            {
+                bail!(
+                    "id map entry {} {} {} exceeds the id range",
+                    entry.ns,
+                    entry.host,
+                    entry.range
+                );
+            }
+        }
+
+        check_overlap(&entries, "namespace", |e| e.ns)?;
+        check_overlap(&entries, "host", |e| e.host)?;
+
+        Ok(Self(entries))
     }
 
     pub fn map_into(&self, id: u64) -> Option<u64> {
@@ -33,3 +56,72 @@ impl IdMap {
         None
     }
 }
+
+fn check_overlap<F>(entries: &[IdMapEntry], what: &str, start: F) -> Result<(), Error>
+where
+    F: Fn(&IdMapEntry) -> u64,
+{
+    let mut ranges: Vec<(u64, u64)> = entries
+        .iter()
+        .filter(|e| e.range != 0)
+        .map(|e| (start(e), start(e) + e.range))
+        .collect();
+    ranges.sort_unstable();
+
+    for pair in ranges.windows(2) {
+        if pair[0].1 > pair[1].0 {
+            bail!(
+                "overlapping {} id ranges in id map: {}..{} and {}..{}",
+                what,
+                pair[0].0,
+                pair[0].1,
+                pair[1].0,
+                pair[1].1
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(ns: u64, host: u64, range: u64) -> IdMapEntry {
+        IdMapEntry { ns, host, range }
+    }
+
+    #[test]
+    fn valid_map() {
+        let map = IdMap::new(vec![entry(0, 100000, 1000), entry(1000, 1000, 1)]).unwrap();
+        assert_eq!(map.map_from(0), Some(100000));
+        assert_eq!(map.map_from(999), Some(100999));
+        assert_eq!(map.map_from(1000), Some(1000));
+        assert_eq!(map.map_from(1001), None);
+        assert_eq!(map.map_into(100500), Some(500));
+        assert_eq!(map.map_into(1000), Some(1000));
+        assert_eq!(map.map_into(99999), None);
+    }
+
+    #[test]
+    fn adjacent_ranges_are_fine() {
+        IdMap::new(vec![entry(0, 0, 10), entry(10, 10, 10)]).unwrap();
+        // empty ranges never overlap
+        IdMap::new(vec![entry(0, 0, 10), entry(5, 5, 0)]).unwrap();
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        assert!(IdMap::new(vec![entry(0, 100, 10), entry(5, 1000, 10)]).is_err());
+        assert!(IdMap::new(vec![entry(0, 100, 10), entry(100, 105, 10)]).is_err());
+        // the order of the entries does not matter
+        assert!(IdMap::new(vec![entry(100, 105, 10), entry(0, 100, 10)]).is_err());
+    }
+
+    #[test]
+    fn range_overflow() {
+        assert!(IdMap::new(vec![entry(u64::MAX, 0, 2)]).is_err());
+        assert!(IdMap::new(vec![entry(0, u64::MAX, 2)]).is_err());
+    }
+}
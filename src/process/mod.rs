@@ -1,6 +1,8 @@
 use crate::capability::Capabilities;
 
 pub mod cgroups;
+pub mod device;
+pub mod env_cache;
 pub mod id_map;
 pub mod pid_fd;
 pub mod user_caps;
@@ -9,13 +11,16 @@ pub mod user_caps;
 pub use cgroups::CGroups;
 
 #[doc(inline)]
-pub use pid_fd::PidFd;
+pub use env_cache::EnvCache;
+
+#[doc(inline)]
+pub use pid_fd::{process_vm_readv_usable, PidFd};
 
 #[doc(inline)]
 pub use id_map::{IdMap, IdMapEntry};
 
 #[doc(inline)]
-pub use user_caps::UserCaps;
+pub use user_caps::{min_umask, set_helper_cgroup_subtree, set_min_umask, ProcEnv, UserCaps};
 
 #[derive(Default)]
 pub struct Uids {
@@ -32,6 +37,9 @@ pub struct Uids {
 #[derive(Default)]
 pub struct ProcStatus {
     uids: Uids,
+    groups: Vec<libc::gid_t>,
     capabilities: Capabilities,
+    cap_bounding: u64,
     umask: libc::mode_t,
+    no_new_privs: bool,
 }
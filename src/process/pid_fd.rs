@@ -2,21 +2,69 @@
 
 use std::ffi::{CStr, CString, OsString};
 use std::io::{self, BufRead, BufReader};
+use std::mem;
 use std::os::raw::c_int;
 use std::os::unix::ffi::OsStringExt;
-use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU8, Ordering};
 
 use anyhow::{bail, Error};
+use lazy_static::lazy_static;
 use libc::pid_t;
 
 use crate::capability::Capabilities;
+use crate::fork::error_context;
 use crate::nsfd::{ns_type, NsFd};
+use crate::tools::fd_tracker::FdTracker;
 
 use super::{CGroups, IdMap, IdMapEntry, ProcStatus, Uids, UserCaps};
 
-pub struct PidFd(OwnedFd, pid_t);
+/// A process' `/proc/<pid>` directory (or a pidfd), along with its pid.
+///
+/// The pid is only meaningful in the pid namespace of the proc file system the directory was
+/// opened from, see [`pid_is_ours`](PidFd::pid_is_ours). The last field caches that check (0 if
+/// not done yet, 1 if the pid is valid for us, 2 if it is not).
+pub struct PidFd(OwnedFd, pid_t, FdTracker, AtomicU8);
 file_descriptor_impl!(PidFd);
 
+const PID_UNCHECKED: u8 = 0;
+const PID_OURS: u8 = 1;
+const PID_FOREIGN: u8 = 2;
+
+bitflags::bitflags! {
+    /// The fields of `/proc/<pid>/status` we know how to parse.
+    struct StatusFields: u32 {
+        const UID = 1 << 0;
+        const GID = 1 << 1;
+        const GROUPS = 1 << 2;
+        const CAP_INH = 1 << 3;
+        const CAP_PRM = 1 << 4;
+        const CAP_EFF = 1 << 5;
+        const UMASK = 1 << 6;
+        const NO_NEW_PRIVS = 1 << 7;
+        const CAP_BND = 1 << 8;
+
+        const CAPS = Self::CAP_INH.bits | Self::CAP_PRM.bits | Self::CAP_EFF.bits;
+    }
+}
+
+impl StatusFields {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "Uid:" => Self::UID,
+            "Gid:" => Self::GID,
+            "Groups:" => Self::GROUPS,
+            "CapInh:" => Self::CAP_INH,
+            "CapPrm:" => Self::CAP_PRM,
+            "CapEff:" => Self::CAP_EFF,
+            "CapBnd:" => Self::CAP_BND,
+            "Umask:" => Self::UMASK,
+            "NoNewPrivs:" => Self::NO_NEW_PRIVS,
+            _ => return None,
+        })
+    }
+}
+
 impl PidFd {
     pub fn current() -> io::Result<Self> {
         Self::open(unsafe { libc::getpid() })
@@ -28,7 +76,8 @@ impl PidFd {
         let fd = c_try!(unsafe { libc::open(path.as_ptr(), libc::O_DIRECTORY | libc::O_CLOEXEC) });
         let fd = unsafe { OwnedFd::from_raw_fd(fd) };
 
-        Ok(Self(fd, pid))
+        // opened from our own /proc
+        Ok(Self(fd, pid, FdTracker::new(), AtomicU8::new(PID_OURS)))
     }
 
     /// Turn a valid pid file descriptor into a PidFd.
@@ -39,22 +88,40 @@ impl PidFd {
     /// fails if reading the pid from the pidfd's proc entry fails.
     pub unsafe fn try_from_fd(fd: OwnedFd) -> io::Result<Self> {
         #[allow(clippy::unnecessary_cast)] // pid_t is a type alias
-        let mut this = Self(fd, -1 as pid_t);
+        let mut this = Self(
+            fd,
+            -1 as pid_t,
+            FdTracker::new(),
+            AtomicU8::new(PID_UNCHECKED),
+        );
         let pid = this.read_pid()?;
         this.1 = pid;
         Ok(this)
     }
 
     pub fn mount_namespace(&self) -> io::Result<NsFd<ns_type::Mount>> {
-        NsFd::openat(self.0.as_raw_fd(), c_str!("ns/mnt"))
+        NsFd::openat(self.as_fd(), c_str!("ns/mnt"))
     }
 
     pub fn cgroup_namespace(&self) -> io::Result<NsFd<ns_type::Cgroup>> {
-        NsFd::openat(self.0.as_raw_fd(), c_str!("ns/cgroup"))
+        NsFd::openat(self.as_fd(), c_str!("ns/cgroup"))
     }
 
     pub fn user_namespace(&self) -> io::Result<NsFd<ns_type::User>> {
-        NsFd::openat(self.0.as_raw_fd(), c_str!("ns/user"))
+        NsFd::openat(self.as_fd(), c_str!("ns/user"))
+    }
+
+    /// Note that this fails with `ENOENT` if the kernel does not support time namespaces.
+    pub fn time_namespace(&self) -> io::Result<NsFd<ns_type::Time>> {
+        NsFd::openat(self.as_fd(), c_str!("ns/time"))
+    }
+
+    pub fn ipc_namespace(&self) -> io::Result<NsFd<ns_type::Ipc>> {
+        NsFd::openat(self.as_fd(), c_str!("ns/ipc"))
+    }
+
+    pub fn net_namespace(&self) -> io::Result<NsFd<ns_type::Net>> {
+        NsFd::openat(self.as_fd(), c_str!("ns/net"))
     }
 
     fn fd(&self, path: &CStr, flags: c_int, mode: c_int) -> io::Result<OwnedFd> {
@@ -72,11 +139,55 @@ impl PidFd {
         self.fd(c_str!("cwd"), libc::O_DIRECTORY, 0)
     }
 
+    /// Duplicate one of the process' file descriptors via `pidfd_getfd()` (Linux 5.6).
+    ///
+    /// Unlike `fd_num()` this returns the process' actual open file (with its flags and offset)
+    /// rather than opening the file again. This fails with `ENOSYS` on older kernels, with `EPERM`
+    /// if we may not ptrace the process (eg. due to a LSM), and with `EINVAL` if the pid is not a
+    /// thread group leader or not valid in our pid namespace.
+    ///
+    /// `pidfd_getfd()` needs a real pidfd rather than a `/proc/<pid>` directory, so we open one
+    /// for the pid. To make sure it refers to our process and not to a new one reusing the pid, we
+    /// verify that ours is still alive afterwards.
+    pub fn get_fd(&self, num: RawFd) -> io::Result<OwnedFd> {
+        if !self.pid_is_ours()? {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let pidfd = c_try!(unsafe { libc::syscall(libc::SYS_pidfd_open, self.get_pid(), 0) });
+        let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as RawFd) };
+
+        // fails if our process is gone (and the pid may have been reused)
+        self.read_pid().map_err(|err| match err.raw_os_error() {
+            Some(libc::ENOENT) => io::Error::from_raw_os_error(libc::ESRCH),
+            _ => err,
+        })?;
+
+        let fd = c_try!(unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), num, 0) });
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+    }
+
+    /// Open one of the process' file descriptors as an `O_PATH` descriptor.
+    ///
+    /// `num` is a descriptor number in the *target* process' file descriptor table, which is why
+    /// this takes a plain number rather than a borrowed descriptor of ours. Of the `flags`, only
+    /// `O_DIRECTORY` is useful, since `O_PATH` ignores most others.
+    ///
+    /// `/proc/<pid>/fd/<num>` is a "magic" link: following it does not resolve the path it shows
+    /// when read, but yields the exact mount and dentry the process has open. So renaming the
+    /// directory, or replacing the path with a symlink, between the notification and this call
+    /// does not change what we get. `O_NOFOLLOW` must not be used here: it applies to the magic
+    /// link itself and makes the open fail with `ELOOP`.
+    ///
+    /// `O_PATH` makes sure opening has no side effects and needs no permissions on the file
+    /// itself, which suffices for the `*at()` syscalls we use the result with. The process may
+    /// still replace descriptor `num` itself (from another thread), but then we only use what it
+    /// could have passed in the first place.
     pub fn fd_num(&self, num: RawFd, flags: c_int) -> io::Result<OwnedFd> {
         let path = format!("fd/{num}\0");
         self.fd(
             unsafe { CStr::from_bytes_with_nul_unchecked(path.as_bytes()) },
-            flags,
+            flags | libc::O_PATH,
             0,
         )
     }
@@ -86,16 +197,51 @@ impl PidFd {
         Ok(())
     }
 
+    /// Enter the process' root directory.
+    ///
+    /// The `root` link is opened as an `O_PATH` descriptor first instead of being resolved
+    /// relative to the proc directory, so we can tell which step failed. Since this runs in a
+    /// forked helper, failures other than the process having vanished carry a description for the
+    /// parent to log, see [`error_context`].
     pub fn enter_chroot(&self) -> io::Result<()> {
-        c_try!(unsafe { libc::fchdir(self.as_raw_fd()) });
-        c_try!(unsafe { libc::chroot(b"root\0".as_ptr() as *const _) });
-        c_try!(unsafe { libc::chdir(b"/\0".as_ptr() as *const _) });
+        let step_error = |step: &str, err: io::Error| match err.raw_os_error() {
+            // The proc directory is gone, so is the process.
+            Some(libc::ENOENT) | Some(libc::ESRCH) => io::Error::from_raw_os_error(libc::ESRCH),
+            _ => error_context(
+                err,
+                format_args!("failed to {step} of pid {}", self.get_pid()),
+            ),
+        };
+
+        let root = self
+            .fd(c_str!("root"), libc::O_PATH | libc::O_DIRECTORY, 0)
+            .map_err(|err| step_error("open root directory", err))?;
+        c_result!(unsafe { libc::fchdir(root.as_raw_fd()) })
+            .map_err(|err| step_error("change into root directory", err))?;
+        c_result!(unsafe { libc::chroot(c_str!(".").as_ptr()) })
+            .map_err(|err| step_error("chroot into root directory", err))?;
+        c_try!(unsafe { libc::chdir(c_str!("/").as_ptr()) });
         Ok(())
     }
 
     // procfs files cannot be async, we cannot add them to epoll...
     pub fn open_file(&self, path: &CStr, flags: c_int, mode: c_int) -> io::Result<std::fs::File> {
-        Ok(unsafe { std::fs::File::from_raw_fd(self.fd(path, flags, mode)?.into_raw_fd()) })
+        let fd = self.fd(path, flags, mode);
+        match &fd {
+            Ok(_) => trace!(
+                pid = self.get_pid();
+                "opened /proc/{}/{}",
+                self.get_pid(),
+                path.to_string_lossy(),
+            ),
+            Err(err) => trace!(
+                pid = self.get_pid();
+                "failed to open /proc/{}/{}: {err}",
+                self.get_pid(),
+                path.to_string_lossy(),
+            ),
+        }
+        Ok(unsafe { std::fs::File::from_raw_fd(fd?.into_raw_fd()) })
     }
 
     #[inline]
@@ -112,6 +258,108 @@ impl PidFd {
         self.1
     }
 
+    /// Get the inode number of the process' proc directory.
+    ///
+    /// Together with the pid this identifies a process, as a reused pid gets a new proc inode.
+    pub fn inode(&self) -> io::Result<u64> {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        c_try!(unsafe { libc::fstat(self.as_raw_fd(), &mut stat) });
+        Ok(stat.st_ino)
+    }
+
+    /// Send a signal to the process.
+    ///
+    /// This uses `pidfd_send_signal()`, which (like all pidfd operations) cannot hit a different
+    /// process if the pid got reused. It also accepts `/proc/<pid>` directory descriptors, so this
+    /// works for both kinds of `PidFd`.
+    ///
+    /// On kernels without it (before 5.1) we fall back to `kill()`, after making sure the pid
+    /// still refers to the same proc directory. This narrows the window for pid reuse, but cannot
+    /// close it completely.
+    pub fn kill(&self, sig: c_int) -> io::Result<()> {
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                self.as_raw_fd(),
+                sig,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        };
+        if rc == 0 {
+            return Ok(());
+        }
+
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ENOSYS) {
+            return Err(err);
+        }
+
+        self.kill_by_pid(sig)
+    }
+
+    /// The fallback for kernels without `pidfd_send_signal()`, see [`kill`](Self::kill).
+    fn kill_by_pid(&self, sig: c_int) -> io::Result<()> {
+        let current = Self::open(self.get_pid()).map_err(|err| match err.raw_os_error() {
+            Some(libc::ENOENT) => io::Error::from_raw_os_error(libc::ESRCH),
+            _ => err,
+        })?;
+        if current.inode()? != self.inode()? {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+
+        c_try!(unsafe { libc::kill(self.get_pid(), sig) });
+        Ok(())
+    }
+
+    /// Check whether `get_pid()` refers to this process in *our* pid namespace.
+    ///
+    /// The pid is read from the process' proc directory, so it is relative to the pid namespace of
+    /// the proc file system that directory was opened from. If an lxc monitor running in a
+    /// different pid namespace passes us such a directory, the pid is meaningless to us, or even
+    /// refers to a different process. Everything working with the plain pid (`pidfd_open()`,
+    /// `process_vm_readv()`) must check this first, while everything going through the directory
+    /// descriptor works regardless.
+    ///
+    /// We compare the start times of the process and of the one `/proc/<pid>` refers to in our
+    /// proc file system. This is only done once per `PidFd`.
+    pub fn pid_is_ours(&self) -> io::Result<bool> {
+        match self.3.load(Ordering::Relaxed) {
+            PID_OURS => return Ok(true),
+            PID_FOREIGN => return Ok(false),
+            _ => (),
+        }
+
+        let ours = match Self::open(self.get_pid()) {
+            Ok(ours) => self.get_starttime()? == ours.get_starttime()?,
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => false,
+            Err(err) => return Err(err),
+        };
+        self.3
+            .store(if ours { PID_OURS } else { PID_FOREIGN }, Ordering::Relaxed);
+        Ok(ours)
+    }
+
+    /// The process' start time in clock ticks after boot (field 22 of `/proc/<pid>/stat`).
+    ///
+    /// Together with the pid this identifies a process: a reused pid has a different start time.
+    pub fn get_starttime(&self) -> io::Result<u64> {
+        Self::parse_starttime(&self.read_file(c_str!("stat"))?)
+    }
+
+    fn parse_starttime(stat: &[u8]) -> io::Result<u64> {
+        // the command name in parentheses may contain spaces and parentheses itself
+        let fields = match stat.iter().rposition(|b| *b == b')') {
+            Some(pos) => &stat[pos + 1..],
+            None => return Err(io_format_err!("failed to parse proc stat file")),
+        };
+        std::str::from_utf8(fields)
+            .ok()
+            .and_then(|fields| fields.split_ascii_whitespace().nth(19))
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(|| io_format_err!("failed to parse start time from proc stat file"))
+    }
+
     fn read_pid(&self) -> io::Result<pid_t> {
         let reader = self.open_buffered(c_str!("status"))?;
 
@@ -141,9 +389,37 @@ impl PidFd {
             .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to parse uid from proc"))
     }
 
+    /// Read the process' status.
     pub fn get_status(&self) -> io::Result<ProcStatus> {
-        let reader = self.open_buffered(c_str!("status"))?;
+        self.read_status(StatusFields::all())
+    }
 
+    /// Read only the process' uids and gids.
+    pub fn get_uids(&self) -> io::Result<Uids> {
+        Ok(self
+            .read_status(StatusFields::UID | StatusFields::GID)?
+            .uids)
+    }
+
+    /// Read only the process' capabilities.
+    pub fn get_caps(&self) -> io::Result<Capabilities> {
+        Ok(self.read_status(StatusFields::CAPS)?.capabilities)
+    }
+
+    /// Read only the process' capability bounding set.
+    pub fn get_bounding_set(&self) -> io::Result<u64> {
+        Ok(self.read_status(StatusFields::CAP_BND)?.cap_bounding)
+    }
+
+    /// Parse the requested fields of `/proc/<pid>/status`.
+    ///
+    /// Reading stops as soon as all of them have been found, fields which were not requested keep
+    /// their default values.
+    fn read_status(&self, wanted: StatusFields) -> io::Result<ProcStatus> {
+        Self::parse_status(self.open_buffered(c_str!("status"))?, wanted)
+    }
+
+    fn parse_status<R: BufRead>(reader: R, wanted: StatusFields) -> io::Result<ProcStatus> {
         #[inline]
         fn check_u64_hex(value: Option<&str>) -> io::Result<u64> {
             u64::from_str_radix(
@@ -167,37 +443,60 @@ impl PidFd {
         }
 
         let mut ids = Uids::default();
+        let mut groups = Vec::new();
         let mut caps = Capabilities::default();
+        let mut cap_bounding = 0;
         let mut umask = 0o022;
+        let mut no_new_privs = false;
+        let mut found = StatusFields::empty();
         for line in reader.lines() {
+            if found.contains(wanted) {
+                break;
+            }
+
             let line = line?;
             let mut parts = line.split_ascii_whitespace();
-            match parts.next() {
-                Some("Uid:") => {
+            let field = match parts.next().and_then(StatusFields::from_name) {
+                Some(field) if wanted.contains(field) => field,
+                _ => continue,
+            };
+            found |= field;
+
+            match field {
+                StatusFields::UID => {
                     ids.ruid = Self::__check_uid_gid(parts.next())?;
                     ids.euid = Self::__check_uid_gid(parts.next())?;
                     ids.suid = Self::__check_uid_gid(parts.next())?;
                     ids.fsuid = Self::__check_uid_gid(parts.next())?;
                 }
-                Some("Gid:") => {
+                StatusFields::GID => {
                     ids.rgid = Self::__check_uid_gid(parts.next())?;
                     ids.egid = Self::__check_uid_gid(parts.next())?;
                     ids.sgid = Self::__check_uid_gid(parts.next())?;
                     ids.fsgid = Self::__check_uid_gid(parts.next())?;
                 }
-                Some("CapInh:") => caps.inheritable = check_u64_hex(parts.next())?,
-                Some("CapPrm:") => caps.permitted = check_u64_hex(parts.next())?,
-                Some("CapEff:") => caps.effective = check_u64_hex(parts.next())?,
-                //Some("CapBnd:") => caps.bounding = check_u64_hex(parts.next())?,
-                Some("Umask:") => umask = check_u32_oct(parts.next())?,
-                _ => continue,
+                StatusFields::GROUPS => {
+                    groups = parts
+                        .map(|gid| Self::__check_uid_gid(Some(gid)))
+                        .collect::<io::Result<_>>()?;
+                }
+                StatusFields::CAP_INH => caps.inheritable = check_u64_hex(parts.next())?,
+                StatusFields::CAP_PRM => caps.permitted = check_u64_hex(parts.next())?,
+                StatusFields::CAP_EFF => caps.effective = check_u64_hex(parts.next())?,
+                StatusFields::CAP_BND => cap_bounding = check_u64_hex(parts.next())?,
+                StatusFields::UMASK => umask = check_u32_oct(parts.next())?,
+                StatusFields::NO_NEW_PRIVS => no_new_privs = parts.next() == Some("1"),
+                _ => unreachable!(),
             }
         }
 
         Ok(ProcStatus {
             uids: ids,
+            groups,
             capabilities: caps,
+            cap_bounding,
             umask,
+            no_new_privs,
         })
     }
 
@@ -234,9 +533,19 @@ impl PidFd {
         Ok(cgroups)
     }
 
+    /// Read a process' `uid_map` or `gid_map`.
+    ///
+    /// Note that `BufRead::lines()` already retries reads interrupted by a signal, so `EINTR`
+    /// does not need to be handled here.
+    ///
+    /// An empty map is not an identity mapping: it means nothing has been mapped (yet), so no id
+    /// is valid in the namespace. Processes sharing our user namespace (privileged containers)
+    /// see the full identity mapping `0 0 4294967295` instead, which passes ids through.
     pub fn get_uid_gid_map(&self, file: &CStr) -> Result<IdMap, Error> {
-        let reader = self.open_buffered(file)?;
+        Self::parse_uid_gid_map(self.open_buffered(file)?)
+    }
 
+    fn parse_uid_gid_map<R: BufRead>(reader: R) -> Result<IdMap, Error> {
         let mut entries = Vec::new();
         for line in reader.lines() {
             let line = line?;
@@ -247,7 +556,7 @@ impl PidFd {
             entries.push(IdMapEntry { ns, host, range });
         }
 
-        Ok(IdMap::new(entries))
+        IdMap::new(entries)
     }
 
     pub fn get_uid_map(&self) -> Result<IdMap, Error> {
@@ -263,11 +572,423 @@ impl PidFd {
 
         let mut reader = self.open_file(file, libc::O_RDONLY | libc::O_CLOEXEC, 0)?;
         let mut out = Vec::new();
-        reader.read_to_end(&mut out)?;
+        match reader.read_to_end(&mut out) {
+            Ok(len) => trace!(
+                pid = self.get_pid();
+                "read {len} bytes from /proc/{}/{}",
+                self.get_pid(),
+                file.to_string_lossy(),
+            ),
+            Err(err) => {
+                trace!(
+                    pid = self.get_pid();
+                    "failed to read /proc/{}/{}: {err}",
+                    self.get_pid(),
+                    file.to_string_lossy(),
+                );
+                return Err(err);
+            }
+        }
         Ok(out)
     }
 
+    /// Get the process' audit login uid (as seen from our user namespace).
+    ///
+    /// Returns `None` if it is not set or the kernel was built without audit support.
+    pub fn get_loginuid(&self) -> io::Result<Option<libc::uid_t>> {
+        let data = match self.read_file(c_str!("loginuid")) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let uid = std::str::from_utf8(&data)
+            .ok()
+            .and_then(|s| s.trim().parse::<libc::uid_t>().ok())
+            .ok_or_else(|| io_format_err!("failed to parse loginuid from proc"))?;
+
+        // `(uid_t)-1` means "unset"
+        Ok(Some(uid).filter(|&uid| uid != libc::uid_t::MAX))
+    }
+
+    /// Check whether processes in this process' user namespace may call `setgroups()`, see
+    /// `user_namespaces(7)`.
+    pub fn setgroups_allowed(&self) -> io::Result<bool> {
+        Self::parse_setgroups(&self.read_file(c_str!("setgroups"))?)
+    }
+
+    fn parse_setgroups(data: &[u8]) -> io::Result<bool> {
+        match data {
+            b"allow\n" => Ok(true),
+            b"deny\n" => Ok(false),
+            other => Err(io_format_err!(
+                "unexpected contents in setgroups file: {:?}",
+                String::from_utf8_lossy(other)
+            )),
+        }
+    }
+
     pub fn user_caps(&self) -> Result<UserCaps, Error> {
         UserCaps::new(self)
     }
+
+    /// Check whether the process still exists.
+    ///
+    /// Once a process was reaped, lookups inside its proc directory fail, even if the pid has
+    /// been reused already.
+    pub fn is_alive(&self) -> bool {
+        unsafe { libc::faccessat(self.as_raw_fd(), c_str!("stat").as_ptr(), libc::F_OK, 0) == 0 }
+    }
+
+    /// Read the process' memory via `process_vm_readv()`.
+    ///
+    /// This behaves like a `pread()` on `/proc/<pid>/mem`: the remote range is split at page
+    /// boundaries so a read running into an unmapped page returns the bytes before it, and a read
+    /// starting in an unmapped page fails with `EIO`.
+    ///
+    /// Since `process_vm_readv()` works on the plain pid, we verify that the process is still
+    /// alive after the read, otherwise the pid may have been reused and we read from the wrong
+    /// process. If the pid is not valid in our pid namespace this fails with `EPERM`, so callers
+    /// fall back to the mem fd.
+    pub fn read_mem(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        const PAGE_SIZE: u64 = 4096;
+
+        if !self.pid_is_ours()? {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        let mut remote = Vec::new();
+        let mut pos = offset;
+        let end = offset.saturating_add(buf.len() as u64);
+        while pos < end {
+            let next = (pos / PAGE_SIZE + 1).saturating_mul(PAGE_SIZE).min(end);
+            remote.push(libc::iovec {
+                iov_base: pos as usize as *mut libc::c_void,
+                iov_len: (next - pos) as usize,
+            });
+            pos = next;
+        }
+
+        let local = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+
+        let got = match c_result!(unsafe {
+            libc::process_vm_readv(self.1, &local, 1, remote.as_ptr(), remote.len() as _, 0)
+        }) {
+            Ok(got) => got as usize,
+            // match the error /proc/<pid>/mem gives us for unmapped memory
+            Err(ref err) if err.raw_os_error() == Some(libc::EFAULT) => {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            Err(err) => return Err(err),
+        };
+
+        if !self.is_alive() {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+
+        Ok(got)
+    }
+}
+
+lazy_static! {
+    static ref PROCESS_VM_READV_USABLE: bool = {
+        let value: u64 = 0x1234_5678_9abc_def0;
+        let mut out = [0u8; 8];
+        match PidFd::current() {
+            Ok(this) => {
+                let got = this.read_mem(&mut out, &value as *const u64 as usize as u64);
+                matches!(got, Ok(8)) && u64::from_ne_bytes(out) == value
+            }
+            Err(_) => false,
+        }
+    };
+}
+
+/// Check whether `process_vm_readv()` is available and usable by us.
+///
+/// This is probed once, on kernels without support (or with it blocked by a seccomp filter) we
+/// need to fall back to reading `/proc/<pid>/mem`.
+pub fn process_vm_readv_usable() -> bool {
+    *PROCESS_VM_READV_USABLE
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+    use std::time::Instant;
+
+    use super::*;
+
+    const STATUS: &str = "\
+Name:\tbash
+Umask:\t0027
+State:\tS (sleeping)
+Uid:\t1000\t1001\t1002\t1003
+Gid:\t100\t101\t102\t103
+Groups:\t4 27 100 
+CapInh:\t0000000000000000
+CapPrm:\t00000000a80425fb
+CapEff:\t00000000a80425fb
+CapBnd:\t000001ffffffffff
+NoNewPrivs:\t1
+Seccomp:\t2
+";
+
+    #[test]
+    fn parse_status() {
+        let status = PidFd::parse_status(STATUS.as_bytes(), StatusFields::all()).unwrap();
+        assert_eq!(
+            (
+                status.uids.ruid,
+                status.uids.euid,
+                status.uids.suid,
+                status.uids.fsuid
+            ),
+            (1000, 1001, 1002, 1003)
+        );
+        assert_eq!(
+            (
+                status.uids.rgid,
+                status.uids.egid,
+                status.uids.sgid,
+                status.uids.fsgid
+            ),
+            (100, 101, 102, 103)
+        );
+        assert_eq!(status.groups, [4, 27, 100]);
+        assert_eq!(status.capabilities.inheritable, 0);
+        assert_eq!(status.capabilities.permitted, 0xa804_25fb);
+        assert_eq!(status.capabilities.effective, 0xa804_25fb);
+        assert_eq!(status.cap_bounding, 0x1ff_ffff_ffff);
+        assert_eq!(status.umask, 0o027);
+        assert!(status.no_new_privs);
+    }
+
+    #[test]
+    fn parse_status_without_no_new_privs() {
+        // kernels before 4.10 don't have the field
+        let old = STATUS.replace("NoNewPrivs:\t1\n", "");
+        let status = PidFd::parse_status(old.as_bytes(), StatusFields::all()).unwrap();
+        assert!(!status.no_new_privs);
+        assert_eq!(status.umask, 0o027);
+
+        let unset = STATUS.replace("NoNewPrivs:\t1", "NoNewPrivs:\t0");
+        let status = PidFd::parse_status(unset.as_bytes(), StatusFields::NO_NEW_PRIVS).unwrap();
+        assert!(!status.no_new_privs);
+    }
+
+    #[test]
+    fn parse_status_only_wanted_fields() {
+        let status = PidFd::parse_status(STATUS.as_bytes(), StatusFields::UMASK).unwrap();
+        assert_eq!(status.umask, 0o027);
+        assert_eq!(status.uids.euid, 0);
+        assert!(status.groups.is_empty());
+        assert!(!status.no_new_privs);
+
+        let bad = STATUS.replace("CapEff:\t00000000a80425fb", "CapEff:\tnope");
+        assert!(PidFd::parse_status(bad.as_bytes(), StatusFields::CAPS).is_err());
+        // a broken field we don't look at doesn't matter
+        PidFd::parse_status(bad.as_bytes(), StatusFields::UID).unwrap();
+    }
+
+    #[test]
+    fn parse_uid_gid_map() {
+        // nothing mapped (yet), so no id is valid
+        let map = PidFd::parse_uid_gid_map(&b""[..]).unwrap();
+        assert_eq!(map.map_into(0), None);
+        assert_eq!(map.map_from(0), None);
+
+        // what a process in our user namespace sees
+        let map = PidFd::parse_uid_gid_map(&b"         0          0 4294967295\n"[..]).unwrap();
+        assert_eq!(map.map_into(0), Some(0));
+        assert_eq!(map.map_into(100_000), Some(100_000));
+        assert_eq!(
+            map.map_into(u64::from(u32::MAX) - 1),
+            Some(u64::from(u32::MAX) - 1)
+        );
+
+        let map = PidFd::parse_uid_gid_map(
+            &b"         0     100000       1000\n      1000       1000          1\n"[..],
+        )
+        .unwrap();
+        assert_eq!(map.map_into(100_000), Some(0));
+        assert_eq!(map.map_into(1000), Some(1000));
+        assert_eq!(map.map_into(0), None);
+
+        assert!(PidFd::parse_uid_gid_map(&b"0 100000\n"[..]).is_err());
+    }
+
+    #[test]
+    fn parse_starttime() {
+        // the command name is whatever the process set, including spaces and parentheses
+        let stat = b"1234 (weird )proc) S 1 1234 1234 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 \
+                     987654 12345678 300 18446744073709551615 1 1 0 0 0 0 0 0 0 0 0 17 3\n";
+        assert_eq!(PidFd::parse_starttime(stat).unwrap(), 987654);
+
+        assert!(PidFd::parse_starttime(b"1234 (truncated) S 1 1234").is_err());
+        assert!(PidFd::parse_starttime(b"1234 no comm").is_err());
+
+        let this = PidFd::current().unwrap();
+        assert!(this.get_starttime().unwrap() > 0);
+    }
+
+    #[test]
+    fn parse_setgroups() {
+        assert!(PidFd::parse_setgroups(b"allow\n").unwrap());
+        assert!(!PidFd::parse_setgroups(b"deny\n").unwrap());
+        assert!(PidFd::parse_setgroups(b"").is_err());
+        assert!(PidFd::parse_setgroups(b"allow").is_err());
+
+        // we are not in a user namespace with setgroups denied
+        let this = PidFd::current().unwrap();
+        assert!(this.setgroups_allowed().unwrap());
+    }
+
+    /// Fork a child which waits to be killed.
+    fn fork_child() -> pid_t {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            loop {
+                unsafe { libc::pause() };
+            }
+        }
+        pid
+    }
+
+    fn reap_killed(pid: pid_t) {
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(libc::WTERMSIG(status), libc::SIGKILL);
+    }
+
+    #[test]
+    fn kill() {
+        let pid = fork_child();
+        let child = PidFd::open(pid).unwrap();
+        child.kill(0).unwrap();
+        child.kill(libc::SIGKILL).unwrap();
+        reap_killed(pid);
+        assert_eq!(child.kill(0).unwrap_err().raw_os_error(), Some(libc::ESRCH));
+    }
+
+    #[test]
+    fn kill_by_pid() {
+        let pid = fork_child();
+        let child = PidFd::open(pid).unwrap();
+
+        // pretend the child's pid got reused by us, this must not hit the new process
+        let fd = unsafe { libc::fcntl(child.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        assert!(fd >= 0);
+        let reused = PidFd(
+            unsafe { OwnedFd::from_raw_fd(fd) },
+            unsafe { libc::getpid() },
+            FdTracker::new(),
+            AtomicU8::new(PID_OURS),
+        );
+        let err = reused.kill_by_pid(libc::SIGKILL).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+
+        child.kill_by_pid(libc::SIGKILL).unwrap();
+        reap_killed(pid);
+        let err = child.kill_by_pid(libc::SIGKILL).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+    }
+
+    #[test]
+    fn fd_num_follows_open_file() {
+        fn stat(fd: RawFd) -> (libc::dev_t, libc::ino_t) {
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            assert_eq!(unsafe { libc::fstat(fd, &mut stat) }, 0);
+            (stat.st_dev, stat.st_ino)
+        }
+
+        let root = std::env::temp_dir().join(format!(
+            "pve-lxc-syscalld-test-fd-num-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        let dir = std::fs::File::open(root.join("dir")).unwrap();
+
+        // the path the process opened now leads elsewhere
+        std::fs::rename(root.join("dir"), root.join("moved")).unwrap();
+        std::os::unix::fs::symlink("/", root.join("dir")).unwrap();
+
+        let this = PidFd::current().unwrap();
+        let fd = this.fd_num(dir.as_raw_fd(), libc::O_DIRECTORY).unwrap();
+        assert_eq!(stat(fd.as_raw_fd()), stat(dir.as_raw_fd()));
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+        assert_ne!(flags & libc::O_PATH, 0);
+
+        // and O_DIRECTORY still applies to what the process has open
+        let file = std::fs::File::create(root.join("file")).unwrap();
+        let err = this
+            .fd_num(file.as_raw_fd(), libc::O_DIRECTORY)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn test_data() -> Vec<u8> {
+        (0..4096).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn read_mem_matches_mem_file() {
+        if !process_vm_readv_usable() {
+            return;
+        }
+        let data = test_data();
+        let addr = data.as_ptr() as usize as u64;
+        let this = PidFd::current().unwrap();
+        let mem = this.open_file(c_str!("mem"), libc::O_RDONLY, 0).unwrap();
+
+        let mut direct = vec![0u8; data.len()];
+        let mut file = vec![0u8; data.len()];
+        assert_eq!(this.read_mem(&mut direct, addr).unwrap(), data.len());
+        assert_eq!(mem.read_at(&mut file, addr).unwrap(), data.len());
+        assert_eq!(direct, data);
+        assert_eq!(file, data);
+
+        // both fail the same way for unmapped memory
+        let err = this.read_mem(&mut direct, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        let err = mem.read_at(&mut file, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    }
+
+    /// Compare reading a 4 KiB struct via `process_vm_readv()` and via `/proc/<pid>/mem`.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_read_mem`.
+    #[test]
+    #[ignore]
+    fn bench_read_mem() {
+        const ROUNDS: u32 = 100_000;
+
+        let data = test_data();
+        let addr = data.as_ptr() as usize as u64;
+        let this = PidFd::current().unwrap();
+        let mem = this.open_file(c_str!("mem"), libc::O_RDONLY, 0).unwrap();
+        let mut buf = vec![0u8; data.len()];
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            mem.read_at(&mut buf, addr).unwrap();
+        }
+        let file = start.elapsed() / ROUNDS;
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            this.read_mem(&mut buf, addr).unwrap();
+        }
+        let direct = start.elapsed() / ROUNDS;
+
+        println!("4 KiB read: /proc/<pid>/mem {file:?}, process_vm_readv() {direct:?}");
+    }
 }
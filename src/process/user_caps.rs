@@ -3,11 +3,147 @@
 use std::ffi::{OsStr, OsString};
 use std::io;
 use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
 
 use anyhow::Error;
+use lazy_static::lazy_static;
 
-use super::PidFd;
-use crate::capability::Capabilities;
+use super::{CGroups, PidFd};
+use crate::capability::{self, Capabilities};
+use crate::fork::error_context;
+use crate::nsfd::{NsFd, NsType};
+
+/// Bits which are always added to the umask applied for a process, see [`set_min_umask`].
+static MIN_UMASK: AtomicU32 = AtomicU32::new(0);
+
+/// Make the umask used for all proxied syscalls at least as restrictive as `mask`.
+///
+/// This only affects the permission bits of newly created files and device nodes, existing ones
+/// are never touched.
+pub fn set_min_umask(mask: libc::mode_t) {
+    MIN_UMASK.store(mask & 0o777, Ordering::Relaxed);
+}
+
+/// The mask set via [`set_min_umask`], 0 if none was.
+pub fn min_umask() -> libc::mode_t {
+    MIN_UMASK.load(Ordering::Relaxed)
+}
+
+/// The umask to apply for a process with the umask `umask`, at least as restrictive as `min`.
+fn clamp_umask(umask: libc::mode_t, min: libc::mode_t) -> libc::mode_t {
+    umask | min
+}
+
+/// Name of a child cgroup to run helpers in, see [`set_helper_cgroup_subtree`].
+static HELPER_CGROUP_SUBTREE: Mutex<Option<OsString>> = Mutex::new(None);
+
+/// Run helper processes in a child cgroup `name` of the container's v2 cgroup instead of the
+/// process' cgroup, so they are accounted separately and can be frozen or killed independently.
+///
+/// lxc only delegates the [`LXC_INNER_CGROUP`] child of a container's cgroup to the container, so
+/// the helper cgroup is created next to it: there the container can neither tamper with it (eg.
+/// create it frozen before we do) nor is its own cgroup management disturbed by it, and lxc
+/// removes it along with the container's cgroup. Device restrictions of cgroups within the
+/// container (eg. of nested containers) do not apply to helpers in it, only the container's own.
+///
+/// Processes not in such a delegated cgroup, and any for which the helper cgroup cannot be
+/// created or entered, run helpers in their own cgroup.
+pub fn set_helper_cgroup_subtree(name: OsString) {
+    *HELPER_CGROUP_SUBTREE.lock().unwrap() = Some(name);
+}
+
+/// The child of a container's cgroup lxc delegates to the container
+/// (`lxc.cgroup.dir.container.inner`).
+const LXC_INNER_CGROUP: &[u8] = b"ns";
+
+/// The cgroup of the container a v2 `cgroup` is in: the part before the delegated
+/// [`LXC_INNER_CGROUP`], `None` if there is none.
+fn container_cgroup(cgroup: &OsStr) -> Option<&OsStr> {
+    let bytes = cgroup.as_bytes();
+    let mut start = 0;
+    for component in bytes.split(|b| *b == b'/') {
+        if component == LXC_INNER_CGROUP && start > 1 {
+            return Some(OsStr::from_bytes(&bytes[..(start - 1)]));
+        }
+        start += component.len() + 1;
+    }
+    None
+}
+
+/// Whether we already warned about a helper cgroup which could not be created.
+static HELPER_CGROUP_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Create the helper cgroup `subtree` for a process in the v2 cgroup `cgroup` below `root` and
+/// `base`, if it does not exist yet.
+///
+/// Returns the helper cgroup's name, or `None` if the process is not in a container's delegated
+/// cgroup, or (after warning once) if it cannot be created.
+fn create_helper_cgroup(
+    root: &str,
+    base: &str,
+    cgroup: &OsStr,
+    subtree: &OsStr,
+) -> Option<OsString> {
+    let mut name = container_cgroup(cgroup)?.to_owned();
+    name.push("/");
+    name.push(subtree);
+
+    let mut path = OsString::from(root);
+    path.push(base);
+    path.push(&name);
+    match std::fs::create_dir(&path) {
+        Ok(()) => Some(name),
+        // created for an earlier request, or concurrently
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Some(name),
+        Err(err) => {
+            if !HELPER_CGROUP_WARNED.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "failed to create helper cgroup {path:?}, using the processes' cgroups \
+                     (further failures are not logged): {err}"
+                );
+            }
+            None
+        }
+    }
+}
+
+/// The kernel's limit for the number of supplementary groups (`NGROUPS_MAX` in `linux/limits.h`).
+const NGROUPS_MAX: usize = 65536;
+
+/// Whether we already warned about a process without a devices cgroup, see [`ProcEnv::read`].
+static NO_DEVICES_CGROUP_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// The parts of a process' permission-check environment which are comparatively expensive to
+/// query and only change on explicit action (cgroup migration or an AppArmor profile change).
+///
+/// These may be cached for a short time via an [`EnvCache`](super::EnvCache), whereas the data
+/// from `/proc/<pid>/status` is always re-read by [`UserCaps::with_env`].
+#[derive(Clone)]
+pub struct ProcEnv {
+    cgroups: CGroups,
+    apparmor_profile: Option<OsString>,
+}
+
+impl ProcEnv {
+    pub fn read(pidfd: &PidFd) -> Result<Self, Error> {
+        let cgroups = pidfd.get_cgroups()?;
+        if !cgroups.has_devices() && !NO_DEVICES_CGROUP_WARNED.swap(true, Ordering::Relaxed) {
+            warn!(
+                pid = pidfd.get_pid();
+                "pid {} is in neither a v1 devices nor a v2 cgroup, \
+                 device access checks will not reflect the container \
+                 (not logged again for other processes)",
+                pidfd.get_pid(),
+            );
+        }
+
+        Ok(Self {
+            cgroups,
+            apparmor_profile: crate::apparmor::get_label(pidfd)?,
+        })
+    }
+}
 
 /// Helper to enter a process' permission-check environment.
 ///
@@ -19,9 +155,28 @@ use crate::capability::Capabilities;
 /// affected, and access to devices as well.
 ///
 /// Then we must enter the mount namespace, chroot and current working directory, in order to get
-/// the correct view of paths.
+/// the correct view of paths. If the process lives in a different time namespace, we enter that
+/// one first.
 ///
-/// Next we copy the caller's `umask`.
+/// We deliberately stay in our own (the initial) user namespace, however deeply nested the
+/// container's is:
+///
+/// * Entering the process' user namespace would turn the capabilities we copy into capabilities
+///   within that namespace, where the kernel refuses what we are here to do (eg. `mknod()` of a
+///   device node), so the helper would be no more privileged than the process itself.
+/// * Being in the initial user namespace, we have authority over every namespace, so `setns()`
+///   into the mount (and time, IPC, network) namespace works regardless of nesting. After entering
+///   a user namespace we would no longer be able to join namespaces owned by its ancestors, nor
+///   `chroot()`, join cgroups or change the AppArmor label.
+/// * The uids and gids we read from `/proc/<pid>/status` are in terms of our user namespace, ie.
+///   the host's kernel ids, so switching to them here yields exactly the caller's credentials.
+///   Inside the nested namespace they would have to be mapped through every level first.
+///
+/// The flip side is that the capabilities apply to our user namespace, so handlers must check
+/// themselves whatever the kernel would restrict for the container (see the security notes of
+/// the handlers).
+///
+/// Next we copy the caller's `umask`, optionally made more restrictive via `--min-umask`.
 ///
 /// Then switch over our effective and file system uid and gid. This has 2 reasons: First, it means
 /// we do not need to run `chown()` on files we create, secondly, the user may have dropped
@@ -32,6 +187,13 @@ use crate::capability::Capabilities;
 /// require us to handle ACLs, quotas, which are all file system tyep dependent as well, so better
 /// leave all that up to the kernel, too!)).
 ///
+/// We also copy the caller's supplementary groups, so group based permission checks don't see the
+/// daemon's groups instead.
+///
+/// If the process runs with `no_new_privs` we set it for ourselves as well. This only affects what
+/// an `execve()` may grant and does not interfere with the securebits we set, but it keeps the
+/// helper's environment consistent with the caller's.
+///
 /// Next we clone the process' capability set. This is because the process may have dropped
 /// capabilties which under normal conditions would prevent them from executing the syscall.  For
 /// example a process may be executing `mknod()` after having dropped `CAP_MKNOD`.
@@ -44,19 +206,129 @@ pub struct UserCaps<'a> {
     egid: libc::gid_t,
     fsuid: libc::uid_t,
     fsgid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
     capabilities: Capabilities,
     umask: libc::mode_t,
+    no_new_privs: bool,
     cgroup_v1_devices: Option<OsString>,
     cgroup_v2_base: &'static str,
     cgroup_v2: Option<OsString>,
+    /// The helper cgroup below `cgroup_v2`, already created.
+    cgroup_v2_subtree: Option<OsString>,
     apparmor_profile: Option<OsString>,
+    enter_ipc_namespace: bool,
+    enter_net_namespace: bool,
+    start_time: Option<u64>,
+}
+
+lazy_static! {
+    static ref DEVICES_CONTROLLER_MOUNTED: bool = {
+        let mounted = std::path::Path::new("/sys/fs/cgroup/devices").is_dir();
+        if !mounted {
+            warn!(
+                "cgroup v1 devices controller not mounted, \
+                 not entering containers' devices cgroups"
+            );
+        }
+        mounted
+    };
+}
+
+/// Drop the capabilities we cannot grant to a helper since they are not in our `bounding` set.
+///
+/// `capset()` would fail with `EPERM` for the whole set otherwise. Handlers see the result via
+/// [`UserCaps::capabilities`], so they do not attempt what the helper could not do anyway.
+fn clamp_capabilities(mut capabilities: Capabilities, bounding: u64) -> Capabilities {
+    let dropped = capabilities.clamp_to_bounding(bounding);
+    if dropped != 0 {
+        debug!(
+            "not granting {} to the helper, they are not in our bounding set",
+            capability::names(dropped)
+        );
+    }
+    capabilities
+}
+
+/// Set the fs uid or gid via `setfsuid()` or `setfsgid()`.
+///
+/// These don't report errors, but return the previous id. So we query the id again afterwards
+/// (-1 is never a valid id, so this changes nothing) and fail with `EPERM` if it was not applied.
+fn set_fs_id(set: unsafe extern "C" fn(u32) -> libc::c_int, id: u32) -> io::Result<()> {
+    unsafe {
+        set(id);
+        if set(u32::MAX) as u32 != id {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+    }
+    Ok(())
+}
+
+/// Enter the namespace `ns` unless it is the one we are already in (`own`).
+fn enter_namespace<T: NsType>(ns: NsFd<T>, own: NsFd<T>) -> io::Result<()> {
+    if !ns.is_same(&own)? {
+        ns.setns()?;
+    }
+    Ok(())
+}
+
+/// Where the cgroup hierarchies are mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup/";
+
+/// Move the current process into the cgroup `name` of the hierarchy `kind` below `root`.
+///
+/// Called in the forked helper, so rather than logging, errors carry the cgroup's path.
+// FIXME: Handle `kind` taking /proc/self/mountinfo into account instead of assuming "unified/"
+fn enter_cgroup(root: &str, kind: &str, name: &OsStr) -> io::Result<()> {
+    let mut path = OsString::with_capacity(root.len() + kind.len() + name.len() + 13 + 1);
+    path.push(root);
+    path.push(kind);
+    path.push(name);
+    path.push(OsStr::from_bytes(b"/cgroup.procs"));
+    std::fs::write(&path, b"0").map_err(|err| match err.raw_os_error() {
+        // The cgroup was removed, so the container (or at least the process) is gone, treat it
+        // like any other vanished target.
+        Some(libc::ENOENT) => io::Error::from_raw_os_error(libc::ESRCH),
+        _ => error_context(err, format_args!("failed to enter cgroup {path:?}")),
+    })
+}
+
+/// A process may be listed in a v1 devices cgroup which we cannot enter because the controller
+/// is not mounted on the host. Rather than failing all requests we skip it (and warn once).
+fn devices_controller_mounted() -> bool {
+    *DEVICES_CONTROLLER_MOUNTED
 }
 
 impl UserCaps<'_> {
     pub fn new(pidfd: &PidFd) -> Result<UserCaps, Error> {
+        UserCaps::with_env(pidfd, &ProcEnv::read(pidfd)?)
+    }
+
+    /// Create the permission-check environment using previously read cgroup and AppArmor data.
+    ///
+    /// The process' ids, capabilities and umask are always read freshly, since the process can
+    /// change them at any time.
+    pub fn with_env<'a>(pidfd: &'a PidFd, env: &ProcEnv) -> Result<UserCaps<'a>, Error> {
         let status = pidfd.get_status()?;
-        let cgroups = pidfd.get_cgroups()?;
-        let apparmor_profile = crate::apparmor::get_label(pidfd)?;
+        if crate::log::enabled(crate::log::Level::Debug)
+            && !status.groups.is_empty()
+            && matches!(pidfd.setgroups_allowed(), Ok(false))
+        {
+            // see `apply_user_caps()` for why this is fine
+            debug!(
+                "copying supplementary groups of pid {} although its user namespace denies \
+                 setgroups()",
+                pidfd.get_pid()
+            );
+        }
+        let cgroups = &env.cgroups;
+        let cgroup_v2_base = if cgroups.has_v1() { "unified/" } else { "" };
+        let subtree = HELPER_CGROUP_SUBTREE.lock().unwrap().clone();
+        let cgroup_v2_subtree = match (cgroups.v2(), subtree) {
+            (Some(cgroup), Some(subtree)) => {
+                create_helper_cgroup(CGROUP_ROOT, cgroup_v2_base, cgroup, &subtree)
+            }
+            _ => None,
+        };
 
         Ok(UserCaps {
             pidfd,
@@ -65,33 +337,39 @@ impl UserCaps<'_> {
             egid: status.uids.egid,
             fsuid: status.uids.fsuid,
             fsgid: status.uids.fsgid,
-            capabilities: status.capabilities,
+            groups: status.groups,
+            capabilities: clamp_capabilities(status.capabilities, capability::bounding_set()),
             umask: status.umask,
-            cgroup_v1_devices: cgroups.get("devices").map(|s| s.to_owned()),
-            cgroup_v2_base: if cgroups.has_v1() { "unified/" } else { "" },
+            no_new_privs: status.no_new_privs,
+            cgroup_v1_devices: cgroups
+                .get("devices")
+                .filter(|_| devices_controller_mounted())
+                .map(|s| s.to_owned()),
+            cgroup_v2_base,
             cgroup_v2: cgroups.v2().map(|s| s.to_owned()),
-            apparmor_profile,
+            cgroup_v2_subtree,
+            apparmor_profile: env.apparmor_profile.clone(),
+            enter_ipc_namespace: false,
+            enter_net_namespace: false,
+            start_time: None,
         })
     }
 
     fn apply_cgroups(&self) -> io::Result<()> {
-        // FIXME: Handle `kind` taking /proc/self/mountinfo into account instead of assuming
-        // "unified/"
-        fn enter_cgroup(kind: &str, name: &OsStr) -> io::Result<()> {
-            let mut path = OsString::with_capacity(15 + kind.len() + name.len() + 13 + 1);
-            path.push(OsStr::from_bytes(b"/sys/fs/cgroup/"));
-            path.push(kind);
-            path.push(name);
-            path.push(OsStr::from_bytes(b"/cgroup.procs"));
-            std::fs::write(path, b"0")
-        }
-
         if let Some(ref cg) = self.cgroup_v1_devices {
-            enter_cgroup("devices/", cg)?;
+            enter_cgroup(CGROUP_ROOT, "devices/", cg)?;
         }
 
         if let Some(ref cg) = self.cgroup_v2 {
-            enter_cgroup(self.cgroup_v2_base, cg)?;
+            // The parent already created the helper cgroup, so this only fails if it was removed
+            // in the meantime. We cannot log here, and the process' cgroup is a fine fallback.
+            if let Some(ref subtree) = self.cgroup_v2_subtree {
+                if enter_cgroup(CGROUP_ROOT, self.cgroup_v2_base, subtree).is_ok() {
+                    return Ok(());
+                }
+            }
+
+            enter_cgroup(CGROUP_ROOT, self.cgroup_v2_base, cg)?;
         }
 
         Ok(())
@@ -101,20 +379,52 @@ impl UserCaps<'_> {
         use crate::capability::SecureBits;
         if self.apply_uids {
             unsafe {
-                libc::umask(self.umask);
+                libc::umask(clamp_umask(self.umask, min_umask()));
             }
             let mut secbits = SecureBits::get_current()?;
             secbits |= SecureBits::KEEP_CAPS | SecureBits::NO_SETUID_FIXUP;
             secbits.apply()?;
-            c_try!(unsafe { libc::setegid(self.egid) });
-            c_try!(unsafe { libc::setfsgid(self.fsgid) });
-            c_try!(unsafe { libc::seteuid(self.euid) });
-            c_try!(unsafe { libc::setfsuid(self.fsuid) });
+            // The ids in `/proc/<pid>/status` are shown as seen from the reader's user namespace,
+            // which is the host's for us, so the group list needs no further mapping.
+            // We copy the list even if the container has `setgroups()` denied: that only prevents
+            // it from changing (and in particular dropping) its own groups, which is not what we
+            // do, whereas skipping this would leave us with our own groups instead.
+            // This requires CAP_SETGID, so it must happen before we `capset()` the process'
+            // capabilities, which usually won't include it for our user namespace.
+            if self.groups.len() > NGROUPS_MAX {
+                io_bail!("too many supplementary groups ({})", self.groups.len());
+            }
+            c_result!(unsafe { libc::setgroups(self.groups.len(), self.groups.as_ptr()) })
+                .map_err(|err| error_context(err, "failed to set supplementary groups"))?;
+            c_result!(unsafe { libc::setegid(self.egid) }).map_err(|err| {
+                error_context(err, format!("failed to set egid to {}", self.egid))
+            })?;
+            set_fs_id(libc::setfsgid, self.fsgid).map_err(|err| {
+                error_context(err, format!("failed to set fsgid to {}", self.fsgid))
+            })?;
+            c_result!(unsafe { libc::seteuid(self.euid) }).map_err(|err| {
+                error_context(err, format!("failed to set euid to {}", self.euid))
+            })?;
+            set_fs_id(libc::setfsuid, self.fsuid).map_err(|err| {
+                error_context(err, format!("failed to set fsuid to {}", self.fsuid))
+            })?;
         }
-        self.capabilities.capset()?;
+        if self.no_new_privs {
+            c_result!(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })
+                .map_err(|err| error_context(err, "failed to set no_new_privs"))?;
+        }
+        self.capabilities
+            .capset()
+            .map_err(|err| error_context(err, "failed to set capabilities"))?;
         Ok(())
     }
 
+    /// The capabilities the process had when this was created, without those outside of our
+    /// bounding set.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
     pub fn disable_uid_change(&mut self) {
         self.apply_uids = false;
     }
@@ -124,11 +434,70 @@ impl UserCaps<'_> {
         self.cgroup_v2 = None;
     }
 
+    /// Also enter the process' IPC namespace, for handlers dealing with IPC objects.
+    pub fn enable_ipc_namespace(&mut self) {
+        self.enter_ipc_namespace = true;
+    }
+
+    /// Also enter the process' network namespace, for handlers which need its view of the
+    /// network. Only use this where necessary, entering a network namespace is comparatively
+    /// expensive.
+    pub fn enable_net_namespace(&mut self) {
+        self.enter_net_namespace = true;
+    }
+
+    /// Fail with `ESRCH` in `apply()` unless the process still has this start time (see
+    /// [`PidFd::get_starttime`]) after entering its namespaces.
+    pub fn verify_start_time(&mut self, start_time: u64) {
+        self.start_time = Some(start_time);
+    }
+
+    fn check_start_time(&self) -> io::Result<()> {
+        let expected = match self.start_time {
+            Some(start_time) => start_time,
+            None => return Ok(()),
+        };
+        if self.pidfd.get_starttime()? != expected {
+            return Err(error_context(
+                io::Error::from_raw_os_error(libc::ESRCH),
+                format!(
+                    "process {} changed its start time, the pid was reused",
+                    self.pidfd.get_pid()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn apply_time_namespace(&self, own_pidfd: &PidFd) -> io::Result<()> {
+        let time_ns = match self.pidfd.time_namespace() {
+            Ok(ns) => ns,
+            // no time namespace support in the kernel
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        enter_namespace(time_ns, own_pidfd.time_namespace()?)
+    }
+
+    fn apply_namespaces(&self, own_pidfd: &PidFd) -> io::Result<()> {
+        self.apply_time_namespace(own_pidfd)?;
+        if self.enter_ipc_namespace {
+            enter_namespace(self.pidfd.ipc_namespace()?, own_pidfd.ipc_namespace()?)?;
+        }
+        if self.enter_net_namespace {
+            enter_namespace(self.pidfd.net_namespace()?, own_pidfd.net_namespace()?)?;
+        }
+        // not the user namespace, see the `UserCaps` documentation
+        self.pidfd.mount_namespace()?.setns()
+    }
+
     pub fn apply(self, own_pidfd: &PidFd) -> io::Result<()> {
         self.apply_cgroups()?;
-        self.pidfd.mount_namespace()?.setns()?;
+        self.apply_namespaces(own_pidfd)?;
         self.pidfd.enter_chroot()?;
         self.pidfd.enter_cwd()?;
+        self.check_start_time()?;
         if let Some(ref label) = self.apparmor_profile {
             crate::apparmor::set_label(own_pidfd, label)?;
         }
@@ -136,3 +505,179 @@ impl UserCaps<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::syscall::SyscallStatus;
+
+    /// A directory tree looking like a cgroup hierarchy, removed again on drop.
+    struct FakeCGroups(PathBuf);
+
+    impl FakeCGroups {
+        fn new(cgroups: &[&str]) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "pve-lxc-syscalld-test-cgroups-{}",
+                std::process::id()
+            ));
+            for cgroup in cgroups {
+                let dir = root.join(cgroup);
+                std::fs::create_dir_all(&dir).unwrap();
+                std::fs::write(dir.join("cgroup.procs"), b"").unwrap();
+            }
+            Self(root)
+        }
+
+        fn root(&self) -> String {
+            format!("{}/", self.0.display())
+        }
+
+        fn procs(&self, cgroup: &str) -> Vec<u8> {
+            std::fs::read(self.0.join(cgroup).join("cgroup.procs")).unwrap()
+        }
+    }
+
+    impl Drop for FakeCGroups {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn umask_clamp() {
+        let path = std::env::temp_dir().join(format!(
+            "pve-lxc-syscalld-test-umask-{}",
+            std::process::id()
+        ));
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+
+        // the umask is per process, so create the node in a helper like the handlers do
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(crate::fork::forking_syscall(move || {
+            unsafe {
+                libc::umask(clamp_umask(0o002, 0o027));
+            }
+            let mode = libc::S_IFREG | 0o666;
+            if unsafe { libc::mknod(c_path.as_ptr(), mode, 0) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(SyscallStatus::Ok(0))
+        }));
+        let metadata = std::fs::metadata(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert!(matches!(result, Ok(SyscallStatus::Ok(0))));
+        // 0o666 & !(0o002 | 0o027)
+        assert_eq!(metadata.unwrap().permissions().mode() & 0o777, 0o640);
+    }
+
+    #[test]
+    fn capabilities_clamp() {
+        let bounding = (1 << capability::CAP_MKNOD) | (1 << capability::CAP_SYS_ADMIN);
+        let capabilities = Capabilities {
+            inheritable: 1 << capability::CAP_SYS_ADMIN,
+            permitted: (1 << capability::CAP_MKNOD) | (1 << capability::CAP_SYS_CHROOT),
+            effective: 1 << capability::CAP_SYS_CHROOT,
+        };
+
+        let clamped = clamp_capabilities(capabilities.clone(), bounding);
+        assert_eq!(clamped.inheritable, 1 << capability::CAP_SYS_ADMIN);
+        assert_eq!(clamped.permitted, 1 << capability::CAP_MKNOD);
+        assert_eq!(clamped.effective, 0);
+
+        // nothing outside of the bounding set, nothing to drop
+        let clamped = clamp_capabilities(capabilities, !0);
+        assert_eq!(
+            clamped.permitted,
+            (1 << capability::CAP_MKNOD) | (1 << capability::CAP_SYS_CHROOT)
+        );
+    }
+
+    #[test]
+    fn enter_own_namespace_is_skipped() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(crate::fork::forking_syscall(|| {
+            let own = PidFd::current()?;
+            // without capabilities any actual `setns()` fails
+            Capabilities::default().capset()?;
+            match own.net_namespace()?.setns() {
+                Err(err) if err.raw_os_error() == Some(libc::EPERM) => (),
+                _ => return Ok(SyscallStatus::Err(libc::EINVAL)),
+            }
+
+            enter_namespace(own.ipc_namespace()?, own.ipc_namespace()?)?;
+            enter_namespace(own.net_namespace()?, own.net_namespace()?)?;
+            Ok(SyscallStatus::Ok(0))
+        }));
+        assert!(matches!(result, Ok(SyscallStatus::Ok(0))));
+    }
+
+    #[test]
+    fn helper_cgroup() {
+        assert_eq!(
+            container_cgroup(OsStr::new("/lxc/100/ns/system.slice/foo.service")),
+            Some(OsStr::new("/lxc/100"))
+        );
+        assert_eq!(
+            container_cgroup(OsStr::new("/lxc/100/ns")),
+            Some(OsStr::new("/lxc/100"))
+        );
+        // not delegated by lxc
+        for cgroup in ["/lxc/100", "/ns", "/", "/user.slice/nsd.service"] {
+            assert_eq!(container_cgroup(OsStr::new(cgroup)), None, "{cgroup}");
+        }
+
+        let cgroups = FakeCGroups::new(&["unified/lxc/100/ns/init.scope"]);
+        let root = cgroups.root();
+        let cgroup = OsStr::new("/lxc/100/ns/init.scope");
+        for _ in 0..2 {
+            let name = create_helper_cgroup(&root, "unified/", cgroup, OsStr::new("helpers"));
+            assert_eq!(name.as_deref(), Some(OsStr::new("/lxc/100/helpers")));
+        }
+        assert!(cgroups.0.join("unified/lxc/100/helpers").is_dir());
+        assert!(!cgroups
+            .0
+            .join("unified/lxc/100/ns/init.scope/helpers")
+            .exists());
+
+        let cgroup = OsStr::new("/system.slice/foo.service");
+        assert_eq!(
+            create_helper_cgroup(&root, "unified/", cgroup, OsStr::new("helpers")),
+            None
+        );
+    }
+
+    #[test]
+    fn enter_cgroups() {
+        let cgroups = FakeCGroups::new(&["devices/lxc/100", "unified/lxc/100"]);
+        let root = cgroups.root();
+
+        enter_cgroup(&root, "devices/", OsStr::new("lxc/100")).unwrap();
+        assert_eq!(cgroups.procs("devices/lxc/100"), b"0");
+        enter_cgroup(&root, "unified/", OsStr::new("lxc/100")).unwrap();
+        assert_eq!(cgroups.procs("unified/lxc/100"), b"0");
+
+        // a vanished cgroup means the container is gone
+        let err = enter_cgroup(&root, "unified/", OsStr::new("lxc/101")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+
+        // anything else keeps its errno and says which cgroup it was about
+        let err = enter_cgroup(&root, "devices/lxc/100/", OsStr::new("cgroup.procs"));
+        let err = err.unwrap_err();
+        assert_eq!(err.raw_os_error(), None);
+        assert!(
+            err.to_string()
+                .contains("lxc/100/cgroup.procs/cgroup.procs"),
+            "{err}"
+        );
+    }
+}
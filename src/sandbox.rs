@@ -0,0 +1,132 @@
+//! Optional seccomp filter for forked helpers (`--sandbox-helpers`).
+//!
+//! Helpers run with the target process' credentials and capabilities, in its namespaces, and
+//! perform a single syscall with arguments controlled by the container. As a defense in depth
+//! measure they can restrict themselves to that syscall right before performing it, once all of
+//! the setup is done, so that a compromised helper cannot do anything else.
+//!
+//! Apart from the handler specific syscalls listed below, helpers may only use what is needed to
+//! send their result and exit: `write`, `close` (and `fcntl`), `exit`, `exit_group`, memory
+//! management (`brk`, `mmap`, `munmap`, `mremap`, `madvise`), `futex` and the signal handling
+//! syscalls the standard library may use when unwinding a panic. Anything else kills the helper,
+//! which makes the request fail with `EPERM` and is logged.
+//!
+//! | handler    | additional syscalls                                       |
+//! |------------|-----------------------------------------------------------|
+//! | `mknod`    | `mknodat`                                                 |
+//! | `quotactl` | `quotactl`, `pwrite64` (writing results to memory)        |
+//! | `renameat2`| `openat`, `newfstatat`, `renameat2`                       |
+//! | `fchownat` | `openat`, `newfstatat`, `fchownat`                        |
+//! | `umount2`  | `openat`, `newfstatat`, `readlinkat`, `fchdir`, `umount2` |
+//!
+//! Since our capabilities usually don't include `CAP_SYS_ADMIN` at this point, installing the
+//! filter requires setting `no_new_privs` first, which makes no difference to the helpers.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc::{c_long, sock_filter};
+
+/// The only architecture our own (helper) processes use.
+pub const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Offsets into `struct seccomp_data`.
+pub const DATA_NR: u32 = 0;
+pub const DATA_ARCH: u32 = 4;
+
+/// Syscalls every helper needs to report its result and exit.
+const ESSENTIAL: &[c_long] = &[
+    libc::SYS_write,
+    libc::SYS_close,
+    // with debug assertions the standard library checks descriptors are valid before closing them
+    libc::SYS_fcntl,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_futex,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Make helpers call `apply()` actually install a filter.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Restrict the current (helper) process to `syscalls` and the essential ones, if enabled.
+///
+/// Call this right before performing the syscall, after `UserCaps::apply()`.
+pub fn apply(syscalls: &[c_long]) -> io::Result<()> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let allowed: Vec<c_long> = ESSENTIAL.iter().chain(syscalls).copied().collect();
+    let mut filter = vec![
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARCH),
+        jump(libc::BPF_JEQ, AUDIT_ARCH_X86_64, 1, 0),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_NR),
+        jump(libc::BPF_JSET, X32_SYSCALL_BIT, 0, 1),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+    ];
+    for (i, nr) in allowed.iter().enumerate() {
+        // jump to the final ALLOW, past the remaining comparisons and the KILL
+        let skip = (allowed.len() - i) as u8;
+        filter.push(jump(libc::BPF_JEQ, *nr as u32, skip, 0));
+    }
+    filter.push(stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_KILL_PROCESS,
+    ));
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+
+    c_try!(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) });
+    c_try!(unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            0,
+            &prog as *const libc::sock_fprog,
+        )
+    });
+    Ok(())
+}
+
+/// A BPF statement.
+pub fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+/// A BPF conditional jump comparing with `k`, `jt` and `jf` are relative to the next instruction.
+pub fn jump(op: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: (libc::BPF_JMP | op | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
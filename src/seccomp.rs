@@ -2,9 +2,12 @@
 //!
 //! Mostly provides data structures.
 
+use std::ffi::CStr;
 use std::os::raw::c_int;
 use std::{io, mem};
 
+use lazy_static::lazy_static;
+
 /// Contains syscall data.
 #[repr(C)]
 pub struct SeccompData {
@@ -33,14 +36,33 @@ pub struct SeccompNotifResp {
     pub id: u64,
     pub val: i64,
     pub error: i32,
-    pub flags: u32,
+    pub flags: SeccompNotifRespFlags,
+}
+
+// The layouts of `linux/seccomp.h`, these are what `SeccompNotifSizes::check()` compares with the
+// kernel's sizes at runtime.
+const _: () = {
+    assert!(mem::size_of::<SeccompData>() == 64);
+    assert!(mem::size_of::<SeccompNotif>() == 80);
+    assert!(mem::size_of::<SeccompNotifResp>() == 24);
+};
+
+bitflags::bitflags! {
+    /// Flags of a `SeccompNotifResp`.
+    #[repr(transparent)]
+    pub struct SeccompNotifRespFlags: u32 {
+        /// `SECCOMP_USER_NOTIF_FLAG_CONTINUE`: run the syscall as if it had not been intercepted.
+        ///
+        /// `val` and `error` must be 0 with this flag.
+        const CONTINUE = 1;
+    }
 }
 
 /// Information about the actual sizes of `SeccompNotif`, and `SeccompNotifResp` and `SeccompData`.
 ///
 /// If the sizes mismatch it is likely that the kernel has an incompatible view of these data
 /// structures.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 #[repr(C)]
 pub struct SeccompNotifSizes {
     pub notif: u16,
@@ -48,6 +70,16 @@ pub struct SeccompNotifSizes {
     pub data: u16,
 }
 
+impl std::fmt::Display for SeccompNotifSizes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "notif={}, notif_resp={}, data={}",
+            self.notif, self.notif_resp, self.data
+        )
+    }
+}
+
 impl SeccompNotifSizes {
     /// Query the kernel for its data structure sizes.
     pub fn get() -> io::Result<Self> {
@@ -98,3 +130,30 @@ impl SeccompNotifSizes {
         Ok(this)
     }
 }
+
+/// Check whether the kernel supports `SECCOMP_USER_NOTIF_FLAG_CONTINUE`.
+///
+/// The flag can only be tested by responding to an actual notification, so this checks the kernel
+/// version it was introduced with (5.5) instead.
+pub fn check_flag_continue() -> io::Result<()> {
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    c_try!(unsafe { libc::uname(&mut uts) });
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) }.to_string_lossy();
+
+    let mut parts = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>().ok());
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(major), Some(minor)) if (major, minor) >= (5, 5) => Ok(()),
+        (Some(_), Some(_)) => Err(io_format_err!("kernel {release} is older than 5.5")),
+        _ => Err(io_format_err!("failed to parse kernel version {release:?}")),
+    }
+}
+
+/// Cached result of `check_flag_continue()`.
+pub fn flag_continue_supported() -> bool {
+    lazy_static! {
+        static ref SUPPORTED: bool = check_flag_continue().is_ok();
+    }
+    *SUPPORTED
+}
@@ -0,0 +1,304 @@
+//! `--selftest`: handle a syscall of one of our own child processes, without a container.
+//!
+//! The child enters a new user namespace, installs a seccomp filter which sends its `mknodat()`
+//! calls to us, and creates a `/dev/null` node in a temporary directory. We handle the
+//! notification with the same code as messages from lxc (which forks a helper taking over the
+//! child's credentials, cgroups and namespaces), respond to it and check that the node exists
+//! afterwards.
+
+use std::ffi::{CString, OsString};
+use std::future::Future;
+use std::io::{IoSlice, IoSliceMut};
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use anyhow::{bail, format_err, Error};
+use nix::sys::socket::{self, ControlMessage, ControlMessageOwned, MsgFlags};
+
+use crate::client;
+use crate::lxcseccomp::ProxyMessageBuffer;
+use crate::process::PidFd;
+use crate::sandbox::{self, jump, stmt};
+use crate::seccomp::{SeccompNotif, SeccompNotifResp};
+use crate::syscall::{self, CallerArch, SyscallStatus};
+
+/// How long to wait for the child's notification.
+const TIMEOUT_MS: c_int = 10_000;
+
+/// Run the self test and print the result to stdout like `--check`. Returns `true` on success.
+pub async fn run() -> bool {
+    let name = "mknodat() of /dev/null via seccomp user notification";
+    match run_do().await {
+        Ok(()) => {
+            println!("ok:     {name}");
+            true
+        }
+        Err(err) => {
+            println!("FAILED: {name}: {err}");
+            false
+        }
+    }
+}
+
+async fn run_do() -> Result<(), Error> {
+    run_child(handle_notification).await
+}
+
+/// Let a child process create a `/dev/null` node via `mknodat()` and check that it exists
+/// afterwards. `handle` gets the child's seccomp listener and must handle its notification.
+pub async fn run_child<F, R>(handle: F) -> Result<(), Error>
+where
+    F: FnOnce(OwnedFd) -> R,
+    R: Future<Output = Result<SyscallStatus, Error>>,
+{
+    let dir = TempDir::new()?;
+    let mut path = dir.0.clone();
+    path.push("/null");
+    let path = CString::new(path.into_vec())?;
+
+    let (ours, theirs) = socket::socketpair(
+        socket::AddressFamily::Unix,
+        socket::SockType::Stream,
+        None,
+        socket::SockFlag::SOCK_CLOEXEC,
+    )?;
+    let (ours, theirs) = unsafe { (OwnedFd::from_raw_fd(ours), OwnedFd::from_raw_fd(theirs)) };
+
+    let pid = c_try!(unsafe { libc::fork() });
+    if pid == 0 {
+        drop(ours);
+        let status = child(theirs, &path);
+        unsafe { libc::_exit(status) };
+    }
+    drop(theirs);
+    let mut child = Child(Some(pid));
+
+    let result = handle(recv_fd(&ours)?).await?;
+    if !matches!(result, SyscallStatus::Ok(0) | SyscallStatus::Continue) {
+        bail!("handler did not succeed");
+    }
+
+    let status = child.wait()?;
+    if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+        bail!(
+            "mknodat() in the child process failed (wait status {:#x})",
+            status
+        );
+    }
+
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    c_try!(unsafe { libc::stat(path.as_ptr(), &mut stat) });
+    if stat.st_mode & libc::S_IFMT != libc::S_IFCHR || stat.st_rdev != libc::makedev(1, 3) {
+        bail!("the created node is not /dev/null");
+    }
+
+    Ok(())
+}
+
+/// Handle the notification the child sends to the seccomp `listener`.
+async fn handle_notification(listener: OwnedFd) -> Result<SyscallStatus, Error> {
+    let request = recv_notification(&listener)?;
+
+    let pid_fd = PidFd::open(request.pid as libc::pid_t)?;
+    let mem = pid_fd.open_file(c_str!("mem"), libc::O_RDWR, 0)?;
+    let mut msg = ProxyMessageBuffer::from_parts(request, mem, pid_fd);
+
+    let data = &msg.request().data;
+    let arch = CallerArch::from_notification(data.arch, data.nr)
+        .ok_or_else(|| format_err!("unexpected architecture {:#x}", data.arch))?;
+    let syscall = syscall::translate_syscall(arch, data.nr)
+        .ok_or_else(|| format_err!("unexpected syscall {}", data.nr))?;
+
+    let result = client::handle_request(&msg, arch, syscall)
+        .await
+        .map_err(|err| format_err!("failed to handle the request: {err}"))?;
+
+    msg.set_result(&result);
+    send_response(&listener, msg.response_mut())?;
+
+    Ok(result)
+}
+
+/// Wait for a notification on a seccomp listener and receive it.
+pub fn recv_notification(listener: &OwnedFd) -> Result<SeccompNotif, Error> {
+    let mut pfd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    if c_try!(unsafe { libc::poll(&mut pfd, 1, TIMEOUT_MS) }) == 0 {
+        bail!("timed out waiting for the seccomp notification");
+    }
+
+    let mut request: SeccompNotif = unsafe { mem::zeroed() };
+    c_try!(unsafe {
+        libc::ioctl(
+            listener.as_raw_fd(),
+            libc::SECCOMP_IOCTL_NOTIF_RECV,
+            &mut request as *mut SeccompNotif,
+        )
+    });
+    Ok(request)
+}
+
+/// Send the response to a notification to a seccomp listener.
+pub fn send_response(listener: &OwnedFd, response: &mut SeccompNotifResp) -> Result<(), Error> {
+    c_try!(unsafe {
+        libc::ioctl(
+            listener.as_raw_fd(),
+            libc::SECCOMP_IOCTL_NOTIF_SEND,
+            response as *mut SeccompNotifResp,
+        )
+    });
+    Ok(())
+}
+
+fn recv_fd(socket: &OwnedFd) -> Result<OwnedFd, Error> {
+    let mut buf = [0u8; 1];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg_buf = nix::cmsg_space!(RawFd);
+    let msg = socket::recvmsg::<()>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buf),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(fd) = fds.first() {
+                return Ok(unsafe { OwnedFd::from_raw_fd(*fd) });
+            }
+        }
+    }
+    bail!("child process did not send a seccomp listener (it probably failed to install it)");
+}
+
+/// Install the filter, pass the listener to the parent and create the node. Returns the exit
+/// status.
+fn child(socket: OwnedFd, path: &CString) -> c_int {
+    let mut filter = [
+        stmt(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            sandbox::DATA_ARCH,
+        ),
+        jump(libc::BPF_JEQ, sandbox::AUDIT_ARCH_X86_64, 0, 3),
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, sandbox::DATA_NR),
+        jump(libc::BPF_JEQ, libc::SYS_mknodat as u32, 0, 1),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_USER_NOTIF),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+    ];
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+
+    // Like a container, so the handler cannot let us create the node ourselves (see
+    // `sys_mknod::privileged_caller()`) and we test the helper. Should user namespaces not be
+    // available, we still test handling the notification.
+    unsafe {
+        libc::unshare(libc::CLONE_NEWUSER);
+    }
+
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return 2;
+    }
+    let listener = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_NEW_LISTENER,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    if listener < 0 {
+        return 3;
+    }
+    let listener = unsafe { OwnedFd::from_raw_fd(listener as RawFd) };
+
+    let fds = [listener.as_raw_fd()];
+    if socket::sendmsg::<()>(
+        socket.as_raw_fd(),
+        &[IoSlice::new(b"x")],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )
+    .is_err()
+    {
+        return 4;
+    }
+    drop(listener);
+
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_mknodat,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::S_IFCHR | 0o666,
+            libc::makedev(1, 3),
+        )
+    };
+    if rc == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+/// Kills and reaps the child unless it was waited for.
+struct Child(Option<libc::pid_t>);
+
+impl Child {
+    fn wait(&mut self) -> Result<c_int, Error> {
+        let pid = self.0.take().unwrap();
+        let mut status: c_int = -1;
+        loop {
+            match c_result!(unsafe { libc::waitpid(pid, &mut status, 0) }) {
+                Ok(_) => return Ok(status),
+                Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            }
+            let _ = self.wait();
+        }
+    }
+}
+
+/// A temporary directory, removed along with a `null` node in it when dropped.
+struct TempDir(OsString);
+
+impl TempDir {
+    fn new() -> Result<Self, Error> {
+        let mut template = std::env::temp_dir().into_os_string();
+        template.push("/pve-lxc-syscalld-selftest.XXXXXX");
+        let mut template = CString::new(template.into_vec())?.into_bytes_with_nul();
+        if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+            return Err(format_err!(
+                "failed to create temporary directory: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        template.pop();
+        Ok(Self(OsString::from_vec(template)))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let dir = std::path::Path::new(self.0.as_os_str());
+        let _ = std::fs::remove_file(dir.join("null"));
+        let _ = std::fs::remove_dir(dir);
+    }
+}
@@ -0,0 +1,75 @@
+//! `SIGHUP`: reopen the audit log and reload the device allow list.
+//!
+//! The signal handler only writes to a pipe, a separate thread reads from it and does the actual
+//! work, so nothing but `write()` happens in signal context. Signals arriving while the thread is
+//! busy are coalesced into one more run.
+
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use crate::audit;
+use crate::sys_mknod;
+
+/// The pipe's write end, written to by the signal handler.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Install the `SIGHUP` handler and start the thread handling it.
+///
+/// Only call this if something is configured to be reloaded, otherwise `SIGHUP` should keep
+/// terminating us.
+pub fn install() -> io::Result<()> {
+    let mut fds = [0 as c_int; 2];
+    c_try!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) });
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    // the handler must never block, a full pipe already has a run pending
+    c_try!(unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) });
+
+    std::thread::Builder::new()
+        .name("sighup".to_string())
+        .spawn(move || handler_thread(read))?;
+
+    PIPE.store(write.into_raw_fd(), Ordering::Relaxed);
+    let handler = handle_sighup as extern "C" fn(c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+extern "C" fn handle_sighup(_signal: c_int) {
+    let fd = PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe {
+            let errno = *libc::__errno_location();
+            libc::write(fd, b"x".as_ptr() as *const libc::c_void, 1);
+            *libc::__errno_location() = errno;
+        }
+    }
+}
+
+fn handler_thread(pipe: OwnedFd) {
+    let mut buf = [0u8; 64];
+    loop {
+        let got = unsafe {
+            libc::read(
+                pipe.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if got < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("failed to read from the SIGHUP pipe, no longer reloading: {err}");
+            return;
+        }
+
+        info!("received SIGHUP, reloading");
+        audit::reopen();
+        sys_mknod::reload_device_list();
+    }
+}
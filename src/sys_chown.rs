@@ -0,0 +1,116 @@
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+use anyhow::Error;
+use nix::errno::Errno;
+
+use crate::fork::forking_syscall;
+use crate::lxcseccomp::ProxyMessageBuffer;
+use crate::process::{IdMap, PidFd};
+use crate::sandbox;
+use crate::sc_libc_try;
+use crate::syscall::SyscallStatus;
+
+/// Syscalls the helper may use with `--sandbox-helpers`, see the `sandbox` module.
+const SANDBOX: &[libc::c_long] = &[libc::SYS_openat, libc::SYS_newfstatat, libc::SYS_fchownat];
+
+/// `(uid_t)-1` / `(gid_t)-1` mean "leave unchanged".
+const ID_UNCHANGED: u32 = u32::MAX;
+
+/*
+ * int fchownat(int dirfd, const char *pathname, uid_t owner, gid_t group, int flags);
+ *
+ * With `AT_EMPTY_PATH` and an empty `pathname` this operates on `dirfd` itself, which may refer
+ * to any kind of file, not just a directory.
+ */
+pub async fn fchownat(msg: &ProxyMessageBuffer) -> Result<SyscallStatus, Error> {
+    let flags = msg.arg_int(4)?;
+    if flags & !(libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW) != 0 {
+        return Ok(Errno::EINVAL.into());
+    }
+
+    let pathname = msg.arg_c_string(1)?;
+    let empty_path = flags & libc::AT_EMPTY_PATH != 0 && pathname.as_bytes().is_empty();
+
+    // The container's fd is reopened via `/proc/<pid>/fd/<num>`, which gives us a new descriptor
+    // for the same file. For the empty-path case it must be passed on as `dirfd` together with the
+    // (still empty) path and `AT_EMPTY_PATH`, so the kernel acts on the file itself. Resolving it
+    // to a path instead would break for unlinked files and races with renames, and since the fd
+    // need not be a directory we must not require `O_DIRECTORY` here either.
+    let dirfd = if empty_path {
+        msg.arg_fd(0, libc::O_PATH)?
+    } else {
+        msg.arg_fd(0, libc::O_DIRECTORY)?
+    };
+
+    let uid_map = msg.pid_fd().get_uid_map()?;
+    let gid_map = msg.pid_fd().get_gid_map()?;
+    let owner = match map_id(&uid_map, msg.arg_uint(2)?) {
+        Some(owner) => owner,
+        None => return Ok(Errno::EINVAL.into()),
+    };
+    let group = match map_id(&gid_map, msg.arg_uint(3)?) {
+        Some(group) => group,
+        None => return Ok(Errno::EINVAL.into()),
+    };
+
+    let caps = msg.user_caps()?;
+
+    Ok(forking_syscall(move || {
+        caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
+
+        // The file is opened once and both checked and changed via that descriptor, so it cannot
+        // be swapped in between. For the empty-path case we already have such a descriptor.
+        let fd = if empty_path {
+            dirfd
+        } else {
+            let mut open_flags = libc::O_PATH | libc::O_CLOEXEC;
+            if flags & libc::AT_SYMLINK_NOFOLLOW != 0 {
+                open_flags |= libc::O_NOFOLLOW;
+            }
+            let fd = sc_libc_try!(unsafe {
+                libc::openat(dirfd.as_raw_fd(), pathname.as_ptr(), open_flags)
+            });
+            unsafe { OwnedFd::from_raw_fd(fd) }
+        };
+        let empty = b"\0".as_ptr() as *const libc::c_char;
+
+        // We apply the process' capabilities in *our* user namespace, so `CAP_CHOWN` would allow
+        // changing files the container does not own at all. Only touch files whose current owner
+        // is mapped into the container, as the kernel would require inside the container. The
+        // remaining checks (ownership, `CAP_CHOWN`) are left to the kernel.
+        let mut stat = MaybeUninit::<libc::stat>::uninit();
+        sc_libc_try!(unsafe {
+            libc::fstatat(
+                fd.as_raw_fd(),
+                empty,
+                stat.as_mut_ptr(),
+                libc::AT_EMPTY_PATH,
+            )
+        });
+        let stat = unsafe { stat.assume_init() };
+        if uid_map.map_into(u64::from(stat.st_uid)).is_none()
+            || gid_map.map_into(u64::from(stat.st_gid)).is_none()
+        {
+            return Ok(Errno::EPERM.into());
+        }
+
+        let out = sc_libc_try!(unsafe {
+            libc::fchownat(fd.as_raw_fd(), empty, owner, group, libc::AT_EMPTY_PATH)
+        });
+        Ok(SyscallStatus::Ok(out.into()))
+    })
+    .await?)
+}
+
+/// Map a container uid or gid to the host, keeping the "unchanged" value as is.
+fn map_id(map: &IdMap, id: u32) -> Option<u32> {
+    if id == ID_UNCHANGED {
+        return Some(id);
+    }
+
+    map.map_from(u64::from(id))
+        .and_then(|id| u32::try_from(id).ok())
+        .filter(|&id| id != ID_UNCHANGED)
+}
@@ -1,77 +1,583 @@
-use std::ffi::CString;
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::io;
 use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::sync::{Mutex, RwLock};
 
-use anyhow::Error;
+use anyhow::{bail, format_err, Error};
 use nix::errno::Errno;
 use nix::sys::stat;
 
+use crate::audit::{self, Decision};
+use crate::capability::CAP_MKNOD;
 use crate::fork::forking_syscall;
 use crate::lxcseccomp::ProxyMessageBuffer;
-use crate::process::PidFd;
+use crate::process::device::{DevKind, Device, DeviceRule};
+use crate::process::{self, PidFd};
+use crate::sandbox;
 use crate::sc_libc_try;
+use crate::seccomp;
 use crate::syscall::SyscallStatus;
 
 pub async fn mknod(msg: &ProxyMessageBuffer) -> Result<SyscallStatus, Error> {
     let mode = msg.arg_mode_t(1)?;
     let dev = msg.arg_dev_t(2)?;
-    if !check_mknod_dev(mode, dev) {
-        return Ok(Errno::EPERM.into());
+    let dev = match check_mknod_dev(mode, dev) {
+        Ok(device) if needs_no_privileges(&device) => return Ok(allow(msg, "mknod", mode, dev)),
+        Ok(device) => device.dev_t(),
+        Err(reason) => return Ok(deny(msg, "mknod", mode, dev, reason)),
+    };
+    if privileged_caller(msg)? {
+        return Ok(allow(msg, "mknod", mode, dev));
     }
 
-    let pathname = msg.arg_c_string(0)?;
-    let cwd = msg.pid_fd().fd_cwd()?;
-
-    do_mknodat(msg.pid_fd(), cwd, pathname, mode, dev).await
+    let result = async {
+        let pathname = msg.arg_c_string(0)?;
+        let cwd = msg.pid_fd().fd_cwd()?;
+        do_mknodat(msg, cwd, pathname, mode, dev).await
+    }
+    .await;
+    audit_result(msg, "mknod", mode, dev, &result);
+    result
 }
 
 pub async fn mknodat(msg: &ProxyMessageBuffer) -> Result<SyscallStatus, Error> {
     let mode = msg.arg_mode_t(2)?;
     let dev = msg.arg_dev_t(3)?;
-    if !check_mknod_dev(mode, dev) {
-        return Ok(Errno::EPERM.into());
+    let dev = match check_mknod_dev(mode, dev) {
+        Ok(device) if needs_no_privileges(&device) => return Ok(allow(msg, "mknodat", mode, dev)),
+        Ok(device) => device.dev_t(),
+        Err(reason) => return Ok(deny(msg, "mknodat", mode, dev, reason)),
+    };
+    if privileged_caller(msg)? {
+        return Ok(allow(msg, "mknodat", mode, dev));
+    }
+
+    let result = async {
+        let dirfd = msg.arg_fd(0, libc::O_DIRECTORY)?;
+        let pathname = msg.arg_c_string(1)?;
+        do_mknodat(msg, dirfd, pathname, mode, dev).await
+    }
+    .await;
+    audit_result(msg, "mknodat", mode, dev, &result);
+    result
+}
+
+/// Syscalls the helper may use with `--sandbox-helpers`, see the `sandbox` module.
+const SANDBOX: &[libc::c_long] = &[libc::SYS_mknodat];
+
+const fn rule(kind: DevKind, major: u64, minor: u64) -> DeviceRule {
+    DeviceRule {
+        kind,
+        major,
+        minor: Some(minor),
+    }
+}
+
+const ALLOWED_DEVICES: &[DeviceRule] = &[
+    rule(DevKind::Regular, 0, 0), // touch
+    rule(DevKind::Char, 0, 0),    // whiteout
+    rule(DevKind::Char, 5, 0),    // /dev/tty
+    rule(DevKind::Char, 5, 1),    // /dev/console
+    rule(DevKind::Char, 5, 2),    // /dev/ptmx
+    rule(DevKind::Char, 1, 3),    // /dev/null
+    rule(DevKind::Char, 1, 5),    // /dev/zero
+    rule(DevKind::Char, 1, 7),    // /dev/full
+    rule(DevKind::Char, 1, 8),    // /dev/random
+    rule(DevKind::Char, 1, 9),    // /dev/urandom
+    // neither of these grants access to a device
+    rule(DevKind::Fifo, 0, 0),
+    rule(DevKind::Socket, 0, 0),
+];
+
+/// The character device rules loaded from `--device-allow-list`, replacing those in
+/// `ALLOWED_DEVICES`. The other rules of `ALLOWED_DEVICES` are copied, since they don't grant
+/// access to any device.
+///
+/// Reloading replaces the whole list under the write lock, so a check always sees either the old
+/// or the new list.
+static DEVICE_LIST: RwLock<Option<Vec<DeviceRule>>> = RwLock::new(None);
+static DEVICE_LIST_PATH: Mutex<Option<OsString>> = Mutex::new(None);
+
+/// Load the character devices which may be created from a file, replacing the built-in list.
+///
+/// The file contains one `MAJOR:MINOR` per line, where `MINOR` may be `*` to allow all minor
+/// numbers. Empty lines and lines starting with `#` are ignored. Note that the whiteout device
+/// `0:0` used by overlayfs needs to be listed as well.
+///
+/// Returns the number of rules. On error the current list is kept.
+pub fn load_device_list(path: &OsStr) -> Result<usize, Error> {
+    let data = std::fs::read_to_string(path)?;
+    let rules = parse_device_list(&data)?;
+    let count = rules.len();
+
+    let list = ALLOWED_DEVICES
+        .iter()
+        .filter(|rule| rule.kind != DevKind::Char)
+        .copied()
+        .chain(rules)
+        .collect();
+    *DEVICE_LIST.write().unwrap() = Some(list);
+    *DEVICE_LIST_PATH.lock().unwrap() = Some(path.to_owned());
+    Ok(count)
+}
+
+/// Load the `--device-allow-list` file again, if there is one, keeping the current list if that
+/// fails.
+pub fn reload_device_list() {
+    let path = match &*DEVICE_LIST_PATH.lock().unwrap() {
+        Some(path) => path.clone(),
+        None => return,
+    };
+
+    match load_device_list(&path) {
+        Ok(count) => info!(
+            "reloaded device allow list {}: {count} rules",
+            path.to_string_lossy()
+        ),
+        Err(err) => error!(
+            "failed to reload device allow list {}, keeping the current one: {err}",
+            path.to_string_lossy()
+        ),
     }
+}
 
-    let dirfd = msg.arg_fd(0, libc::O_DIRECTORY)?;
-    let pathname = msg.arg_c_string(1)?;
+fn parse_device_list(data: &str) -> Result<Vec<DeviceRule>, Error> {
+    let mut rules = Vec::new();
+    for (n, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let rule = parse_device_rule(line).map_err(|err| format_err!("line {}: {err}", n + 1))?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
 
-    do_mknodat(msg.pid_fd(), dirfd, pathname, mode, dev).await
+fn parse_device_rule(line: &str) -> Result<DeviceRule, Error> {
+    let (major, minor) = line
+        .split_once(':')
+        .ok_or_else(|| format_err!("expected MAJOR:MINOR, got '{line}'"))?;
+    // limited to what the kernel's `mknod()` can encode, see `Device::split_dev()`
+    let major: u64 = major
+        .parse()
+        .ok()
+        .filter(|major| *major <= 0xfff)
+        .ok_or_else(|| format_err!("invalid major number '{major}'"))?;
+    let minor = match minor {
+        "*" => None,
+        minor => match minor.parse::<u64>() {
+            Ok(minor) if minor <= 0xfffff => Some(minor),
+            _ => bail!("invalid minor number '{minor}'"),
+        },
+    };
+    Ok(DeviceRule {
+        kind: DevKind::Char,
+        major,
+        minor,
+    })
 }
 
-fn check_mknod_dev(mode: stat::mode_t, dev: stat::dev_t) -> bool {
-    let sflag = mode & libc::S_IFMT;
-    let major = stat::major(dev);
-    let minor = stat::minor(dev);
+/// Why a `mknod()` was denied, for the log. The process always gets `EPERM`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DenyReason {
+    /// The file type bits of the mode are not a valid file type.
+    BadMode,
+    /// Block devices are never allowed.
+    BlockDevice,
+    /// The character device is not in `ALLOWED_DEVICES` or the `--device-allow-list`.
+    CharDeviceNotAllowed,
+    /// A regular file, FIFO or socket with a device number.
+    UnexpectedDevice,
+}
 
-    #[allow(clippy::match_like_matches_macro)]
-    match (sflag, major, minor) {
-        (libc::S_IFREG, 0, 0) => true, // touch
-        (libc::S_IFCHR, 0, 0) => true, // whiteout
-        (libc::S_IFCHR, 5, 0) => true, // /dev/tty
-        (libc::S_IFCHR, 5, 1) => true, // /dev/console
-        (libc::S_IFCHR, 5, 2) => true, // /dev/ptmx
-        (libc::S_IFCHR, 1, 3) => true, // /dev/null
-        (libc::S_IFCHR, 1, 5) => true, // /dev/zero
-        (libc::S_IFCHR, 1, 7) => true, // /dev/full
-        (libc::S_IFCHR, 1, 8) => true, // /dev/random
-        (libc::S_IFCHR, 1, 9) => true, // /dev/urandom
-        _ => false,
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DenyReason::BadMode => "invalid file type in mode",
+            DenyReason::BlockDevice => "block devices are not allowed",
+            DenyReason::CharDeviceNotAllowed => "character device is not in the allow list",
+            DenyReason::UnexpectedDevice => "device number given for a non-device file",
+        })
     }
 }
 
+/// Returns the device if it may be created.
+fn check_mknod_dev(mode: stat::mode_t, dev: stat::dev_t) -> Result<Device, DenyReason> {
+    let device = Device::from_mode_dev(mode, dev).ok_or(DenyReason::BadMode)?;
+    let allowed = match &*DEVICE_LIST.read().unwrap() {
+        Some(list) => device.matches(list),
+        None => device.matches(ALLOWED_DEVICES),
+    };
+    if allowed {
+        return Ok(device);
+    }
+    Err(match device.kind {
+        DevKind::Block => DenyReason::BlockDevice,
+        DevKind::Char => DenyReason::CharDeviceNotAllowed,
+        DevKind::Regular | DevKind::Fifo | DevKind::Socket => DenyReason::UnexpectedDevice,
+    })
+}
+
+fn deny(
+    msg: &ProxyMessageBuffer,
+    syscall: &str,
+    mode: stat::mode_t,
+    dev: stat::dev_t,
+    reason: DenyReason,
+) -> SyscallStatus {
+    let (major, minor) = Device::split_dev(dev);
+    debug!(
+        pid = msg.pid_fd().get_pid(), syscall = syscall;
+        "denied {syscall}() for pid {}: {reason} (mode {mode:#o}, device {major}:{minor})",
+        msg.pid_fd().get_pid(),
+    );
+    audit::mknod(msg, syscall, mode, dev, Decision::Denied(&reason));
+    Errno::EPERM.into()
+}
+
+/// Let the process create the node itself.
+fn allow(
+    msg: &ProxyMessageBuffer,
+    syscall: &str,
+    mode: stat::mode_t,
+    dev: stat::dev_t,
+) -> SyscallStatus {
+    audit::mknod(msg, syscall, mode, dev, Decision::Continue);
+    SyscallStatus::Continue
+}
+
+fn audit_result(
+    msg: &ProxyMessageBuffer,
+    syscall: &str,
+    mode: stat::mode_t,
+    dev: stat::dev_t,
+    result: &Result<SyscallStatus, Error>,
+) {
+    let decision = match result {
+        Ok(SyscallStatus::Err(errno)) => Decision::Failed(Some(*errno)),
+        Ok(_) => Decision::Created,
+        Err(err) => Decision::Failed(
+            err.downcast_ref::<std::io::Error>()
+                .and_then(|err| err.raw_os_error())
+                .or_else(|| err.downcast_ref::<nix::Error>().map(|errno| *errno as _)),
+        ),
+    };
+    audit::mknod(msg, syscall, mode, dev, decision);
+}
+
+/// Regular files, FIFOs and sockets can be created without privileges, so if the kernel supports
+/// it we let the process do that itself, saving us the fork and all of the namespace setup.
+///
+/// This only depends on the mode, which is passed in a register and cannot change afterwards.
+/// With `--min-umask` the helper is needed to apply it, see [`privileged_caller`].
+fn needs_no_privileges(device: &Device) -> bool {
+    matches!(
+        device.kind,
+        DevKind::Regular | DevKind::Fifo | DevKind::Socket
+    ) && seccomp::flag_continue_supported()
+        && process::min_umask() == 0
+}
+
+/// Device nodes (of allowed devices) only need to be created by us if the process cannot do so
+/// itself, which is the case for every container with its own user namespace. A process in *our*
+/// user namespace with `CAP_MKNOD` in its effective set, eg. in a privileged container, can, so
+/// we let it run the syscall itself and save the fork.
+///
+/// This does not depend on the file system: a `nodev` mount only prevents opening device nodes,
+/// not creating them. The kernel also checks the devices cgroup and AppArmor profile the helper
+/// would enter, so only `--min-umask`, which the kernel does not know about, makes a difference.
+/// With one configured we never skip the helper, neither here nor in [`needs_no_privileges`].
+///
+/// The device was already checked against the allow list, from the mode and device number passed
+/// in registers, which cannot change once the notification was sent. The capabilities and
+/// namespace are read later and may be outdated by the time the kernel runs the syscall, but
+/// letting it continue never grants the process anything: the kernel checks its permissions
+/// again, so the worst case is the syscall failing with `EPERM` where the helper might have
+/// succeeded. For the thread blocked in the syscall this cannot happen anyway, since it could
+/// only change its own credentials and namespaces itself.
+fn privileged_caller(msg: &ProxyMessageBuffer) -> io::Result<bool> {
+    if !seccomp::flag_continue_supported() || process::min_umask() != 0 {
+        return Ok(false);
+    }
+    let pid_fd = msg.pid_fd();
+    if !pid_fd.get_caps()?.has_effective(CAP_MKNOD) {
+        return Ok(false);
+    }
+    let own = PidFd::current()?.user_namespace()?;
+    pid_fd.user_namespace()?.is_same(&own)
+}
+
 async fn do_mknodat(
-    pidfd: &PidFd,
+    msg: &ProxyMessageBuffer,
     dirfd: OwnedFd,
     pathname: CString,
     mode: stat::mode_t,
     dev: stat::dev_t,
 ) -> Result<SyscallStatus, Error> {
-    let caps = pidfd.user_caps()?;
+    let caps = msg.user_caps()?;
 
     Ok(forking_syscall(move || {
         caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
         let out =
             sc_libc_try!(unsafe { libc::mknodat(dirfd.as_raw_fd(), pathname.as_ptr(), mode, dev) });
         Ok(SyscallStatus::Ok(out.into()))
     })
     .await?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn char_rule(major: u64, minor: Option<u64>) -> DeviceRule {
+        DeviceRule {
+            kind: DevKind::Char,
+            major,
+            minor,
+        }
+    }
+
+    #[test]
+    fn unprivileged_nodes_allowed() {
+        for mode in [libc::S_IFIFO, libc::S_IFSOCK, libc::S_IFREG] {
+            let device = check_mknod_dev(mode | 0o644, 0).unwrap();
+            assert_eq!((device.major, device.minor), (0, 0));
+        }
+        assert_eq!(
+            check_mknod_dev(libc::S_IFIFO | 0o644, 0).unwrap().kind,
+            DevKind::Fifo
+        );
+        assert_eq!(
+            check_mknod_dev(libc::S_IFSOCK | 0o644, 0).unwrap().kind,
+            DevKind::Socket
+        );
+    }
+
+    #[test]
+    fn deny_reasons() {
+        // the overlayfs whiteout
+        let device = check_mknod_dev(libc::S_IFCHR, 0).unwrap();
+        assert_eq!(device.kind, DevKind::Char);
+
+        let cases = [
+            (
+                libc::S_IFBLK | 0o600,
+                libc::makedev(8, 0),
+                DenyReason::BlockDevice,
+            ),
+            (libc::S_IFBLK | 0o600, 0, DenyReason::BlockDevice),
+            (
+                libc::S_IFREG | 0o644,
+                libc::makedev(1, 3),
+                DenyReason::UnexpectedDevice,
+            ),
+            (
+                libc::S_IFIFO | 0o644,
+                libc::makedev(0, 1),
+                DenyReason::UnexpectedDevice,
+            ),
+            (
+                libc::S_IFCHR | 0o600,
+                libc::makedev(10, 200),
+                DenyReason::CharDeviceNotAllowed,
+            ),
+            (libc::S_IFDIR | 0o755, 0, DenyReason::BadMode),
+        ];
+        for (mode, dev, reason) in cases {
+            assert_eq!(
+                check_mknod_dev(mode, dev).unwrap_err(),
+                reason,
+                "{mode:o} {dev:x}"
+            );
+        }
+    }
+
+    #[test]
+    fn device_rules() {
+        assert_eq!(parse_device_rule("1:3").unwrap(), char_rule(1, Some(3)));
+        assert_eq!(parse_device_rule("136:*").unwrap(), char_rule(136, None));
+        // the largest numbers `mknod()` can encode
+        assert_eq!(
+            parse_device_rule("4095:1048575").unwrap(),
+            char_rule(0xfff, Some(0xfffff))
+        );
+
+        for line in [
+            "4096:0",
+            "1:1048576",
+            "-1:0",
+            "1:-1",
+            "*:1",
+            "1",
+            "1:",
+            ":1",
+            "a:b",
+        ] {
+            assert!(parse_device_rule(line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn device_list() {
+        let rules = parse_device_list("# comment\n\n  1:3  \n\t\n  # indented comment\n10:*\n");
+        assert_eq!(rules.unwrap(), [char_rule(1, Some(3)), char_rule(10, None)]);
+
+        let err = parse_device_list("1:3\n\n1:x\n").unwrap_err();
+        assert!(err.to_string().starts_with("line 3:"), "{err}");
+    }
+
+    #[test]
+    fn device_list_reload_keeps_list_on_error() {
+        let path = std::env::temp_dir().join(format!(
+            "pve-lxc-syscalld-test-device-list-{}",
+            std::process::id()
+        ));
+        // keep the built-in character devices, other tests rely on them
+        let mut data = String::from("13:*\n");
+        for rule in ALLOWED_DEVICES
+            .iter()
+            .filter(|rule| rule.kind == DevKind::Char)
+        {
+            data.push_str(&format!("{}:{}\n", rule.major, rule.minor.unwrap()));
+        }
+        std::fs::write(&path, &data).unwrap();
+
+        let count = load_device_list(path.as_os_str()).unwrap();
+        let list = DEVICE_LIST.read().unwrap().clone().unwrap();
+        assert_eq!(list.len(), ALLOWED_DEVICES.len() + 1);
+        assert_eq!(count, list.len() - 3); // regular file, FIFO and socket
+        assert!(check_mknod_dev(libc::S_IFCHR, libc::makedev(13, 64)).is_ok());
+
+        std::fs::write(&path, "13:*\nbroken\n").unwrap();
+        reload_device_list();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(DEVICE_LIST.read().unwrap().as_ref(), Some(&list));
+    }
+
+    /// A `mknod()` request of the current process for `path`, which is placed in fake memory.
+    fn mknod_message(path: &std::path::Path, mode: libc::mode_t) -> ProxyMessageBuffer {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::FileExt;
+        use std::os::unix::io::FromRawFd;
+
+        const PATH: u64 = 0x1000;
+
+        let fd = unsafe { libc::memfd_create(b"mem\0".as_ptr() as _, libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let mem = unsafe { std::fs::File::from_raw_fd(fd) };
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        mem.write_all_at(path.as_bytes_with_nul(), PATH).unwrap();
+
+        let mut request: seccomp::SeccompNotif = unsafe { std::mem::zeroed() };
+        request.pid = unsafe { libc::getpid() } as u32;
+        request.data.arch = sandbox::AUDIT_ARCH_X86_64;
+        request.data.nr = libc::SYS_mknod as _;
+        request.data.args[0] = PATH;
+        request.data.args[1] = u64::from(mode);
+        ProxyMessageBuffer::from_parts(request, mem, PidFd::current().unwrap())
+    }
+
+    #[test]
+    fn fifo_with_min_umask() {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let path =
+            std::env::temp_dir().join(format!("pve-lxc-syscalld-test-fifo-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // This is global, but only makes other tests' nodes more restrictive.
+        process::set_min_umask(0o077);
+        let result = runtime.block_on(mknod(&mknod_message(&path, libc::S_IFIFO | 0o666)));
+        process::set_min_umask(0);
+
+        // created by the helper rather than left to the kernel, so the minimum umask applies
+        assert!(matches!(result, Ok(SyscallStatus::Ok(0))));
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(metadata.file_type().is_fifo());
+        assert_eq!(metadata.permissions().mode() & 0o077, 0);
+    }
+
+    /// A `renameat2()` request of the current process, the paths are placed in fake memory.
+    fn rename_message(old: &std::path::Path, new: &std::path::Path) -> ProxyMessageBuffer {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::FileExt;
+        use std::os::unix::io::FromRawFd;
+
+        const OLD: u64 = 0x1000;
+        const NEW: u64 = 0x2000;
+
+        let fd = unsafe { libc::memfd_create(b"mem\0".as_ptr() as _, libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let mem = unsafe { std::fs::File::from_raw_fd(fd) };
+        for (path, offset) in [(old, OLD), (new, NEW)] {
+            let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+            mem.write_all_at(path.as_bytes_with_nul(), offset).unwrap();
+        }
+
+        let mut request: seccomp::SeccompNotif = unsafe { std::mem::zeroed() };
+        request.pid = unsafe { libc::getpid() } as u32;
+        request.data.arch = sandbox::AUDIT_ARCH_X86_64;
+        request.data.nr = libc::SYS_renameat2 as _;
+        request.data.args = [libc::AT_FDCWD as u64, OLD, libc::AT_FDCWD as u64, NEW, 0, 0];
+        ProxyMessageBuffer::from_parts(request, mem, PidFd::current().unwrap())
+    }
+
+    #[test]
+    fn helpers_in_sandbox() {
+        use std::os::unix::fs::FileTypeExt;
+
+        let dir = std::env::temp_dir().join(format!(
+            "pve-lxc-syscalld-test-sandbox-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // This is global, but every handler's helper must work with it anyway.
+        sandbox::enable();
+        let (killed, created, renamed) = runtime.block_on(async {
+            // make sure the filter is actually installed
+            let killed = forking_syscall(|| {
+                sandbox::apply(SANDBOX)?;
+                Ok(SyscallStatus::Ok(unsafe { libc::getppid() }.into()))
+            })
+            .await;
+
+            // calling the helper directly, privileged callers create the node themselves
+            let dirfd = OwnedFd::from(std::fs::File::open(&dir).unwrap());
+            let msg = mknod_message(&dir.join("fifo"), libc::S_IFIFO | 0o600);
+            let created = do_mknodat(
+                &msg,
+                dirfd,
+                CString::new("fifo").unwrap(),
+                libc::S_IFIFO | 0o600,
+                0,
+            )
+            .await;
+
+            let msg = rename_message(&dir.join("fifo"), &dir.join("renamed"));
+            let renamed = crate::sys_rename::renameat2(&msg).await;
+            (killed, created, renamed)
+        });
+        sandbox::disable();
+
+        let fifo = std::fs::symlink_metadata(dir.join("renamed"));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(!matches!(killed, Ok(SyscallStatus::Ok(_))));
+        assert!(matches!(created, Ok(SyscallStatus::Ok(0))));
+        assert!(matches!(renamed, Ok(SyscallStatus::Ok(0))));
+        assert!(fifo.unwrap().file_type().is_fifo());
+    }
+}
@@ -0,0 +1,248 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+
+use anyhow::Error;
+use nix::errno::Errno;
+
+use crate::capability::{self, CAP_SYS_ADMIN};
+use crate::fork::{error_context, forking_syscall};
+use crate::lxcseccomp::ProxyMessageBuffer;
+use crate::process::PidFd;
+use crate::sandbox;
+use crate::sc_libc_try;
+use crate::syscall::SyscallStatus;
+
+/// Syscalls the helper may use with `--sandbox-helpers`, see the `sandbox` module.
+const SANDBOX: &[libc::c_long] = &[
+    libc::SYS_openat,
+    libc::SYS_newfstatat,
+    libc::SYS_readlinkat,
+    libc::SYS_fchdir,
+    libc::SYS_umount2,
+];
+
+/*
+ * int umount2(const char *target, int flags);
+ *
+ * Security notes:
+ *
+ * This is only handled if enabled via `--enable-syscall umount2`.
+ *
+ * `target` is resolved in the helper after entering the process' mount namespace, root and
+ * working directory, so absolute paths and `..` components cannot leave the container's root
+ * directory. The only way out is a working directory *outside* of the root (which a process can
+ * have after a `chroot()` without `chdir()`), so we refuse to act if that is the case. Since the
+ * working directory is pinned by the helper, this check cannot race with the container.
+ *
+ * The capabilities are applied in *our* user namespace, so we only act if the process' mount
+ * namespace is owned by its own user namespace, where the kernel would check its capabilities
+ * for `umount2()` itself. Mounts "locked" into the mount namespace (the ones it inherited from a
+ * more privileged one when it was created, rather than created inside of it) are refused by the
+ * kernel for any caller, and `MNT_DETACH` of a mount keeps such mounts beneath it attached.
+ *
+ * We also refuse to unmount the container's root directory itself, and mounts the container does
+ * not own, ie. whose root directory's owner or group is not mapped into the container. This
+ * excludes eg. `/proc`, `/sys` and the file systems lxc mounts over parts of them, as well as
+ * bind mounts of host directories.
+ *
+ * The target is opened once (`O_PATH`, with `O_NOFOLLOW` for `UMOUNT_NOFOLLOW`) and checked via
+ * that descriptor. Since an open descriptor keeps the mount busy, the unmount itself goes by the
+ * last component of its path beneath the pinned parent directory, which must still be the
+ * checked one. The mount point cannot be renamed in between while it is one in the container's
+ * namespace, so at most the container can unmount and replace the mount in between, which needs
+ * the very privileges it would need to unmount the replacement itself.
+ *
+ * `MNT_FORCE` aborts pending requests on network file systems and `MNT_EXPIRE` is meant for
+ * automounter style expiry, neither of which a container should need from us. `MNT_DETACH` is
+ * allowed.
+ */
+pub async fn umount2(msg: &ProxyMessageBuffer) -> Result<SyscallStatus, Error> {
+    let flags = msg.arg_int(1)?;
+    if flags & !(libc::MNT_DETACH | libc::UMOUNT_NOFOLLOW) != 0 {
+        return Ok(Errno::EPERM.into());
+    }
+
+    let target = msg.arg_c_string(0)?;
+
+    let pid_fd = msg.pid_fd();
+    if !pid_fd
+        .mount_namespace()?
+        .owner()?
+        .is_same(&*pid_fd.user_namespace()?)?
+    {
+        debug!(
+            pid = pid_fd.get_pid(), syscall = "umount2";
+            "denied umount2() for pid {}: its mount namespace is not owned by its user namespace",
+            pid_fd.get_pid(),
+        );
+        return Ok(Errno::EPERM.into());
+    }
+
+    let uid_map = pid_fd.get_uid_map()?;
+    let gid_map = pid_fd.get_gid_map()?;
+    let caps = msg.user_caps()?;
+    if !caps.capabilities().has_effective(CAP_SYS_ADMIN) {
+        debug!(
+            pid = pid_fd.get_pid(), syscall = "umount2";
+            "denied umount2() for pid {}: it lacks {} in its effective set",
+            pid_fd.get_pid(),
+            capability::name(CAP_SYS_ADMIN),
+        );
+        return Ok(Errno::EPERM.into());
+    }
+
+    Ok(forking_syscall(move || {
+        // our own fds, before entering the process' mount namespace and root
+        let own_fds = open_path(None, c_str!("/proc/self/fd"))?;
+
+        caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
+
+        if !cwd_beneath_root()? {
+            return Err(error_context(
+                io::Error::from_raw_os_error(libc::EPERM),
+                "refusing umount2() with a working directory outside of the root directory",
+            ));
+        }
+
+        let mut open_flags = libc::O_PATH | libc::O_CLOEXEC;
+        if flags & libc::UMOUNT_NOFOLLOW != 0 {
+            open_flags |= libc::O_NOFOLLOW;
+        }
+        let fd = sc_libc_try!(unsafe { libc::openat(libc::AT_FDCWD, target.as_ptr(), open_flags) });
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let target_stat = fd_stat(fd.as_fd())?;
+        if same_file(&target_stat, &path_stat(c_str!("/"))?) {
+            return Ok(Errno::EPERM.into());
+        }
+        if uid_map.map_into(u64::from(target_stat.st_uid)).is_none()
+            || gid_map.map_into(u64::from(target_stat.st_gid)).is_none()
+        {
+            return Ok(Errno::EPERM.into());
+        }
+
+        let path = fd_path(own_fds.as_fd(), fd.as_fd())?;
+        let (parent, name) = match split_path(&path) {
+            Some(parts) => parts,
+            None => return Ok(Errno::EPERM.into()),
+        };
+        let parent = open_path(None, &parent)?;
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        sc_libc_try!(unsafe {
+            libc::fstatat(
+                parent.as_raw_fd(),
+                name.as_ptr(),
+                &mut stat,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        });
+        if !same_file(&stat, &target_stat) {
+            // it was moved or replaced concurrently
+            return Ok(Errno::EBUSY.into());
+        }
+        drop(fd);
+
+        sc_libc_try!(unsafe { libc::fchdir(parent.as_raw_fd()) });
+        let out =
+            sc_libc_try!(unsafe { libc::umount2(name.as_ptr(), flags | libc::UMOUNT_NOFOLLOW) });
+        Ok(SyscallStatus::Ok(out.into()))
+    })
+    .await?)
+}
+
+/// The path of the file `fd` refers to, via our `/proc/self/fd` directory `own_fds`.
+fn fd_path(own_fds: BorrowedFd<'_>, fd: BorrowedFd<'_>) -> io::Result<Vec<u8>> {
+    let name = CString::new(fd.as_raw_fd().to_string()).unwrap();
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let len = c_try!(unsafe {
+        libc::readlinkat(
+            own_fds.as_raw_fd(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+        )
+    });
+    buf.truncate(len as usize);
+    Ok(buf)
+}
+
+/// Split an absolute path as returned by [`fd_path`] into its parent directory and last
+/// component, `None` for anything else (eg. an unreachable file) and `/`.
+fn split_path(path: &[u8]) -> Option<(CString, CString)> {
+    if path.first() != Some(&b'/') {
+        return None;
+    }
+    let pos = path.iter().rposition(|&b| b == b'/')?;
+    let parent = if pos == 0 { &b"/"[..] } else { &path[..pos] };
+    let name = &path[(pos + 1)..];
+    if name.is_empty() {
+        return None;
+    }
+    Some((CString::new(parent).ok()?, CString::new(name).ok()?))
+}
+
+fn same_file(a: &libc::stat, b: &libc::stat) -> bool {
+    a.st_dev == b.st_dev && a.st_ino == b.st_ino
+}
+
+/// `stat()` via `fstatat()`, so the sandbox only needs to allow the latter.
+fn path_stat(path: &CStr) -> io::Result<libc::stat> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    c_try!(unsafe { libc::fstatat(libc::AT_FDCWD, path.as_ptr(), &mut stat, 0) });
+    Ok(stat)
+}
+
+/// `fstat()` via `fstatat()`, which also works for `O_PATH` descriptors.
+fn fd_stat(fd: BorrowedFd<'_>) -> io::Result<libc::stat> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    c_try!(unsafe {
+        libc::fstatat(
+            fd.as_raw_fd(),
+            c_str!("").as_ptr(),
+            &mut stat,
+            libc::AT_EMPTY_PATH,
+        )
+    });
+    Ok(stat)
+}
+
+/// Check whether the current working directory lies beneath the root directory by walking up
+/// the `..` entries.
+///
+/// Path lookups stop at the root directory, so if the working directory is outside of it we end
+/// up at the top of the file system tree instead, where `..` refers to itself.
+fn cwd_beneath_root() -> io::Result<bool> {
+    let root = path_stat(c_str!("/"))?;
+
+    let mut dir = open_path(None, c_str!("."))?;
+    let mut dir_stat = fd_stat(dir.as_fd())?;
+    loop {
+        if same_file(&dir_stat, &root) {
+            return Ok(true);
+        }
+
+        let parent = open_path(Some(dir.as_fd()), c_str!(".."))?;
+        let parent_stat = fd_stat(parent.as_fd())?;
+        if same_file(&parent_stat, &dir_stat) {
+            return Ok(false);
+        }
+
+        dir = parent;
+        dir_stat = parent_stat;
+    }
+}
+
+/// Open a directory as `O_PATH` descriptor, relative to `dir` or the working directory.
+fn open_path(dir: Option<BorrowedFd<'_>>, path: &CStr) -> io::Result<OwnedFd> {
+    let fd = c_try!(unsafe {
+        libc::openat(
+            dir.map(|dir| dir.as_raw_fd()).unwrap_or(libc::AT_FDCWD),
+            path.as_ptr(),
+            libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    });
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
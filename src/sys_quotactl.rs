@@ -9,8 +9,9 @@ use nix::errno::Errno;
 use crate::fork::forking_syscall;
 use crate::lxcseccomp::ProxyMessageBuffer;
 use crate::process::{IdMap, PidFd};
+use crate::sandbox;
 use crate::sc_libc_try;
-use crate::syscall::SyscallStatus;
+use crate::syscall::{CallerArch, SyscallStatus};
 
 /*
  * int quotactl(int cmd, const char *special, int id, caddr_t addr);
@@ -19,7 +20,7 @@ use crate::syscall::SyscallStatus;
  * `addr` will point to a datastructure, so we should read a reasonable amount (a 4k page?) of
  *  memory from there, but only if the sub command type makes use of it.
  *
- *  Actually the libc crate contains most of the structures anyway!
+ *  The structures are defined below as the kernel does, see the notes about i386 there.
  *
  * Cmd:
  *  QCMD(SubCmd, Type)
@@ -39,6 +40,25 @@ use crate::syscall::SyscallStatus;
  *    X       Q_SETINFO       struct dqinfo {}
  *    X          Q_SYNC                ignored       -EOPNOTSUPP if `special` is NULL!
  *
+ * The `id` argument is a user or group id (of the process' user namespace) only for the per-id
+ * commands Q_GETQUOTA, Q_SETQUOTA and Q_GETNEXTQUOTA, so only those map it via `uid_gid_arg()`.
+ * For Q_QUOTAON it is the quota format, and the other commands ignore it, so it is passed on
+ * unchanged.
+ *
+ * All sub commands, including the read-only ones, are run in a forked helper. A thread entering
+ * the process' environment and leaving it again was considered to save the fork for monitoring
+ * tools polling quotas, but:
+ *  - glibc applies `setgroups()`, `setegid()` and `seteuid()` to *all* threads, so the daemon's
+ *    other threads would briefly run with the container's credentials (we'd need raw syscalls
+ *    for every one of them, including in code we don't own),
+ *  - cgroup membership of regular cgroups and the AppArmor label we set via `/proc/<pid>/attr`
+ *    belong to the process (or its main thread), not to a helper thread,
+ *  - a thread blocked in `quotactl()` (eg. on a hung network file system at `special`, which the
+ *    container controls) cannot be killed, while a helper process can,
+ *  - the saving is small: a bare fork + exit + wait costs about 100µs compared to about 20µs for
+ *    spawning and joining a thread (measured on a 16MiB process), whereas reading the process'
+ *    /proc files and entering its namespaces is needed either way.
+ *
  * xfs stuff:
  *           Q_XQUOTAON           unsigned int
  *          Q_XQUOTAOFF           unsigned int
@@ -48,18 +68,144 @@ use crate::syscall::SyscallStatus;
 
 const Q_GETNEXTQUOTA: c_int = 0x80_0009;
 
+/// Syscalls the helper may use with `--sandbox-helpers`, see the `sandbox` module. `pwrite64` is
+/// used to write results to the process' memory.
+const SANDBOX: &[libc::c_long] = &[libc::SYS_quotactl, libc::SYS_pwrite64];
+
 const KINDMASK: c_int = 0xff;
 const SUBCMDSHIFT: c_int = 8;
 
+/*
+ * The kernel's quota structures.
+ *
+ * `struct if_dqblk` ends with a `__u32` after 64 bit fields, so on x86_64 (and x32) it has 4 bytes
+ * of trailing padding, whereas on i386, where `__u64` is only 4 byte aligned, it does not. The
+ * kernel converts between the two for i386 processes, so we need to do the same, which also moves
+ * `dqb_id` in `struct if_nextdqblk`. We always use the 64 bit layout for our own `quotactl()`.
+ *
+ * `struct if_dqinfo` consists of two `__u64` followed by two `__u32` and has the same layout on
+ * all of them: the `__u32` fields fill the last 8 bytes, so there is no padding regardless of the
+ * alignment of `__u64`, and both the native and the i386 layout are 24 bytes. The same holds for
+ * 32 bit arm (where `__u64` is 8 byte aligned, so `struct if_dqblk` matches x86_64 there), but
+ * arm callers get `ENOSYS` before reaching the handlers (see `CallerArch`), so any support for
+ * them needs the syscall numbers first.
+ *
+ * These layouts do not depend on the kernel version or the quota format in use: they are the
+ * generic interface used since Linux 2.4.22, and the kernel converts from its internal
+ * representation. The older interfaces with different structures used other command numbers
+ * (eg. `Q_GETSTATS` and the v1/v2 specific commands), which we reject with `EOPNOTSUPP` above
+ * without touching `addr`. So there is no layout to probe at runtime, and the sizes are checked at
+ * build time below.
+ */
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy)]
 #[repr(C)]
-struct nextdqblk {
-    dqblk: libc::dqblk,
+struct if_dqblk {
+    dqb_bhardlimit: u64,
+    dqb_bsoftlimit: u64,
+    dqb_curspace: u64,
+    dqb_ihardlimit: u64,
+    dqb_isoftlimit: u64,
+    dqb_curinodes: u64,
+    dqb_btime: u64,
+    dqb_itime: u64,
+    dqb_valid: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct if_nextdqblk {
+    dqblk: if_dqblk,
+    dqb_id: u32,
+}
+
+/// `struct if_dqblk` as seen by i386 processes.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy)]
+#[repr(C, packed(4))]
+struct compat_if_dqblk {
+    dqb_bhardlimit: u64,
+    dqb_bsoftlimit: u64,
+    dqb_curspace: u64,
+    dqb_ihardlimit: u64,
+    dqb_isoftlimit: u64,
+    dqb_curinodes: u64,
+    dqb_btime: u64,
+    dqb_itime: u64,
+    dqb_valid: u32,
+}
+
+/// `struct if_nextdqblk` as seen by i386 processes.
+#[allow(non_camel_case_types)]
+#[repr(C, packed(4))]
+struct compat_if_nextdqblk {
+    dqblk: compat_if_dqblk,
     dqb_id: u32,
 }
 
-pub async fn quotactl(msg: &ProxyMessageBuffer) -> Result<SyscallStatus, Error> {
+const _: () = {
+    assert!(mem::size_of::<if_dqblk>() == 72);
+    assert!(mem::size_of::<if_nextdqblk>() == 80);
+    assert!(mem::size_of::<compat_if_dqblk>() == 68);
+    assert!(mem::size_of::<compat_if_nextdqblk>() == 72);
+    assert!(mem::size_of::<dqinfo>() == 24);
+    assert!(mem::size_of::<compat_dqinfo>() == 24);
+};
+
+/// `struct if_dqinfo` as seen by i386 processes, only used to check it matches `dqinfo`.
+#[allow(dead_code, non_camel_case_types)]
+#[repr(C, packed(4))]
+struct compat_dqinfo {
+    dqi_bgrace: u64,
+    dqi_igrace: u64,
+    dqi_flags: u32,
+    dqi_valid: u32,
+}
+
+impl From<&if_dqblk> for compat_if_dqblk {
+    fn from(data: &if_dqblk) -> Self {
+        Self {
+            dqb_bhardlimit: data.dqb_bhardlimit,
+            dqb_bsoftlimit: data.dqb_bsoftlimit,
+            dqb_curspace: data.dqb_curspace,
+            dqb_ihardlimit: data.dqb_ihardlimit,
+            dqb_isoftlimit: data.dqb_isoftlimit,
+            dqb_curinodes: data.dqb_curinodes,
+            dqb_btime: data.dqb_btime,
+            dqb_itime: data.dqb_itime,
+            dqb_valid: data.dqb_valid,
+        }
+    }
+}
+
+impl From<&if_nextdqblk> for compat_if_nextdqblk {
+    fn from(data: &if_nextdqblk) -> Self {
+        Self {
+            dqblk: compat_if_dqblk::from(&data.dqblk),
+            dqb_id: data.dqb_id,
+        }
+    }
+}
+
+impl From<compat_if_dqblk> for if_dqblk {
+    fn from(data: compat_if_dqblk) -> Self {
+        Self {
+            dqb_bhardlimit: data.dqb_bhardlimit,
+            dqb_bsoftlimit: data.dqb_bsoftlimit,
+            dqb_curspace: data.dqb_curspace,
+            dqb_ihardlimit: data.dqb_ihardlimit,
+            dqb_isoftlimit: data.dqb_isoftlimit,
+            dqb_curinodes: data.dqb_curinodes,
+            dqb_btime: data.dqb_btime,
+            dqb_itime: data.dqb_itime,
+            dqb_valid: data.dqb_valid,
+        }
+    }
+}
+
+pub async fn quotactl(msg: &ProxyMessageBuffer, arch: CallerArch) -> Result<SyscallStatus, Error> {
     let cmd = msg.arg_int(0)?;
-    let special = msg.arg_opt_c_string(1)?;
     // let _id = msg.arg_int(2)?;
     // let _addr = msg.arg_caddr_t(3)?;
 
@@ -72,20 +218,36 @@ pub async fn quotactl(msg: &ProxyMessageBuffer) -> Result<SyscallStatus, Error>
 
     let kind = cmd & KINDMASK;
     let subcmd = ((cmd as c_uint) >> SUBCMDSHIFT) as c_int;
+
+    // reject unsupported commands before reading anything from the process' memory
+    if !matches!(
+        subcmd,
+        libc::Q_GETINFO
+            | libc::Q_SETINFO
+            | libc::Q_GETFMT
+            | libc::Q_QUOTAON
+            | libc::Q_QUOTAOFF
+            | libc::Q_GETQUOTA
+            | libc::Q_SETQUOTA
+            | libc::Q_SYNC
+            | Q_GETNEXTQUOTA
+    ) {
+        //eprintln!("Unhandled quota subcommand: {:x}", subcmd);
+        return Ok(Errno::EOPNOTSUPP.into());
+    }
+
+    let special = msg.arg_opt_c_string(1)?;
     match subcmd {
         libc::Q_GETINFO => q_getinfo(msg, cmd, special).await,
         libc::Q_SETINFO => q_setinfo(msg, cmd, special).await,
         libc::Q_GETFMT => q_getfmt(msg, cmd, special).await,
         libc::Q_QUOTAON => q_quotaon(msg, cmd, special).await,
         libc::Q_QUOTAOFF => q_quotaoff(msg, cmd, special).await,
-        libc::Q_GETQUOTA => q_getquota(msg, cmd, special, kind).await,
-        libc::Q_SETQUOTA => q_setquota(msg, cmd, special, kind).await,
+        libc::Q_GETQUOTA => q_getquota(msg, arch, cmd, special, kind).await,
+        libc::Q_SETQUOTA => q_setquota(msg, arch, cmd, special, kind).await,
         libc::Q_SYNC => q_sync(msg, cmd, special).await,
-        Q_GETNEXTQUOTA => q_getnextquota(msg, cmd, special, kind).await,
-        _ => {
-            //eprintln!("Unhandled quota subcommand: {:x}", subcmd);
-            Ok(Errno::EOPNOTSUPP.into())
-        }
+        Q_GETNEXTQUOTA => q_getnextquota(msg, arch, cmd, special, kind).await,
+        _ => Ok(Errno::EINVAL.into()),
     }
 }
 
@@ -106,9 +268,10 @@ pub async fn q_getinfo(
     let id = msg.arg_int(2)?;
     let addr = msg.arg_caddr_t(3)? as u64;
 
-    let caps = msg.pid_fd().user_caps()?;
+    let caps = msg.user_caps()?;
     Ok(forking_syscall(move || {
         caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
 
         let mut data: dqinfo = unsafe { mem::zeroed() };
         let special = special.as_ref().map(|c| c.as_ptr()).unwrap_or(ptr::null());
@@ -134,9 +297,10 @@ pub async fn q_setinfo(
     let id = msg.arg_int(2)?;
     let mut data: dqinfo = msg.arg_struct_by_ptr(3)?;
 
-    let caps = msg.pid_fd().user_caps()?;
+    let caps = msg.user_caps()?;
     Ok(forking_syscall(move || {
         caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
 
         sc_libc_try!(unsafe {
             libc::quotactl(
@@ -160,9 +324,10 @@ pub async fn q_getfmt(
     let id = msg.arg_int(2)?;
     let addr = msg.arg_caddr_t(3)? as u64;
 
-    let caps = msg.pid_fd().user_caps()?;
+    let caps = msg.user_caps()?;
     Ok(forking_syscall(move || {
         caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
 
         let mut data: u32 = 0;
         let special = special.as_ref().map(|c| c.as_ptr()).unwrap_or(ptr::null());
@@ -182,9 +347,10 @@ pub async fn q_quotaon(
     let id = msg.arg_int(2)?;
     let addr = msg.arg_c_string(3)?;
 
-    let caps = msg.pid_fd().user_caps()?;
+    let caps = msg.user_caps()?;
     Ok(forking_syscall(move || {
         caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
 
         let special = special.as_ref().map(|c| c.as_ptr()).unwrap_or(ptr::null());
         let out = sc_libc_try!(unsafe { libc::quotactl(cmd, special, id, addr.as_ptr() as _) });
@@ -201,9 +367,10 @@ pub async fn q_quotaoff(
 ) -> Result<SyscallStatus, Error> {
     let id = msg.arg_int(2)?;
 
-    let caps = msg.pid_fd().user_caps()?;
+    let caps = msg.user_caps()?;
     Ok(forking_syscall(move || {
         caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
 
         let special = special.as_ref().map(|c| c.as_ptr()).unwrap_or(ptr::null());
         let out = sc_libc_try!(unsafe { libc::quotactl(cmd, special, id, ptr::null_mut()) });
@@ -213,6 +380,10 @@ pub async fn q_quotaoff(
     .await?)
 }
 
+/// Map a user or group id argument of the per-id commands to our user namespace, fails with
+/// `ERANGE` if it is not mapped. Project ids (and other kinds) are passed on unchanged.
+///
+/// Also returns the map, to map ids returned by the kernel back (see `q_getnextquota()`).
 fn uid_gid_arg(
     msg: &ProxyMessageBuffer,
     arg: u32,
@@ -235,6 +406,7 @@ fn uid_gid_arg(
 
 pub async fn q_getquota(
     msg: &ProxyMessageBuffer,
+    arch: CallerArch,
     cmd: c_int,
     special: Option<CString>,
     kind: c_int,
@@ -246,22 +418,28 @@ pub async fn q_getquota(
 
     let (id, _) = uid_gid_arg(msg, 2, kind)?;
     let addr = msg.arg_caddr_t(3)? as u64;
+    let i386 = arch == CallerArch::I386;
 
-    let caps = msg.pid_fd().user_caps()?;
+    let caps = msg.user_caps()?;
     Ok(forking_syscall(move || {
         caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
 
-        let mut data: libc::dqblk = unsafe { mem::zeroed() };
+        let mut data: if_dqblk = unsafe { mem::zeroed() };
         sc_libc_try!(unsafe {
             libc::quotactl(
                 cmd,
                 special.as_ptr(),
                 id,
-                &mut data as *mut libc::dqblk as *mut i8,
+                &mut data as *mut if_dqblk as *mut i8,
             )
         });
 
-        msg.mem_write_struct(addr, &data)?;
+        if i386 {
+            msg.mem_write_struct(addr, &compat_if_dqblk::from(&data))?;
+        } else {
+            msg.mem_write_struct(addr, &data)?;
+        }
         Ok(SyscallStatus::Ok(0))
     })
     .await?)
@@ -269,6 +447,7 @@ pub async fn q_getquota(
 
 pub async fn q_setquota(
     msg: &ProxyMessageBuffer,
+    arch: CallerArch,
     cmd: c_int,
     special: Option<CString>,
     kind: c_int,
@@ -279,18 +458,23 @@ pub async fn q_setquota(
     };
 
     let (id, _) = uid_gid_arg(msg, 2, kind)?;
-    let mut data: libc::dqblk = msg.arg_struct_by_ptr(3)?;
+    let mut data: if_dqblk = if arch == CallerArch::I386 {
+        msg.arg_struct_by_ptr::<compat_if_dqblk>(3)?.into()
+    } else {
+        msg.arg_struct_by_ptr(3)?
+    };
 
-    let caps = msg.pid_fd().user_caps()?;
+    let caps = msg.user_caps()?;
     Ok(forking_syscall(move || {
         caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
 
         sc_libc_try!(unsafe {
             libc::quotactl(
                 cmd,
                 special.as_ptr(),
                 id,
-                &mut data as *mut libc::dqblk as *mut i8,
+                &mut data as *mut if_dqblk as *mut i8,
             )
         });
 
@@ -301,6 +485,7 @@ pub async fn q_setquota(
 
 pub async fn q_getnextquota(
     msg: &ProxyMessageBuffer,
+    arch: CallerArch,
     cmd: c_int,
     special: Option<CString>,
     kind: c_int,
@@ -312,18 +497,20 @@ pub async fn q_getnextquota(
 
     let (id, idmap) = uid_gid_arg(msg, 2, kind)?;
     let addr = msg.arg_caddr_t(3)? as u64;
+    let i386 = arch == CallerArch::I386;
 
-    let caps = msg.pid_fd().user_caps()?;
+    let caps = msg.user_caps()?;
     Ok(forking_syscall(move || {
         caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
 
-        let mut data: nextdqblk = unsafe { mem::zeroed() };
+        let mut data: if_nextdqblk = unsafe { mem::zeroed() };
         sc_libc_try!(unsafe {
             libc::quotactl(
                 cmd,
                 special.as_ptr(),
                 id,
-                &mut data as *mut nextdqblk as *mut i8,
+                &mut data as *mut if_nextdqblk as *mut i8,
             )
         });
 
@@ -334,7 +521,11 @@ pub async fn q_getnextquota(
                 as u32;
         }
 
-        msg.mem_write_struct(addr, &data)?;
+        if i386 {
+            msg.mem_write_struct(addr, &compat_if_nextdqblk::from(&data))?;
+        } else {
+            msg.mem_write_struct(addr, &data)?;
+        }
         Ok(SyscallStatus::Ok(0))
     })
     .await?)
@@ -350,9 +541,10 @@ pub async fn q_sync(
         None => return Ok(Errno::EINVAL.into()),
     };
 
-    let caps = msg.pid_fd().user_caps()?;
+    let caps = msg.user_caps()?;
     Ok(forking_syscall(move || {
         caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
 
         sc_libc_try!(unsafe { libc::quotactl(cmd, special.as_ptr(), 0, ptr::null_mut()) });
 
@@ -360,3 +552,101 @@ pub async fn q_sync(
     })
     .await?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dqblk() -> if_dqblk {
+        if_dqblk {
+            dqb_bhardlimit: 0x0101_0101_0101_0101,
+            dqb_bsoftlimit: 0x0202_0202_0202_0202,
+            dqb_curspace: 0x0303_0303_0303_0303,
+            dqb_ihardlimit: 0x0404_0404_0404_0404,
+            dqb_isoftlimit: 0x0505_0505_0505_0505,
+            dqb_curinodes: 0x0606_0606_0606_0606,
+            dqb_btime: 0x0707_0707_0707_0707,
+            dqb_itime: 0x0808_0808_0808_0808,
+            dqb_valid: 0x0909_0909,
+        }
+    }
+
+    fn bytes<T>(data: &T) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(data as *const T as *const u8, mem::size_of::<T>()) }
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_ne_bytes(data[offset..(offset + 8)].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_ne_bytes(data[offset..(offset + 4)].try_into().unwrap())
+    }
+
+    #[test]
+    fn compat_dqblk_round_trip() {
+        let native = dqblk();
+        let compat = compat_if_dqblk::from(&native);
+        let data = bytes(&compat);
+        for (i, offset) in (0..64).step_by(8).enumerate() {
+            assert_eq!(u64_at(data, offset), 0x0101_0101_0101_0101 * (i as u64 + 1));
+        }
+        assert_eq!(u32_at(data, 64), 0x0909_0909);
+
+        let back = if_dqblk::from(compat);
+        assert_eq!(bytes(&back)[..68], bytes(&native)[..68]);
+    }
+
+    #[test]
+    fn compat_nextdqblk_layout() {
+        let native = if_nextdqblk {
+            dqblk: dqblk(),
+            dqb_id: 100_000,
+        };
+        // on x86_64 `dqb_id` follows the 4 bytes of padding at the end of `if_dqblk`
+        assert_eq!(u32_at(bytes(&native), 72), 100_000);
+
+        let compat = compat_if_nextdqblk::from(&native);
+        let data = bytes(&compat);
+        assert_eq!(u32_at(data, 64), 0x0909_0909);
+        assert_eq!(u32_at(data, 68), 100_000);
+        assert_eq!(data[..64], bytes(&native)[..64]);
+    }
+
+    /// A `quotactl()` request of the current process with `id` as id argument.
+    fn quotactl_message(id: c_int) -> ProxyMessageBuffer {
+        let mut request: crate::seccomp::SeccompNotif = unsafe { mem::zeroed() };
+        request.pid = unsafe { libc::getpid() } as u32;
+        request.data.nr = libc::SYS_quotactl as c_int;
+        request.data.args[2] = u64::from(id as c_uint);
+        ProxyMessageBuffer::from_parts(
+            request,
+            std::fs::File::open("/dev/null").unwrap(),
+            PidFd::current().unwrap(),
+        )
+    }
+
+    #[test]
+    fn uid_gid_arg_kinds() {
+        let pid_fd = PidFd::current().unwrap();
+        let uid = pid_fd.get_uid_map().unwrap().map_from(0).unwrap() as c_int;
+        let gid = pid_fd.get_gid_map().unwrap().map_from(0).unwrap() as c_int;
+
+        let msg = quotactl_message(0);
+        let (id, map) = uid_gid_arg(&msg, 2, libc::USRQUOTA).unwrap();
+        assert_eq!((id, map.is_some()), (uid, true));
+        let (id, map) = uid_gid_arg(&msg, 2, libc::GRPQUOTA).unwrap();
+        assert_eq!((id, map.is_some()), (gid, true));
+
+        // project ids are not mapped, not even unmappable ones
+        let msg = quotactl_message(-1);
+        let (id, map) = uid_gid_arg(&msg, 2, 2 /* PRJQUOTA */).unwrap();
+        assert_eq!((id, map.is_some()), (-1, false));
+
+        // (uid_t)-1 is never mapped
+        for kind in [libc::USRQUOTA, libc::GRPQUOTA] {
+            let err = uid_gid_arg(&msg, 2, kind).err().unwrap();
+            assert_eq!(err.downcast_ref::<Errno>(), Some(&Errno::ERANGE));
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::ffi::{CStr, CString};
+use std::mem::MaybeUninit;
+use std::os::raw::{c_int, c_uint};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use anyhow::Error;
+use nix::errno::Errno;
+
+use crate::capability::{self, CAP_MKNOD};
+use crate::fork::forking_syscall;
+use crate::lxcseccomp::ProxyMessageBuffer;
+use crate::process::{IdMap, PidFd};
+use crate::sandbox;
+use crate::sc_libc_try;
+use crate::syscall::SyscallStatus;
+
+const RENAME_NOREPLACE: c_uint = 1 << 0;
+const RENAME_EXCHANGE: c_uint = 1 << 1;
+const RENAME_WHITEOUT: c_uint = 1 << 2;
+
+/// Syscalls the helper may use with `--sandbox-helpers`, see the `sandbox` module.
+const SANDBOX: &[libc::c_long] = &[libc::SYS_openat, libc::SYS_newfstatat, libc::SYS_renameat2];
+
+/*
+ * int renameat2(int olddirfd, const char *oldpath, int newdirfd, const char *newpath,
+ *               unsigned int flags);
+ */
+pub async fn renameat2(msg: &ProxyMessageBuffer) -> Result<SyscallStatus, Error> {
+    let flags = msg.arg_uint(4)?;
+    if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE | RENAME_WHITEOUT) != 0 {
+        return Ok(Errno::EINVAL.into());
+    }
+
+    // the kernel rejects these combinations as well, no need to fork for that
+    if flags & RENAME_EXCHANGE != 0 && flags & (RENAME_NOREPLACE | RENAME_WHITEOUT) != 0 {
+        return Ok(Errno::EINVAL.into());
+    }
+
+    // `AT_FDCWD` is resolved to the process' working directory by `arg_fd()`
+    let olddirfd = msg.arg_fd(0, libc::O_DIRECTORY)?;
+    let oldpath = msg.arg_c_string(1)?;
+    let newdirfd = msg.arg_fd(2, libc::O_DIRECTORY)?;
+    let newpath = msg.arg_c_string(3)?;
+
+    let (oldparent, oldname) = match split_path(oldpath.as_bytes()) {
+        Ok(parts) => parts,
+        Err(errno) => return Ok(errno.into()),
+    };
+    let (newparent, newname) = match split_path(newpath.as_bytes()) {
+        Ok(parts) => parts,
+        Err(errno) => return Ok(errno.into()),
+    };
+
+    let uid_map = msg.pid_fd().get_uid_map()?;
+    let gid_map = msg.pid_fd().get_gid_map()?;
+    let caps = msg.user_caps()?;
+
+    // A whiteout is a character device node (0, 0), so require the same capability as `mknod()`.
+    if flags & RENAME_WHITEOUT != 0 && !caps.capabilities().has_effective(CAP_MKNOD) {
+        debug!(
+            pid = msg.pid_fd().get_pid(), syscall = "renameat2";
+            "denied renameat2() with RENAME_WHITEOUT for pid {}: it lacks {} in its effective set",
+            msg.pid_fd().get_pid(),
+            capability::name(CAP_MKNOD),
+        );
+        return Ok(Errno::EPERM.into());
+    }
+
+    Ok(forking_syscall(move || {
+        caps.apply(&PidFd::current()?)?;
+        sandbox::apply(SANDBOX)?;
+
+        // We apply the process' capabilities in *our* user namespace, so `CAP_DAC_OVERRIDE` and
+        // `CAP_FOWNER` would allow moving files the container does not own at all. Only touch
+        // directories and files whose owner is mapped into the container. The rename then goes
+        // through the very parent directories we checked, so they cannot be swapped in between.
+        let oldparent = sc_libc_try!(unsafe {
+            libc::openat(olddirfd.as_raw_fd(), oldparent.as_ptr(), PARENT_FLAGS)
+        });
+        let oldparent = unsafe { OwnedFd::from_raw_fd(oldparent) };
+        let newparent = sc_libc_try!(unsafe {
+            libc::openat(newdirfd.as_raw_fd(), newparent.as_ptr(), PARENT_FLAGS)
+        });
+        let newparent = unsafe { OwnedFd::from_raw_fd(newparent) };
+
+        let empty = CStr::from_bytes_with_nul(b"\0").unwrap();
+        let checks = [
+            (oldparent.as_raw_fd(), empty, libc::AT_EMPTY_PATH),
+            (newparent.as_raw_fd(), empty, libc::AT_EMPTY_PATH),
+            (
+                oldparent.as_raw_fd(),
+                oldname.as_c_str(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            ),
+        ];
+        for (dirfd, name, flags) in checks {
+            if let Err(errno) = check_owner(dirfd, name, flags, &uid_map, &gid_map) {
+                return Ok(errno.into());
+            }
+        }
+        // the target need not exist, unless exchanging, which the kernel checks for us
+        match check_owner(
+            newparent.as_raw_fd(),
+            &newname,
+            libc::AT_SYMLINK_NOFOLLOW,
+            &uid_map,
+            &gid_map,
+        ) {
+            Ok(()) | Err(Errno::ENOENT) => (),
+            Err(errno) => return Ok(errno.into()),
+        }
+
+        let out = sc_libc_try!(unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                oldparent.as_raw_fd(),
+                oldname.as_ptr(),
+                newparent.as_raw_fd(),
+                newname.as_ptr(),
+                flags,
+            )
+        });
+        Ok(SyscallStatus::Ok(out))
+    })
+    .await?)
+}
+
+const PARENT_FLAGS: c_int = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+
+/// Split `path` into its parent directory and its last component.
+///
+/// Trailing slashes stay with the last component, so the kernel still requires it to be a
+/// directory. Paths without a proper last component fail the way `renameat2()` would fail.
+fn split_path(path: &[u8]) -> Result<(CString, CString), Errno> {
+    let trimmed = match path.iter().rposition(|&b| b != b'/') {
+        Some(end) => &path[..=end],
+        None if path.is_empty() => return Err(Errno::ENOENT),
+        None => return Err(Errno::EBUSY),
+    };
+
+    let start = trimmed
+        .iter()
+        .rposition(|&b| b == b'/')
+        .map_or(0, |pos| pos + 1);
+    if matches!(&trimmed[start..], b"." | b"..") {
+        return Err(Errno::EBUSY);
+    }
+
+    let parent = match start {
+        0 => &b"."[..],
+        1 => &b"/"[..],
+        _ => &path[..(start - 1)],
+    };
+    let name = &path[start..];
+
+    // neither part can contain a nul byte, they come from a C string
+    Ok((CString::new(parent).unwrap(), CString::new(name).unwrap()))
+}
+
+/// Fail with `EPERM` unless the owner and group of `name` in `dirfd` are mapped into the
+/// container.
+fn check_owner(
+    dirfd: RawFd,
+    name: &CStr,
+    flags: c_int,
+    uid_map: &IdMap,
+    gid_map: &IdMap,
+) -> Result<(), Errno> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::fstatat(dirfd, name.as_ptr(), stat.as_mut_ptr(), flags) } == -1 {
+        return Err(Errno::last());
+    }
+    let stat = unsafe { stat.assume_init() };
+    if uid_map.map_into(u64::from(stat.st_uid)).is_none()
+        || gid_map.map_into(u64::from(stat.st_gid)).is_none()
+    {
+        return Err(Errno::EPERM);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(path: &str) -> Result<(String, String), Errno> {
+        split_path(path.as_bytes())
+            .map(|(parent, name)| (parent.into_string().unwrap(), name.into_string().unwrap()))
+    }
+
+    fn parts(parent: &str, name: &str) -> Result<(String, String), Errno> {
+        Ok((parent.to_string(), name.to_string()))
+    }
+
+    #[test]
+    fn split_paths() {
+        assert_eq!(split("file"), parts(".", "file"));
+        assert_eq!(split("dir/file"), parts("dir", "file"));
+        assert_eq!(split("/file"), parts("/", "file"));
+        assert_eq!(split("/a/b/file"), parts("/a/b", "file"));
+        assert_eq!(split("dir/sub/"), parts("dir", "sub/"));
+        assert_eq!(split("dir//sub//"), parts("dir/", "sub//"));
+        assert_eq!(split("//file"), parts("/", "file"));
+        assert_eq!(split("dir/.hidden"), parts("dir", ".hidden"));
+    }
+
+    #[test]
+    fn split_invalid_paths() {
+        assert_eq!(split(""), Err(Errno::ENOENT));
+        assert_eq!(split("/"), Err(Errno::EBUSY));
+        assert_eq!(split("//"), Err(Errno::EBUSY));
+        assert_eq!(split("."), Err(Errno::EBUSY));
+        assert_eq!(split("dir/.."), Err(Errno::EBUSY));
+        assert_eq!(split("dir/./"), Err(Errno::EBUSY));
+    }
+}
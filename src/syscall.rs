@@ -1,5 +1,7 @@
+use std::cell::RefCell;
 use std::ffi::CString;
 use std::os::raw::c_int;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use anyhow::Error;
 use nix::errno::Errno;
@@ -10,9 +12,34 @@ use crate::tools::vec;
 const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
 const AUDIT_ARCH_I386: u32 = 0x4000_0003;
 
+/// x32 syscalls are reported as `AUDIT_ARCH_X86_64` with this bit set in the syscall number.
+const X32_SYSCALL_BIT: c_int = 0x4000_0000;
+
 pub enum SyscallStatus {
+    /// The syscall's return value.
+    ///
+    /// This is passed on as a full 64 bit value, both from forked helpers (see `fork::Data`) and
+    /// in the response to the kernel, so sizes and offsets beyond 32 bits are fine. Note that for
+    /// i386 processes the kernel only has a 32 bit register to return it in though.
     Ok(i64),
+
+    /// The syscall failed with this errno.
+    ///
+    /// This is always the positive value (eg. `libc::EPERM`), as in `errno` and `Errno`, also in
+    /// `fork::Data::error`. It is only negated once, for the kernel, in
+    /// `ProxyMessageBuffer::set_result()`. Anything not positive is a bug and fails with `EPERM`.
     Err(i32),
+
+    /// Let the kernel run the original syscall in the process, as if it had not been intercepted.
+    ///
+    /// **Only return this if the decision does not depend on memory contents.** The syscall runs
+    /// after we responded, and the process (or another thread of it) can change anything its
+    /// pointer arguments point to in between, so eg. a checked path may be a different one by
+    /// then. Register arguments (flags, modes, device numbers) cannot change.
+    ///
+    /// This requires kernel support, see `seccomp::flag_continue_supported()`, and cannot be
+    /// returned from forked helpers.
+    Continue,
 }
 
 impl From<Errno> for SyscallStatus {
@@ -21,69 +48,196 @@ impl From<Errno> for SyscallStatus {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Syscall {
     Mknod,
     MknodAt,
     Quotactl,
+    RenameAt2,
+    FchownAt,
+    Umount2,
+}
+
+/// Bit mask of syscalls disabled at runtime, indexed by the `Syscall` discriminant.
+///
+/// `umount2` needs to be enabled explicitly, see the security notes of `sys_mount::umount2`.
+static DISABLED_SYSCALLS: AtomicU32 = AtomicU32::new(1 << Syscall::Umount2 as u32);
+
+impl Syscall {
+    const ALL: &'static [Syscall] = &[
+        Syscall::Mknod,
+        Syscall::MknodAt,
+        Syscall::Quotactl,
+        Syscall::RenameAt2,
+        Syscall::FchownAt,
+        Syscall::Umount2,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Syscall::Mknod => "mknod",
+            Syscall::MknodAt => "mknodat",
+            Syscall::Quotactl => "quotactl",
+            Syscall::RenameAt2 => "renameat2",
+            Syscall::FchownAt => "fchownat",
+            Syscall::Umount2 => "umount2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|sc| sc.name() == name)
+    }
+
+    /// Stop handling this syscall, requests for it will fail with `ENOSYS` as if the kernel did
+    /// not know about it.
+    pub fn disable(self) {
+        DISABLED_SYSCALLS.fetch_or(1 << self as u32, Ordering::Relaxed);
+    }
+
+    /// Handle this syscall, for those disabled by default.
+    pub fn enable(self) {
+        DISABLED_SYSCALLS.fetch_and(!(1 << self as u32), Ordering::Relaxed);
+    }
+
+    pub fn is_disabled(self) -> bool {
+        DISABLED_SYSCALLS.load(Ordering::Relaxed) & (1 << self as u32) != 0
+    }
+}
+
+/// The ABI a process made a syscall with.
+///
+/// This is derived once per message in the client and passed on to the handlers which need to
+/// interpret arguments or structs differently depending on it, ie. the syscall number and the
+/// layout of structs in the process' memory.
+///
+/// Scalar arguments do not depend on it: the kernel takes file descriptors and device numbers as
+/// 32 bit integers for every ABI and ignores the upper half of the register, see
+/// `ProxyMessageBuffer::arg_fd()` and `arg_dev_t()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallerArch {
+    X86_64,
+    /// Reported as `AUDIT_ARCH_X86_64` with the `X32_SYSCALL_BIT` set in the syscall number. Uses
+    /// the x86_64 struct layouts.
+    X32,
+    /// 64 bit integers in structs are only 4 byte aligned here, which affects the layout of some
+    /// structs (namely their padding). The kernel converts those for such processes.
+    I386,
+}
+
+impl CallerArch {
+    /// Determine the ABI from a notification's `arch` and `nr`, `None` if we don't support it.
+    pub fn from_notification(arch: u32, nr: c_int) -> Option<Self> {
+        match arch {
+            AUDIT_ARCH_X86_64 if nr & X32_SYSCALL_BIT != 0 => Some(CallerArch::X32),
+            AUDIT_ARCH_X86_64 => Some(CallerArch::X86_64),
+            AUDIT_ARCH_I386 => Some(CallerArch::I386),
+            _ => None,
+        }
+    }
 }
 
 pub struct SyscallArch {
-    arch: u32,
     mknod: i32,
     mknodat: i32,
     quotactl: i32,
+    renameat2: i32,
+    fchownat: i32,
+    umount2: i32,
 }
 
-const SYSCALL_TABLE: &[SyscallArch] = &[
-    SyscallArch {
-        arch: AUDIT_ARCH_X86_64,
-        mknod: 133,
-        mknodat: 259,
-        quotactl: 179,
-    },
-    SyscallArch {
-        arch: AUDIT_ARCH_I386,
-        mknod: 14,
-        mknodat: 297,
-        quotactl: 131,
-    },
-];
-
-pub fn translate_syscall(arch: u32, nr: c_int) -> Option<Syscall> {
+const SYSCALL_TABLE_X86_64: SyscallArch = SyscallArch {
+    mknod: 133,
+    mknodat: 259,
+    quotactl: 179,
+    renameat2: 316,
+    fchownat: 260,
+    umount2: 166,
+};
+
+const SYSCALL_TABLE_I386: SyscallArch = SyscallArch {
+    mknod: 14,
+    mknodat: 297,
+    quotactl: 131,
+    renameat2: 353,
+    fchownat: 298,
+    umount2: 52,
+};
+
+pub fn translate_syscall(arch: CallerArch, nr: c_int) -> Option<Syscall> {
     if nr == -1 {
-        // so we don't hit a -1 in SYSCALL_TABLE by accident...
+        // so we don't hit a -1 in a syscall table by accident...
         return None;
     }
 
-    for sc in SYSCALL_TABLE {
-        if sc.arch == arch {
-            if nr == sc.mknod {
-                return Some(Syscall::Mknod);
-            } else if nr == sc.mknodat {
-                return Some(Syscall::MknodAt);
-            } else if nr == sc.quotactl {
-                return Some(Syscall::Quotactl);
-            }
+    match arch {
+        CallerArch::X86_64 => SYSCALL_TABLE_X86_64.translate(nr),
+        // The syscalls we handle are all "common" entries in the x86_64 syscall table, so x32
+        // uses the same numbers (only x32 specific entries, 512 and up, would differ).
+        CallerArch::X32 => SYSCALL_TABLE_X86_64.translate(nr & !X32_SYSCALL_BIT),
+        CallerArch::I386 => SYSCALL_TABLE_I386.translate(nr),
+    }
+}
+
+impl SyscallArch {
+    fn translate(&self, nr: c_int) -> Option<Syscall> {
+        if nr == self.mknod {
+            Some(Syscall::Mknod)
+        } else if nr == self.mknodat {
+            Some(Syscall::MknodAt)
+        } else if nr == self.quotactl {
+            Some(Syscall::Quotactl)
+        } else if nr == self.renameat2 {
+            Some(Syscall::RenameAt2)
+        } else if nr == self.fchownat {
+            Some(Syscall::FchownAt)
+        } else if nr == self.umount2 {
+            Some(Syscall::Umount2)
+        } else {
+            None
         }
     }
-    None
 }
 
+thread_local! {
+    /// Buffer to read strings into, so we don't need to allocate `max_string` bytes for each of
+    /// them. Its size is bounded by the largest `max_string` used on this thread, and there are
+    /// only a few runtime threads. Measure with the `bench_get_c_string` test.
+    static STRING_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Read a NUL terminated string from the process' memory.
+///
+/// Fails with `EFAULT` if the string is not fully readable, and with `ENAMETOOLONG` if there is
+/// no NUL byte within the message's maximum string length (see
+/// [`ProxyMessageBufferBuilder::max_string`](crate::lxcseccomp::ProxyMessageBufferBuilder)).
 pub fn get_c_string(msg: &ProxyMessageBuffer, offset: u64) -> Result<CString, Error> {
-    let mut data = unsafe { vec::uninitialized(4096) };
-    let got = msg.mem_fd().read_at(&mut data, offset)?;
-
-    let len = unsafe { libc::strnlen(data.as_ptr() as *const _, got) };
-    if len >= got {
-        Err(Errno::EINVAL.into())
-    } else {
-        unsafe {
-            data.set_len(len);
+    STRING_BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        let max_string = msg.max_string();
+        if buffer.len() < max_string {
+            *buffer = unsafe { vec::uninitialized(max_string) };
         }
-        // We used strlen, so the only Error in CString::new() cannot happen at this point:
-        Ok(CString::new(data).unwrap())
-    }
+        let data = &mut buffer[..max_string];
+        let got = match msg.read_mem(data, offset) {
+            Ok(got) => got,
+            // what reading unmapped memory via the mem fd or `PidFd::read_mem()` fails with
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => return Err(Errno::EFAULT.into()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let len = unsafe { libc::strnlen(data.as_ptr() as *const _, got) };
+        if len >= got {
+            // A short read means we ran into memory we cannot read before finding the end.
+            if got < data.len() {
+                Err(Errno::EFAULT.into())
+            } else {
+                Err(Errno::ENAMETOOLONG.into())
+            }
+        } else {
+            // We used strlen, so the only Error in CString::new() cannot happen at this point:
+            Ok(CString::new(&data[..len]).unwrap())
+        }
+    })
 }
 
 #[macro_export]
@@ -99,3 +253,168 @@ macro_rules! sc_libc_try {
         }
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::FromRawFd;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::lxcseccomp::{ProxyMessageBufferBuilder, MAX_STRING};
+    use crate::process::PidFd;
+
+    fn translate(arch: u32, nr: c_int) -> Option<Syscall> {
+        translate_syscall(CallerArch::from_notification(arch, nr)?, nr)
+    }
+
+    #[test]
+    fn translate_x86_64_and_x32() {
+        let mknodat = libc::SYS_mknodat as c_int;
+        assert_eq!(
+            CallerArch::from_notification(AUDIT_ARCH_X86_64, mknodat | X32_SYSCALL_BIT),
+            Some(CallerArch::X32)
+        );
+        assert_eq!(
+            translate(AUDIT_ARCH_X86_64, mknodat | X32_SYSCALL_BIT),
+            Some(Syscall::MknodAt)
+        );
+        assert_eq!(
+            CallerArch::from_notification(AUDIT_ARCH_X86_64, mknodat),
+            Some(CallerArch::X86_64)
+        );
+        assert_eq!(
+            translate(AUDIT_ARCH_X86_64, mknodat),
+            Some(Syscall::MknodAt)
+        );
+        assert_eq!(translate(AUDIT_ARCH_I386, 297), Some(Syscall::MknodAt));
+        // i386's number for mknodat is a different syscall on x86_64
+        assert_eq!(translate(AUDIT_ARCH_X86_64, 297), None);
+        assert_eq!(translate(AUDIT_ARCH_X86_64, -1), None);
+    }
+
+    /// A message whose process memory holds `data` at `offset`, and nothing after it.
+    fn message_with_mem(offset: u64, data: &[u8]) -> ProxyMessageBuffer {
+        message_with_limits(ProxyMessageBuffer::builder(), offset, data)
+    }
+
+    fn message_with_limits(
+        builder: ProxyMessageBufferBuilder,
+        offset: u64,
+        data: &[u8],
+    ) -> ProxyMessageBuffer {
+        let fd = unsafe { libc::memfd_create(b"mem\0".as_ptr() as _, libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let mem = unsafe { std::fs::File::from_raw_fd(fd) };
+        mem.write_all_at(data, offset).unwrap();
+        let request = unsafe { std::mem::zeroed() };
+        builder.build_from_parts(request, mem, PidFd::current().unwrap())
+    }
+
+    fn c_string_errno(msg: &ProxyMessageBuffer, offset: u64) -> Option<Errno> {
+        get_c_string(msg, offset)
+            .unwrap_err()
+            .downcast_ref::<Errno>()
+            .copied()
+    }
+
+    #[test]
+    fn c_string_errors() {
+        let mut data = b"/dev/null\0".to_vec();
+        data.extend_from_slice(b"unterminated");
+        let msg = message_with_mem(0x1000, &data);
+        assert_eq!(get_c_string(&msg, 0x1000).unwrap().as_bytes(), b"/dev/null");
+        // running into the end of the readable memory
+        assert_eq!(c_string_errno(&msg, 0x100a), Some(Errno::EFAULT));
+        assert_eq!(c_string_errno(&msg, 0x2000), Some(Errno::EFAULT));
+
+        // exactly the limit including the NUL byte is fine, one more is not
+        let mut data = vec![b'a'; MAX_STRING + 1];
+        data[MAX_STRING - 1] = 0;
+        let msg = message_with_mem(0, &data);
+        assert_eq!(
+            get_c_string(&msg, 0).unwrap().as_bytes().len(),
+            MAX_STRING - 1
+        );
+        data[MAX_STRING - 1] = b'a';
+        data[MAX_STRING] = 0;
+        let msg = message_with_mem(0, &data);
+        assert_eq!(c_string_errno(&msg, 0), Some(Errno::ENAMETOOLONG));
+    }
+
+    #[test]
+    fn c_string_limits() {
+        let mut data = vec![b'a'; 3 * MAX_STRING];
+        data[2 * MAX_STRING] = 0;
+        let msg = message_with_mem(0, &data);
+        assert_eq!(c_string_errno(&msg, 0), Some(Errno::ENAMETOOLONG));
+
+        // eg. for long paths on deep directory trees
+        let builder = ProxyMessageBuffer::builder().max_string(4 * MAX_STRING);
+        let msg = message_with_limits(builder, 0, &data);
+        assert_eq!(
+            get_c_string(&msg, 0).unwrap().as_bytes().len(),
+            2 * MAX_STRING
+        );
+
+        // the string is still read at once with a smaller memory read limit
+        let builder = ProxyMessageBuffer::builder()
+            .max_string(4 * MAX_STRING)
+            .max_mem_read(16);
+        let msg = message_with_limits(builder, 0, &data);
+        assert_eq!(
+            get_c_string(&msg, 0).unwrap().as_bytes().len(),
+            2 * MAX_STRING
+        );
+
+        let builder = ProxyMessageBuffer::builder().max_string(8).max_mem_read(16);
+        let msg = message_with_limits(builder, 0, &data);
+        assert_eq!(c_string_errno(&msg, 0), Some(Errno::ENAMETOOLONG));
+        let mut buf = [0u8; 64];
+        assert_eq!(msg.read_mem(&mut buf, 0).unwrap(), 16);
+    }
+
+    #[test]
+    fn c_string_from_process_memory() {
+        let path = CString::new("/dev/null").unwrap();
+        let request = unsafe { std::mem::zeroed() };
+        let mem = std::fs::File::open("/proc/self/mem").unwrap();
+        let msg = ProxyMessageBuffer::from_parts(request, mem, PidFd::current().unwrap());
+
+        let addr = path.as_ptr() as usize as u64;
+        assert_eq!(get_c_string(&msg, addr).unwrap(), path);
+        // unmapped, eg. `mknod(NULL, ...)`
+        assert_eq!(c_string_errno(&msg, 0), Some(Errno::EFAULT));
+        assert_eq!(c_string_errno(&msg, 1), Some(Errno::EFAULT));
+    }
+
+    /// Compare [`get_c_string`] with its per-thread buffer to allocating a fresh `MAX_STRING`
+    /// buffer for each string, as it did before.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_get_c_string`.
+    #[test]
+    #[ignore]
+    fn bench_get_c_string() {
+        const ROUNDS: u32 = 100_000;
+        const PATH: &[u8] = b"/var/lib/lxc/100/rootfs/dev/net/tun\0";
+
+        let msg = message_with_mem(0x1000, PATH);
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            get_c_string(&msg, 0x1000).unwrap();
+        }
+        let reused = start.elapsed() / ROUNDS;
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            let mut data: Vec<u8> = unsafe { vec::uninitialized(MAX_STRING) };
+            let got = msg.read_mem(&mut data, 0x1000).unwrap();
+            let len = unsafe { libc::strnlen(data.as_ptr() as *const _, got) };
+            CString::new(&data[..len]).unwrap();
+        }
+        let fresh = start.elapsed() / ROUNDS;
+
+        println!("string read: reused buffer {reused:?}, fresh buffer {fresh:?}");
+    }
+}
@@ -48,6 +48,89 @@ impl<T: FromRawFd> FromFd for T {
     }
 }
 
+/// File descriptor leak detection for debug builds.
+///
+/// Types owning file descriptors embed an [`FdTracker`](fd_tracker::FdTracker), which counts the
+/// live instances when building with debug assertions and compiles to nothing otherwise.
+pub mod fd_tracker {
+    #[cfg(all(debug_assertions, not(test)))]
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[cfg(all(debug_assertions, not(test)))]
+    static LIVE_FDS: AtomicUsize = AtomicUsize::new(0);
+
+    // Tests run in parallel, so count per thread there, otherwise a test could never tell its own
+    // leaks from the file descriptors of other tests.
+    #[cfg(test)]
+    thread_local! {
+        static LIVE_FDS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    pub struct FdTracker(());
+
+    impl FdTracker {
+        #[inline]
+        pub fn new() -> Self {
+            #[cfg(all(debug_assertions, not(test)))]
+            LIVE_FDS.fetch_add(1, Ordering::SeqCst);
+            #[cfg(test)]
+            LIVE_FDS.with(|live| live.set(live.get() + 1));
+            Self(())
+        }
+    }
+
+    impl Default for FdTracker {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Drop for FdTracker {
+        #[inline]
+        fn drop(&mut self) {
+            #[cfg(all(debug_assertions, not(test)))]
+            LIVE_FDS.fetch_sub(1, Ordering::SeqCst);
+            #[cfg(test)]
+            LIVE_FDS.with(|live| live.set(live.get() - 1));
+        }
+    }
+
+    /// Get the number of currently live tracked file descriptors.
+    ///
+    /// This is always 0 in release builds, and only counts those of the current thread in tests.
+    pub fn live_count() -> usize {
+        #[cfg(test)]
+        return LIVE_FDS.with(|live| live.get());
+
+        #[cfg(all(debug_assertions, not(test)))]
+        return LIVE_FDS.load(Ordering::SeqCst);
+
+        #[cfg(not(any(debug_assertions, test)))]
+        return 0;
+    }
+}
+
+/// Assert that no tracked file descriptor is alive anymore.
+///
+/// Only useful in tests after all objects were dropped, and a no-op in release builds.
+pub fn assert_no_leaked_fds() {
+    let live = fd_tracker::live_count();
+    debug_assert!(live == 0, "{live} tracked file descriptors leaked");
+}
+
+/// Compare two byte slices in constant time (with respect to their contents).
+///
+/// Only the length is allowed to leak.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let diff = a.iter().zip(b).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    // don't let the compiler turn this into an early-exit comparison
+    unsafe { std::ptr::read_volatile(&diff) == 0 }
+}
+
 /// This is totally unsafe. Only use this when you know what you're doing.
 #[derive(Debug, Clone)]
 #[repr(transparent)]